@@ -0,0 +1,23 @@
+"""Writes conformance fixtures for `gpt3bpe verify` using OpenAI's tiktoken.
+
+Each line of stdin is encoded with every vocabulary given on the command line and
+written to stdout as a {"vocabulary", "text", "ids"} JSON line, after a comment
+recording the version of tiktoken.
+
+    pip install tiktoken
+    python3 scripts/fixtures.py r50k_base cl100k_base < texts.txt > fixtures.jsonl
+"""
+import json
+import sys
+
+import tiktoken
+
+vocabularies = sys.argv[1:] or ["r50k_base", "p50k_base", "cl100k_base", "o200k_base"]
+encodings = [tiktoken.get_encoding(name) for name in vocabularies]
+
+print(f"# Reference encodings produced by tiktoken {tiktoken.__version__}.")
+for line in sys.stdin:
+    text = line.rstrip("\n")
+    for name, encoding in zip(vocabularies, encodings):
+        ids = encoding.encode(text, disallowed_special=())
+        print(json.dumps({"vocabulary": name, "text": text, "ids": ids}, ensure_ascii=False))
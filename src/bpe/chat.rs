@@ -0,0 +1,149 @@
+//! Token accounting for chat messages.
+//!
+//! Chat models see a conversation as one sequence, with every message wrapped in
+//! template tokens such as `<|im_start|>role\n … <|im_end|>\n`. This module renders a
+//! list of messages in a chat template, inserting the vocabulary's special tokens by ID
+//! rather than encoding them from text, and counts the tokens each message costs on
+//! top of its content.
+
+use super::vocabulary::Vocabularies;
+
+/// A chat message.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The role of the author, e.g. `system`, `user` or `assistant`.
+    pub role: String,
+    /// The optional name of the author.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The text of the message.
+    pub content: String,
+}
+
+/// The chat templates messages can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Template {
+    /// ChatML, as used by the chat models of `cl100k` and `o200k`: each message is
+    /// `<|im_start|>{role}\n{content}<|im_end|>\n`, where `{role}` is replaced by the
+    /// name if one is given, and the reply is primed with `<|im_start|>assistant\n`.
+    #[default]
+    ChatML,
+    /// A plain-text template that needs no special tokens: each message is
+    /// `{role}:\n{content}\n\n`, or `{role} ({name}):\n{content}\n\n` if a name is
+    /// given, and the reply is primed with `assistant:\n`.
+    Plain,
+}
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    /// Parses a string into a `Template`.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The string to parse. Case-insensitive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the string is not a valid template.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "chatml" => Ok(Template::ChatML),
+            "plain" => Ok(Template::Plain),
+            _ => Err(format!("unknown template: {s}. Please use one of: chatml, plain")),
+        }
+    }
+}
+
+/// The tokens a single message costs.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Count {
+    /// The role of the author.
+    pub role: String,
+    /// The name of the author, if given.
+    pub name: Option<String>,
+    /// The number of tokens of the rendered message.
+    pub tokens: usize,
+    /// The number of tokens of the content on its own.
+    pub content: usize,
+    /// The number of tokens added by the template, i.e. `tokens - content`.
+    pub overhead: usize,
+}
+
+/// A conversation rendered in a chat template.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Chat {
+    /// The total number of tokens, including the reply primer.
+    pub total: usize,
+    /// The tokens each message costs.
+    pub messages: Vec<Count>,
+    /// The number of tokens priming the assistant's reply.
+    pub primer: usize,
+    /// The token IDs of the rendered conversation.
+    pub ids: Vec<u32>,
+}
+
+/// Looks up a special token the template needs.
+fn special(vocabulary: &Vocabularies, name: &str) -> Result<u32, String> {
+    vocabulary
+        .special_token(name)
+        .ok_or_else(|| format!("Vocabulary {vocabulary} has no {name} token."))
+}
+
+/// Renders messages in a chat template and counts their tokens.
+///
+/// # Arguments
+///
+/// * `messages` - The conversation.
+/// * `template` - The chat template to render the conversation in.
+/// * `vocabulary` - The vocabulary to encode with.
+///
+/// # Returns
+///
+/// The total and per-message token counts with the encoded IDs, or an error if the
+/// template needs special tokens the vocabulary does not have.
+pub fn render(messages: &[Message], template: Template, vocabulary: &Vocabularies) -> Result<Chat, String> {
+    let encode = |text: &str| vocabulary.encode(text.as_bytes()).concat();
+    let mut chat = Chat { total: 0, messages: vec![], primer: 0, ids: vec![] };
+
+    for message in messages {
+        let content = encode(&message.content);
+        let mut ids = vec![];
+        match template {
+            Template::ChatML => {
+                ids.push(special(vocabulary, "<|im_start|>")?);
+                ids.extend(encode(message.name.as_deref().unwrap_or(&message.role)));
+                ids.extend(encode("\n"));
+                ids.extend(&content);
+                ids.push(special(vocabulary, "<|im_end|>")?);
+                ids.extend(encode("\n"));
+            }
+            Template::Plain => {
+                let author = match &message.name {
+                    Some(name) => format!("{} ({name})", message.role),
+                    None => message.role.clone(),
+                };
+                ids.extend(encode(&format!("{author}:\n")));
+                ids.extend(&content);
+                ids.extend(encode("\n\n"));
+            }
+        };
+        chat.messages.push(Count {
+            role: message.role.clone(),
+            name: message.name.clone(),
+            tokens: ids.len(),
+            content: content.len(),
+            overhead: ids.len().saturating_sub(content.len()),
+        });
+        chat.ids.extend(ids);
+    }
+
+    let primer = match template {
+        Template::ChatML => [vec![special(vocabulary, "<|im_start|>")?], encode("assistant\n")].concat(),
+        Template::Plain => encode("assistant:\n"),
+    };
+    chat.primer = primer.len();
+    chat.ids.extend(primer);
+    chat.total = chat.ids.len();
+    Ok(chat)
+}
@@ -0,0 +1,82 @@
+//! Splits text into chunks of at most a given number of tokens.
+//!
+//! Retrieval pipelines index documents in pieces that each fit an embedding model or a
+//! prompt. `chunk` cuts the text where the encoder already does, between the pieces
+//! split off by `Vocabularies::tokens`, and packs as many whole pieces into each chunk as fit. Only a
+//! piece that is longer than a chunk on its own is cut between its tokens.
+
+use super::vocabulary::Vocabularies;
+use super::Policy;
+
+/// A chunk of text and its tokens.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// The text of the chunk, with `U+FFFD` where a cut falls inside a character.
+    pub text: String,
+    /// The token IDs of the chunk.
+    pub ids: Vec<u32>,
+    /// The byte offset of the start of the chunk in the text.
+    pub start: usize,
+    /// The byte offset of the end of the chunk in the text.
+    pub end: usize,
+}
+
+/// Splits text into chunks of at most `max_tokens` tokens.
+///
+/// The tokens of the chunks are the tokens of the whole text, in order, so that joining
+/// the chunks gives back the text and its encoding.
+///
+/// # Errors
+///
+/// Returns an error if `max_tokens` is `0`.
+pub fn chunk(text: &str, max_tokens: usize, vocabulary: &Vocabularies) -> Result<Vec<Chunk>, String> {
+    if max_tokens == 0 {
+        return Err("Chunks must hold at least one token.".to_string());
+    };
+    let bytes = text.as_bytes();
+
+    // Every token with the byte offset it starts at and whether a piece starts there.
+    // The tokens of a piece decode to exactly its bytes, so their lengths add up to it.
+    let mut tokens: Vec<(usize, u32, bool)> = vec![];
+    for (piece, ids) in vocabulary.tokens(bytes).into_iter().zip(vocabulary.encode(bytes)) {
+        let start = piece.as_ptr() as usize - bytes.as_ptr() as usize;
+        let mut offset = start;
+        for (index, id) in ids.into_iter().enumerate() {
+            tokens.push((offset, id, index == 0));
+            offset += vocabulary
+                .decode(&[id], Policy::Error)
+                .expect("[ERROR]: The encoder returned a token that is not in the vocabulary.")
+                .len();
+        }
+        assert_eq!(offset, start + piece.len(), "[ERROR]: The tokens of a piece do not decode to its bytes.");
+    }
+    if tokens.is_empty() {
+        return Ok(vec![]);
+    };
+
+    // The index of the first token of every chunk, cutting between pieces where one fits.
+    let mut cuts = vec![0];
+    while let Some(&first) = cuts.last().filter(|&&first| tokens.len() - first > max_tokens) {
+        let limit = first + max_tokens;
+        cuts.push((first + 1..=limit).rev().find(|&index| tokens[index].2).unwrap_or(limit));
+    }
+    cuts.push(tokens.len());
+
+    let chunks = cuts
+        .windows(2)
+        .map(|cut| {
+            let start = match cut[0] {
+                0 => 0,
+                first => tokens[first].0,
+            };
+            let end = tokens.get(cut[1]).map_or(bytes.len(), |&(offset, _, _)| offset);
+            Chunk {
+                text: String::from_utf8_lossy(&bytes[start..end]).into_owned(),
+                ids: tokens[cut[0]..cut[1]].iter().map(|&(_, id, _)| id).collect(),
+                start,
+                end,
+            }
+        })
+        .collect();
+    Ok(chunks)
+}
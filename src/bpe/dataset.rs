@@ -0,0 +1,470 @@
+//! Writes tokenized corpora as flat binary shards for training.
+//!
+//! The format is the one of nanoGPT's `prepare.py` scripts: every document is encoded,
+//! followed by the `<|endoftext|>` token, and the token IDs of all documents are
+//! written one after another as little-endian integers, `uint16` if every ID of the
+//! vocabulary fits and `uint32` otherwise. Documents are assigned to the `train` or
+//! `val` split at random, and each split is written to `train.bin` and `val.bin`, or
+//! to numbered shards of at most `shard_tokens` tokens like `train_0000.bin`. A
+//! `meta.json` file next to the shards records the vocabulary, the type of the
+//! integers and the number of documents and tokens of every split and shard, so that a
+//! reader can map the shards without guessing their layout.
+//!
+//! `Dataset` reads a split back by memory-mapping its shards, and `Sampler` draws
+//! random windows of a fixed number of tokens from it with a seeded random number
+//! generator, each paired with the same window shifted by one token as the targets of
+//! next-token prediction.
+
+use super::vocabulary::Vocabularies;
+use rand::rngs::StdRng;
+use super::Policy;
+use rand::RngExt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The name of the metadata file written next to the shards.
+pub const METADATA: &str = "meta.json";
+
+/// The type of the integers a shard is written in.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Dtype {
+    /// Little-endian `u16`, for vocabularies of at most 65536 tokens.
+    Uint16,
+    /// Little-endian `u32`.
+    Uint32,
+}
+
+impl Dtype {
+    /// Returns the smallest type that holds every token ID of `vocabulary`.
+    pub fn of(vocabulary: &Vocabularies) -> Self {
+        match vocabulary.size() <= 1 << 16 {
+            true => Dtype::Uint16,
+            false => Dtype::Uint32,
+        }
+    }
+
+    /// Returns the number of bytes of one token.
+    pub fn width(&self) -> usize {
+        match self {
+            Dtype::Uint16 => 2,
+            Dtype::Uint32 => 4,
+        }
+    }
+}
+
+/// A shard file and the number of tokens in it.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Shard {
+    /// The name of the file, relative to the metadata file.
+    pub file: String,
+    /// The number of tokens in the file.
+    pub tokens: usize,
+}
+
+/// The documents and tokens written to one split.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Split {
+    /// The number of documents.
+    pub documents: usize,
+    /// The number of tokens, `<|endoftext|>` separators included.
+    pub tokens: usize,
+    /// The shards of the split, in order.
+    pub shards: Vec<Shard>,
+}
+
+/// The contents of `meta.json`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// The vocabulary the documents were encoded with.
+    pub vocabulary: String,
+    /// The type of the integers in the shards.
+    pub dtype: Dtype,
+    /// One more than the largest token ID of the vocabulary.
+    pub vocab_size: u32,
+    /// The ID of the `<|endoftext|>` token that follows every document.
+    pub eot: u32,
+    /// The training split.
+    pub train: Split,
+    /// The validation split.
+    pub val: Split,
+}
+
+/// Writes the tokens of one split into shards.
+#[derive(Debug)]
+struct Writer {
+    /// The directory the shards are written to.
+    directory: PathBuf,
+    /// The name of the split, `train` or `val`.
+    name: &'static str,
+    /// The type of the integers.
+    dtype: Dtype,
+    /// The most tokens a shard may hold, or `None` for a single file.
+    shard_tokens: Option<usize>,
+    /// The shard being written.
+    file: Option<std::io::BufWriter<std::fs::File>>,
+    /// The documents and tokens written so far.
+    split: Split,
+}
+
+impl Writer {
+    /// Creates a writer for a split. The first shard is created at once, so that every
+    /// split has at least one, possibly empty, file.
+    fn new(directory: &Path, name: &'static str, dtype: Dtype, shard_tokens: Option<usize>) -> Result<Self, String> {
+        let mut writer = Writer {
+            directory: directory.to_path_buf(),
+            name,
+            dtype,
+            shard_tokens,
+            file: None,
+            split: Split::default(),
+        };
+        writer.open()?;
+        Ok(writer)
+    }
+
+    /// Closes the current shard and opens the next one.
+    fn open(&mut self) -> Result<(), String> {
+        self.close()?;
+        let file = match self.shard_tokens {
+            Some(_) => format!("{}_{:04}.bin", self.name, self.split.shards.len()),
+            None => format!("{}.bin", self.name),
+        };
+        let path = self.directory.join(&file);
+        let handle = std::fs::File::create(&path)
+            .map_err(|e| format!("Could not create shard {}: {e}", path.display()))?;
+        self.file = Some(std::io::BufWriter::new(handle));
+        self.split.shards.push(Shard { file, tokens: 0 });
+        Ok(())
+    }
+
+    /// Flushes the current shard.
+    fn close(&mut self) -> Result<(), String> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().map_err(|e| format!("Could not write shard: {e}"))?;
+        };
+        Ok(())
+    }
+
+    /// Writes the tokens of one document, starting a new shard whenever the current
+    /// one is full, so a document may span two shards.
+    fn write(&mut self, tokens: &[u32]) -> Result<(), String> {
+        for &token in tokens {
+            let full = match (self.shard_tokens, self.split.shards.last()) {
+                (Some(limit), Some(shard)) => shard.tokens >= limit,
+                _ => false,
+            };
+            if full {
+                self.open()?;
+            };
+            let file = self.file.as_mut().expect("[ERROR]: Shard is not open.");
+            let written = match self.dtype {
+                Dtype::Uint16 => {
+                    let token = u16::try_from(token)
+                        .map_err(|_| format!("Token ID {token} does not fit in 16 bits."))?;
+                    file.write_all(&token.to_le_bytes())
+                }
+                Dtype::Uint32 => file.write_all(&token.to_le_bytes()),
+            };
+            written.map_err(|e| format!("Could not write shard: {e}"))?;
+            if let Some(shard) = self.split.shards.last_mut() {
+                shard.tokens += 1;
+            };
+        }
+        self.split.tokens += tokens.len();
+        self.split.documents += 1;
+        Ok(())
+    }
+
+    /// Flushes the last shard.
+    ///
+    /// # Returns
+    ///
+    /// The documents, tokens and shards written.
+    fn finish(mut self) -> Result<Split, String> {
+        self.close()?;
+        Ok(self.split)
+    }
+}
+
+/// Encodes a stream of documents and writes them as `train` and `val` shards.
+///
+/// # Arguments
+///
+/// * `documents` - The documents, or an error if one could not be read.
+/// * `vocabulary` - The vocabulary to encode with.
+/// * `directory` - The directory to write the shards and `meta.json` to. It is created if needed.
+/// * `validation` - The probability of assigning a document to the `val` split.
+/// * `shard_tokens` - The most tokens a shard may hold, or `None` to write one file per split.
+/// * `rng` - The random number generator of the split. Seed it to make the split reproducible.
+///
+/// # Returns
+///
+/// The `Metadata` written to `meta.json`.
+///
+/// # Errors
+///
+/// Returns an error if `validation` is not between `0` and `1`, `shard_tokens` is `0`,
+/// the vocabulary has no `<|endoftext|>` token, a document cannot be read, or a file
+/// cannot be written.
+pub fn prepare<I: Iterator<Item = Result<String, String>>>(
+    documents: I,
+    vocabulary: &Vocabularies,
+    directory: &Path,
+    validation: f64,
+    shard_tokens: Option<usize>,
+    rng: &mut StdRng,
+) -> Result<Metadata, String> {
+    if !(0.0..=1.0).contains(&validation) {
+        return Err(format!("Validation fraction {validation} is not between 0 and 1."));
+    };
+    if shard_tokens == Some(0) {
+        return Err("Shards must hold at least one token.".to_string());
+    };
+    let eot = vocabulary
+        .special_token("<|endoftext|>")
+        .ok_or_else(|| format!("Vocabulary {vocabulary} has no <|endoftext|> token."))?;
+    let dtype = Dtype::of(vocabulary);
+    std::fs::create_dir_all(directory)
+        .map_err(|e| format!("Could not create directory {}: {e}", directory.display()))?;
+
+    let mut train = Writer::new(directory, "train", dtype, shard_tokens)?;
+    let mut val = Writer::new(directory, "val", dtype, shard_tokens)?;
+    for document in documents {
+        let mut tokens = vocabulary.encode(document?.as_bytes()).concat();
+        tokens.push(eot);
+        match rng.random_bool(validation) {
+            true => val.write(&tokens)?,
+            false => train.write(&tokens)?,
+        };
+    }
+
+    let metadata = Metadata {
+        vocabulary: vocabulary.to_string(),
+        dtype,
+        vocab_size: vocabulary.size(),
+        eot,
+        train: train.finish()?,
+        val: val.finish()?,
+    };
+    let path = directory.join(METADATA);
+    std::fs::write(&path, serde_json::to_string_pretty(&metadata).unwrap())
+        .map_err(|e| format!("Could not write metadata {}: {e}", path.display()))?;
+    Ok(metadata)
+}
+
+/// The tokens of a memory-mapped shard.
+#[derive(Debug)]
+pub struct Tokens {
+    /// The mapped bytes of the shard.
+    map: memmap2::Mmap,
+    /// The type of the integers in the shard.
+    dtype: Dtype,
+}
+
+impl Tokens {
+    /// Memory-maps a shard.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened or mapped, or if its length is not
+    /// a multiple of the width of `dtype`.
+    pub fn open(path: &Path, dtype: Dtype) -> Result<Self, String> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("Could not open shard {}: {e}", path.display()))?;
+        // SAFETY: shards are written once by `prepare` and only read afterwards. If
+        // another process truncates the file while it is mapped, reads may fault.
+        let map = unsafe { memmap2::Mmap::map(&file) }
+            .map_err(|e| format!("Could not map shard {}: {e}", path.display()))?;
+        if map.len() % dtype.width() != 0 {
+            return Err(format!(
+                "Shard {} is {} bytes long, which is not a multiple of {}.",
+                path.display(),
+                map.len(),
+                dtype.width()
+            ));
+        };
+        Ok(Tokens { map, dtype })
+    }
+
+    /// Returns the number of tokens in the shard.
+    pub fn len(&self) -> usize {
+        self.map.len() / self.dtype.width()
+    }
+
+    /// Returns `true` if the shard holds no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the token at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn get(&self, index: usize) -> u32 {
+        let start = index * self.dtype.width();
+        let bytes = &self.map[start..start + self.dtype.width()];
+        match self.dtype {
+            Dtype::Uint16 => u32::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+            Dtype::Uint32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+
+    /// Returns the `length` tokens starting at `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the window is out of bounds.
+    pub fn window(&self, start: usize, length: usize) -> Vec<u32> {
+        (start..start + length).map(|index| self.get(index)).collect()
+    }
+}
+
+/// A split of a prepared dataset, with its shards memory-mapped.
+#[derive(Debug)]
+pub struct Dataset {
+    /// The contents of `meta.json`.
+    pub metadata: Metadata,
+    /// The shards of the split, in order.
+    pub shards: Vec<Tokens>,
+}
+
+impl Dataset {
+    /// Opens a split of a dataset written by `prepare`.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - The directory holding `meta.json` and the shards.
+    /// * `split` - The split to open, `train` or `val`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `meta.json` cannot be read or parsed, the split is unknown,
+    /// or a shard cannot be mapped.
+    pub fn open(directory: &Path, split: &str) -> Result<Self, String> {
+        let path = directory.join(METADATA);
+        let metadata: Metadata = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read metadata {}: {e}", path.display()))
+            .and_then(|text| {
+                serde_json::from_str(&text)
+                    .map_err(|e| format!("Could not parse metadata {}: {e}", path.display()))
+            })?;
+        let files = match split {
+            "train" => &metadata.train.shards,
+            "val" => &metadata.val.shards,
+            _ => return Err(format!("unknown split: {split}. Please use one of: train, val")),
+        };
+        let shards = files
+            .iter()
+            .map(|shard| Tokens::open(&directory.join(&shard.file), metadata.dtype))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Dataset { metadata, shards })
+    }
+
+    /// Returns the vocabulary the dataset was encoded with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `meta.json` names an unknown vocabulary.
+    pub fn vocabulary(&self) -> Result<Vocabularies, String> {
+        self.metadata.vocabulary.parse()
+    }
+}
+
+/// A batch of windows sampled from a `Dataset`.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Batch {
+    /// The token IDs of each window.
+    pub contexts: Vec<Vec<u32>>,
+    /// The token IDs following each token of the matching context, i.e. the context
+    /// shifted by one.
+    pub targets: Vec<Vec<u32>>,
+}
+
+impl Batch {
+    /// Decodes every window for inspection.
+    ///
+    /// A window starts and ends at arbitrary tokens, so it may cut a multi-byte
+    /// character in half. Such bytes, and IDs missing from `vocabulary`, are shown as
+    /// `U+FFFD`.
+    ///
+    /// # Returns
+    ///
+    /// The text of every context.
+    pub fn decode(&self, vocabulary: &Vocabularies) -> Vec<String> {
+        self.contexts.iter().map(|context| decode(context, vocabulary)).collect()
+    }
+}
+
+/// Decodes a window of token IDs for inspection.
+///
+/// Special tokens such as the `<|endoftext|>` separating documents are written by
+/// name; unknown IDs and invalid UTF-8 are replaced with `U+FFFD`.
+pub fn decode(window: &[u32], vocabulary: &Vocabularies) -> String {
+    let special = vocabulary.special();
+    let mut bytes = vec![];
+    for run in window.chunk_by(|a, b| special.iter().all(|&(_, id)| id != *a && id != *b)) {
+        match special.iter().find(|&&(_, id)| run == [id]) {
+            Some((name, _)) => bytes.extend_from_slice(name.as_bytes()),
+            None => bytes.extend(
+                vocabulary
+                    .decode(run, Policy::Replace)
+                    .expect("[ERROR]: Replacing unknown tokens cannot fail."),
+            ),
+        };
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Samples random windows of a fixed length from a `Dataset`.
+///
+/// Windows never cross the end of a shard. Every start position in the split is
+/// equally likely, so a shard is drawn in proportion to the windows it holds.
+#[derive(Debug)]
+pub struct Sampler<'a> {
+    /// The dataset to sample from.
+    dataset: &'a Dataset,
+    /// The number of tokens of a window.
+    context: usize,
+    /// The number of windows starting in each shard.
+    starts: Vec<usize>,
+    /// The random number generator.
+    rng: StdRng,
+}
+
+impl<'a> Sampler<'a> {
+    /// Creates a sampler of windows of `context` tokens.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `context` is `0` or no shard holds `context + 1` tokens.
+    pub fn new(dataset: &'a Dataset, context: usize, rng: StdRng) -> Result<Self, String> {
+        if context == 0 {
+            return Err("Windows must hold at least one token.".to_string());
+        };
+        let starts: Vec<usize> = dataset.shards.iter().map(|shard| shard.len().saturating_sub(context)).collect();
+        if starts.iter().all(|&count| count == 0) {
+            return Err(format!("No shard holds the {} tokens of a window and its target.", context + 1));
+        };
+        Ok(Sampler { dataset, context, starts, rng })
+    }
+
+    /// Samples one window and its targets.
+    pub fn window(&mut self) -> (Vec<u32>, Vec<u32>) {
+        let mut start = self.rng.random_range(0..self.starts.iter().sum::<usize>());
+        let mut shard = 0;
+        while start >= self.starts[shard] {
+            start -= self.starts[shard];
+            shard += 1;
+        }
+        let tokens = self.dataset.shards[shard].window(start, self.context + 1);
+        (tokens[..self.context].to_vec(), tokens[1..].to_vec())
+    }
+
+    /// Samples `size` windows.
+    pub fn batch(&mut self, size: usize) -> Batch {
+        let (contexts, targets) = (0..size).map(|_| self.window()).unzip();
+        Batch { contexts, targets }
+    }
+}
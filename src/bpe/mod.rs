@@ -10,11 +10,19 @@
 //!
 //! The implementation is inspired by Andrej Karpathy's [picoGPT](https://github.com/jaymody/picoGPT) project.
 
+pub mod chat;
+pub mod chunk;
+pub mod dataset;
+pub mod pack;
+pub mod statistics;
+pub mod trie;
+pub mod truncation;
 pub(crate) mod unit;
-pub(crate) mod vocabulary;
+pub mod vocabulary;
+use rand::rngs::StdRng;
+use rand::RngExt;
 use regex::bytes::Regex;
 use std::collections::BTreeMap;
-use std::collections::HashMap;
 use std::convert::From;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -34,8 +42,38 @@ type BytePair<Type> = (usize, Type);
 /// - Sequences of numbers (`\p{N}+`).
 /// - Punctuation and other non-alphanumeric characters.
 /// - Whitespace.
-const TOKENS_RE: &str =
-    r"(u)'s|'t|'re|'ve|'m|'l l|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(\S)|\s+";
+///
+/// It is the pattern of GPT-2, used by `r50k_base` and `p50k_base`. GPT-2 ends with
+/// `\s+(?!\S)|\s+`, so that a run of whitespace before a word leaves its last character
+/// to the word. The `regex` crate has no lookahead, so `split` gives that character back
+/// after matching `\s+`.
+const TOKENS_RE: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+";
+
+/// The pattern `cl100k_base` splits text with, as in `tiktoken`.
+///
+/// Unlike GPT-2 it matches contractions in any case, keeps one leading non-letter with a
+/// word, splits numbers into runs of at most three digits and ends a run of whitespace
+/// at its last line break. `tiktoken` writes `?+` and `++` for the first two quantifiers;
+/// they cannot give anything back here, so the greedy ones match the same.
+const CL100K_RE: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]|\s+";
+
+/// The pattern `o200k_base` splits text with, as in `tiktoken`.
+///
+/// Words split at changes from lower to upper case and keep a trailing contraction.
+const O200K_RE: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+",
+);
+
+/// The compiled `TOKENS_RE`, built once instead of on every call to `tokens`.
+pub static TOKENS: LazyLock<Regex> = LazyLock::new(|| Regex::new(TOKENS_RE).unwrap());
+
+/// The compiled `CL100K_RE`.
+pub static CL100K: LazyLock<Regex> = LazyLock::new(|| Regex::new(CL100K_RE).unwrap());
+
+/// The compiled `O200K_RE`.
+pub static O200K: LazyLock<Regex> = LazyLock::new(|| Regex::new(O200K_RE).unwrap());
 
 /// A set of "safe" Unicode characters used for the reversible BPE mapping.
 ///
@@ -107,34 +145,31 @@ static BYTES_TO_UNICODE: LazyLock<BTreeMap<Vec<u8>, u16>> = LazyLock::new(|| {
     tree
 });
 
-/// Lazily initialized map of BPE merge rules.
+/// Converts GPT-unicode text back into the raw bytes it stands for.
 ///
-/// This map contains the core logic of the Byte-Pair Encoding algorithm. It maps a pair of
-/// subword units (as a byte vector) to its merge rank (a `u32`). The lower the rank, the earlier the pair is merged.
-/// The `merges.txt` file is embedded at compile time, but can be overridden at runtime by setting the `GPT3BPE_MERGES` environment variable.
-static MERGES: LazyLock<HashMap<Vec<u8>, u32>> = LazyLock::new(|| {
-    let merges_contents: &str = match std::env::var("GPT3BPE_MERGES") {
-        Ok(l) => {
-            // return the contents of the file by dynamically reading in the file.
-            &std::fs::read_to_string(l).unwrap()
-        }
-        Err(_) => include_str!("merges.txt"),
-    };
-    merges_contents
-        .lines()
-        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
-        .filter_map(|line| {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() == 2 {
-                Some([parts[0].as_bytes(), parts[1].as_bytes()].concat())
-            } else {
-                None
-            }
-        })
-        .enumerate()
-        .map(|(i, bytes)| (bytes, i as u32))
+/// Characters outside of the `BYTES_TO_UNICODE` map are kept as their UTF-8 bytes.
+/// Vocabularies are converted once, when they are loaded, so that encoding can work
+/// on raw bytes.
+pub fn raw(text: &str) -> Vec<u8> {
+    let mut buffer = [0; 4];
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        let symbol = c.encode_utf8(&mut buffer).as_bytes();
+        match BYTES_TO_UNICODE.get(symbol) {
+            Some(&byte) => bytes.push(byte as u8),
+            None => bytes.extend_from_slice(symbol),
+        };
+    }
+    bytes
+}
+
+/// Converts raw bytes into their GPT-unicode text, as UTF-8 bytes.
+pub fn unicode(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|&byte| UNICODE_TO_BYTES[&(byte as u16)].iter().copied())
         .collect()
-});
+}
 
 /// Splits a byte slice into a sequence of Unicode graphemes.
 ///
@@ -162,16 +197,39 @@ pub fn grapheme(slice: &[u8]) -> Vec<Vec<u8>> {
         .collect()
 }
 
-/// Splits a byte slice into initial token chunks.
+/// Splits a byte slice into initial token chunks with the GPT-2 pattern, `TOKENS_RE`.
 ///
 /// This function performs the first pass of tokenization, breaking the input text into
 /// smaller, more manageable pieces like words, numbers, punctuation, and contractions.
 pub fn tokens(slice: &[u8]) -> Vec<&[u8]> {
-    Regex::new(TOKENS_RE)
-        .unwrap()
-        .find_iter(slice)
-        .map(|m| -> &[u8] { m.as_bytes() })
-        .collect()
+    split(slice, &TOKENS)
+}
+
+/// Splits a byte slice into initial token chunks with one of the patterns of a vocabulary.
+///
+/// Every pattern ends with `\s+` standing in for `\s+(?!\S)|\s+`: a run of whitespace
+/// that is followed by more text ends one character early. The patterns of `cl100k_base`
+/// and `o200k_base` match runs up to a line break with `\s*[\r\n]` first, and those
+/// runs are kept whole.
+pub fn split<'a>(slice: &'a [u8], pattern: &Regex) -> Vec<&'a [u8]> {
+    let breaks = pattern.as_str().contains(r"\s*[\r\n]");
+    let mut pieces = vec![];
+    let mut start = 0;
+    while let Some(m) = pattern.find_at(slice, start) {
+        let mut end = m.end();
+        let broken = matches!(m.as_bytes().last(), Some(b'\n' | b'\r'));
+        if end < slice.len() && !(breaks && broken) {
+            if let Ok(run) = std::str::from_utf8(m.as_bytes()) {
+                match run.char_indices().nth(1).and_then(|_| run.char_indices().last()) {
+                    Some((last, _)) if run.chars().all(char::is_whitespace) => end = m.start() + last,
+                    _ => {}
+                };
+            };
+        };
+        pieces.push(&slice[m.start()..end]);
+        start = end;
+    }
+    pieces
 }
 
 /// An iterator that performs the Byte-Pair Encoding merge process.
@@ -179,82 +237,134 @@ pub fn tokens(slice: &[u8]) -> Vec<&[u8]> {
 /// It iteratively finds the byte pair with the lowest merge rank, merges it,
 /// and yields the new sequence of token IDs. This continues until no more
 /// merges are possible.
-struct BytePairEncoder {
-    /// The sequence of graphemes to be merged.
-    pub grapheme: Vec<Vec<u8>>,
-    /// A vector where each element is a tuple containing the start index of a unit in `grapheme`
+///
+/// The encoder works on the raw bytes of a piece: units are ranges of the piece and
+/// their ranks are looked up by slice in the vocabulary, which was converted from
+/// GPT-unicode to raw bytes when it was loaded. A merge therefore allocates nothing.
+/// As in OpenAI's `tiktoken`, the rank of a pair is the token ID of the unit it merges
+/// into, so pairs that are not tokens are never merged.
+///
+/// With BPE-dropout enabled, every candidate pair is skipped with probability `p`
+/// on each step, and the merging stops once all candidates of a step are skipped.
+struct BytePairEncoder<'a, T> {
+    /// The raw bytes of the piece to be merged.
+    piece: &'a [u8],
+    /// A vector where each element is a tuple containing the start index of a unit in `piece`
     /// and the rank of the pair formed by this unit and the next.
     pairs: Vec<BytePair<u32>>,
-    /// A map from a byte sequence (a potential token) to its rank or token ID.
-    encoder: BTreeMap<Vec<u8>, u32>,
+    /// The vocabulary, mapping raw bytes to token IDs.
+    lookup: &'a BTreeMap<Vec<u8>, T>,
+    /// The number of units that have neither a token ID nor a merge rank.
+    missing: usize,
+    /// The number of merges applied so far.
+    merges: usize,
+    /// The byte ranges of the left and right unit and the rank of the pair merged by
+    /// the last call to `merge`.
+    merged: Option<(std::ops::Range<usize>, std::ops::Range<usize>, u32)>,
+    /// The BPE-dropout probability and the random number generator deciding which pairs are skipped.
+    dropout: Option<(f64, &'a mut StdRng)>,
 }
 
-impl BytePairEncoder {
-    /// Creates a new `BytePairEncoder`.
-    pub fn new<T: Into<u32> + Copy + Ord + Debug>(
-        grapheme: Vec<Vec<u8>>,
-        lookup: &BTreeMap<Vec<u8>, T>,
-    ) -> BytePairEncoder {
-        let mut encoder: BTreeMap<Vec<u8>, u32> = std::collections::BTreeMap::new();
-
-        for (key, value) in MERGES.iter() {
-            encoder.insert(key.to_vec(), *value);
-        }
-        for (key, value) in lookup.iter() {
-            encoder.insert(key.to_vec(), (*value).into());
-        }
-
-        let mut pairs: Vec<BytePair<u32>> = (0..grapheme.len()).map(|i| (i, u32::MAX)).collect();
-        for i in 0..pairs.len() - 1 {
-            if let Some(rank) = encoder.get(&grapheme[pairs[i].0..pairs[i + 1].0 + 1].concat()) {
-                pairs[i].1 = *rank;
+impl<'a, T: Into<u32> + Copy + Ord + Debug> BytePairEncoder<'a, T> {
+    /// Creates a new `BytePairEncoder` over the raw bytes of a piece.
+    pub fn new(piece: &'a [u8], lookup: &'a BTreeMap<Vec<u8>, T>) -> BytePairEncoder<'a, T> {
+        let mut encoder = BytePairEncoder {
+            piece,
+            pairs: (0..piece.len()).map(|i| (i, u32::MAX)).collect(),
+            lookup,
+            missing: 0,
+            merges: 0,
+            merged: None,
+            dropout: None,
+        };
+        for i in 0..encoder.pairs.len() - 1 {
+            if let Some(rank) = encoder.rank(&piece[i..i + 2]) {
+                encoder.pairs[i].1 = rank;
             }
         }
+        encoder.missing = (0..piece.len()).filter(|&i| encoder.rank(&piece[i..i + 1]).is_none()).count();
+        encoder
+    }
 
-        BytePairEncoder {
-            grapheme,
-            pairs,
-            encoder,
+    /// Gets the token ID of a unit, which is also its merge rank.
+    fn rank(&self, bytes: &[u8]) -> Option<u32> {
+        self.lookup.get(bytes).map(|token| (*token).into())
+    }
+
+    /// Decides whether a candidate pair is skipped by BPE-dropout on this step.
+    fn dropped(&mut self) -> bool {
+        match &mut self.dropout {
+            Some((p, rng)) => rng.random_bool(*p),
+            None => false,
         }
     }
 
-    /// Gets the rank of a potential merged pair starting at `start_idx` with a given `length`.
+    /// Gets the byte range of the unit at index `idx` of `pairs`.
+    fn unit(&self, idx: usize) -> std::ops::Range<usize> {
+        let end = match self.pairs.get(idx + 1) {
+            Some(&(start, _)) => start,
+            None => self.piece.len(),
+        };
+        self.pairs[idx].0..end
+    }
+
+    /// Gets the rank of the unit that merging `length` units, starting at `start_idx`, would give.
     fn get_rank(&self, start_idx: usize, length: usize) -> Option<u32> {
         if start_idx + length <= self.pairs.len() {
-            self.encoder
-                .get(
-                    &self.grapheme
-                        [self.pairs[start_idx].0..self.pairs[start_idx + length - 1].0 + 1]
-                        .concat(),
-                )
-                .copied()
+            self.rank(&self.piece[self.pairs[start_idx].0..self.unit(start_idx + length - 1).end])
         } else {
             None
         }
     }
-}
-
-impl Iterator for BytePairEncoder {
-    type Item = Vec<u32>;
 
-    /// Finds the next best pair to merge and returns the new sequence of token IDs.
+    /// Merges the pair with the lowest rank.
     ///
-    /// On each call, it identifies the pair with the lowest rank, merges them into a single unit,
-    /// and updates the ranks of the adjacent pairs. It then returns the full list of current token IDs.
-    fn next(&mut self) -> Option<Self::Item> {
+    /// On each call, it identifies the pair with the lowest rank, merges it into a single unit,
+    /// and updates the ranks of the adjacent pairs.
+    ///
+    /// # Returns
+    ///
+    /// `false`, leaving the units as they are, if no pair can be merged or if the merge
+    /// would leave a unit without a token ID. In that case the BPE process cannot proceed
+    /// further with valid merges, and the current units are the result.
+    fn merge(&mut self) -> bool {
         if self.pairs.len() == 1 {
-            return None;
+            return false;
         }
 
         let mut rank: (u32, usize) = (u32::MAX, 0);
-        for (idx, &(_, r)) in self.pairs[..self.pairs.len() - 1].iter().enumerate() {
+        for idx in 0..self.pairs.len() - 1 {
+            let r = self.pairs[idx].1;
+            if r == u32::MAX || self.dropped() {
+                continue;
+            }
             if r < rank.0 {
                 rank = (r, idx);
             }
         }
 
         if rank.0 == u32::MAX {
-            return None;
+            return false;
+        }
+        let (left, right) = (self.unit(rank.1), self.unit(rank.1 + 1));
+        self.merged = Some((left.clone(), right.clone(), rank.0));
+
+        let missing = |range: &std::ops::Range<usize>| self.rank(&self.piece[range.clone()]).is_none() as usize;
+        let remaining = self.missing + missing(&(left.start..right.end)) - missing(&left) - missing(&right);
+        if remaining > 0 {
+            #[cfg(debug_assertions)]
+            {
+                let unknown = (0..self.pairs.len())
+                    .filter(|&i| i != rank.1 + 1)
+                    .map(|i| if i == rank.1 { left.start..right.end } else { self.unit(i) })
+                    .find(|range| missing(range) == 1)
+                    .unwrap_or(left.start..right.end);
+                eprintln!(
+                    "[WARNING]: Encoding value for {:?} not found.",
+                    String::from_utf8_lossy(&unicode(&self.piece[unknown]))
+                );
+            }
+            return false;
         }
 
         // The rank of the new merged pair will be the rank of it with its new right neighbor.
@@ -265,108 +375,414 @@ impl Iterator for BytePairEncoder {
             self.pairs[rank.1].1 = u32::MAX;
         }
 
+        // The left neighbor now pairs with both merged units.
         if rank.1 > 0 {
-            self.pairs[rank.1 - 1].1 = self.get_rank(rank.1 - 1, 2).unwrap_or(u32::MAX);
+            self.pairs[rank.1 - 1].1 = self.get_rank(rank.1 - 1, 3).unwrap_or(u32::MAX);
         }
         self.pairs.remove(rank.1 + 1);
+        self.missing = remaining;
+        self.merges += 1;
+        true
+    }
 
-        let mut result = Vec::with_capacity(self.pairs.len());
-        for i in 0..self.pairs.len() {
-            let start = self.pairs[i].0;
-            let end = if i < self.pairs.len() - 1 {
-                self.pairs[i + 1].0
-            } else {
-                self.grapheme.len()
-            };
-            match self
-                .encoder
-                .get(&self.grapheme[start..end].concat())
-                .copied()
-            {
-                Some(v) => result.push(v),
-                None => {
-                    // If a token is not found, it implies that the BPE process cannot proceed further with valid merges.
-                    // By returning `None`, we stop the iteration. The `encode` function will use the last successfully
-                    // generated token list from the previous `next()` call.
-                    #[cfg(debug_assertions)]
-                    println!(
-                        "[WARNING]: Encoding value for {:?} not found.",
-                        String::from_utf8_lossy(&self.grapheme[start..end].concat())
-                    );
-                    return None;
-                }
-            }
+    /// Returns the token IDs of the current units.
+    fn ids(&self) -> Vec<u32> {
+        (0..self.pairs.len())
+            .map(|i| self.rank(&self.piece[self.unit(i)]).unwrap_or(u32::MAX))
+            .collect()
+    }
+
+    /// Merges until no more merges are possible.
+    ///
+    /// # Returns
+    ///
+    /// The token IDs of the piece, or `fallback` if no merge could be applied and some
+    /// unit has no token ID.
+    fn run(mut self, fallback: Vec<u32>) -> Vec<u32> {
+        while self.merge() {}
+        match (self.merges, self.missing) {
+            (0, 1..) => fallback,
+            _ => self.ids(),
         }
-        Some(result)
     }
 }
 
+impl<T: Into<u32> + Copy + Ord + Debug> Iterator for BytePairEncoder<'_, T> {
+    type Item = Vec<u32>;
+
+    /// Merges the next best pair and returns the new sequence of token IDs.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.merge() {
+            true => Some(self.ids()),
+            false => None,
+        }
+    }
+}
+
+/// Returns the fallback token IDs of a piece that cannot be merged: the IDs of its
+/// bytes, each of which is one of the 256 byte tokens of the vocabulary.
+///
+/// # Panics
+///
+/// Panics if a byte of the piece is not a token of the vocabulary.
+fn fallback<T: Copy + Into<u32>>(piece: &[u8], lookup: &BTreeMap<Vec<u8>, T>) -> Vec<u32> {
+    piece
+        .iter()
+        .map(|byte| match lookup.get(std::slice::from_ref(byte)) {
+            Some(token) => (*token).into(),
+            None => panic!("[ERROR]: Encoding value for byte {byte} not found!"),
+        })
+        .collect()
+}
+
 /// Encodes a byte slice into a vector of token ID vectors using BPE.
 ///
 /// This function orchestrates the entire encoding process:
 /// 1. It splits the input `slice` into initial chunks using `tokens`.
 /// 2. For each chunk, it checks if it exists as a whole token in the `lookup` table.
-/// 3. If not, it converts the chunk to graphemes and uses `BytePairEncoder` to
-///    iteratively merge subword units until no more merges are possible.
+/// 3. If not, it uses `BytePairEncoder` to iteratively merge the raw bytes of the
+///    chunk until no more merges are possible.
 /// 4. The final token IDs for each chunk are collected and returned.
 ///
 /// Each inner `Vec<u32>` corresponds to the tokens from one of the initial chunks.
+/// `tokens` splits with the GPT-2 pattern of `r50k_base` and `p50k_base`; use
+/// `encode_with` or `Vocabularies::encode` for the other vocabularies.
 pub fn encode<T: Copy + Ord + Debug + Into<u32>>(
     slice: &[u8],
     lookup: &LazyLock<BTreeMap<Vec<u8>, T>>,
 ) -> Vec<Vec<u32>> {
+    encode_with(slice, lookup, &TOKENS)
+}
+
+/// Encodes a byte slice like `encode`, splitting it with `pattern` instead of the
+/// GPT-2 pattern.
+pub fn encode_with<T: Copy + Ord + Debug + Into<u32>>(
+    slice: &[u8],
+    lookup: &LazyLock<BTreeMap<Vec<u8>, T>>,
+    pattern: &Regex,
+) -> Vec<Vec<u32>> {
+    split(slice, pattern).into_iter().map(|piece| encode_piece(piece, lookup)).collect()
+}
+
+/// Encodes a single piece split off by `tokens` or `split`.
+///
+/// Looks the whole piece up first, and otherwise merges its raw bytes with
+/// `BytePairEncoder`.
+pub fn encode_piece<T: Copy + Ord + Debug + Into<u32>>(
+    piece: &[u8],
+    lookup: &BTreeMap<Vec<u8>, T>,
+) -> Vec<u32> {
+    let piece = String::from_utf8_lossy(piece);
+    let piece = piece.as_bytes();
+    match lookup.get(piece) {
+        Some(token) => vec![<T as Into<u32>>::into(*token)],
+        None => BytePairEncoder::new(piece, lookup).run(fallback(piece, lookup)),
+    }
+}
+
+/// Encodes a byte slice with BPE-dropout.
+///
+/// Works like `encode_with`, except that every merge, including looking up a whole piece
+/// in the vocabulary, is skipped with probability `p`. The same input therefore
+/// splits into different, usually smaller, tokens on each call, which serves as
+/// subword regularization when training. Units that are not merged stay the byte
+/// tokens of the piece, so the result decodes to the original text, non-ASCII
+/// text and emoji included. With `p = 0.0` the same merges are applied as by `encode_with`.
+///
+/// # Arguments
+///
+/// * `slice` - The bytes to encode.
+/// * `lookup` - The vocabulary to encode with.
+/// * `pattern` - The pattern the vocabulary splits text with, e.g. `Vocabularies::pattern`.
+/// * `p` - The probability of skipping each merge.
+/// * `rng` - The random number generator. Seed it to make the encoding reproducible.
+///
+/// # Panics
+///
+/// Panics if `p` is not between `0.0` and `1.0`.
+pub fn encode_dropout<T: Copy + Ord + Debug + Into<u32>>(
+    slice: &[u8],
+    lookup: &LazyLock<BTreeMap<Vec<u8>, T>>,
+    pattern: &Regex,
+    p: f64,
+    rng: &mut StdRng,
+) -> Vec<Vec<u32>> {
+    assert!(
+        (0.0..=1.0).contains(&p),
+        "[ERROR]: Dropout probability {p} is not between 0 and 1."
+    );
+    let mut result = vec![];
+
+    for piece in split(slice, pattern) {
+        if let Some(token) = lookup.get(piece) {
+            if !rng.random_bool(p) {
+                result.push(vec![<T as Into<u32>>::into(*token)]);
+                continue;
+            }
+        }
+
+        let mut encoder = BytePairEncoder::new(piece, lookup);
+        encoder.dropout = Some((p, &mut *rng));
+        result.push(encoder.run(fallback(piece, lookup)));
+    }
+    result
+}
+
+/// A single merge applied while encoding a piece.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Merge {
+    /// The GPT-unicode text of the left unit of the merged pair.
+    pub left: String,
+    /// The GPT-unicode text of the right unit of the merged pair.
+    pub right: String,
+    /// The rank of the merged pair. Lower ranks are merged first.
+    pub rank: u32,
+    /// The token IDs of the piece after the merge.
+    pub tokens: Vec<u32>,
+}
+
+/// The steps taken to encode one piece of the input.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Trace {
+    /// The GPT-unicode text of the piece, as split by `tokens`.
+    pub piece: String,
+    /// The GPT-unicode graphemes the merges start from.
+    pub graphemes: Vec<String>,
+    /// Whether the whole piece was found in the vocabulary, in which case no merges are applied.
+    pub whole: bool,
+    /// The merges applied, in order.
+    pub merges: Vec<Merge>,
+    /// The final token IDs of the piece, as returned by `encode`.
+    pub tokens: Vec<u32>,
+}
+
+/// Encodes a byte slice like `encode_with`, recording every merge along the way.
+///
+/// Each `Trace` corresponds to one of the initial chunks and holds the graphemes it
+/// started from, the pair merged at every step with its rank, and the final token IDs.
+/// This makes it possible to see why a word is split the way it is.
+pub fn trace<T: Copy + Ord + Debug + Into<u32>>(
+    slice: &[u8],
+    lookup: &LazyLock<BTreeMap<Vec<u8>, T>>,
+    pattern: &Regex,
+) -> Vec<Trace> {
+    let text = |bytes: &[u8]| String::from_utf8_lossy(&unicode(bytes)).into_owned();
     let mut result = vec![];
 
-    for piece in tokens(slice) {
-        let graph = grapheme(piece);
-        if let Some(token) = lookup.get(&graph.concat()) {
-            result.push(vec![<T as Into<u32>>::into(*token)]);
+    for piece in split(slice, pattern) {
+        let piece = String::from_utf8_lossy(piece);
+        let piece = piece.as_bytes();
+        let mut trace = Trace {
+            piece: text(piece),
+            graphemes: piece.chunks(1).map(text).collect(),
+            whole: false,
+            merges: vec![],
+            tokens: vec![],
+        };
+        if let Some(token) = lookup.get(piece) {
+            trace.whole = true;
+            trace.tokens = vec![<T as Into<u32>>::into(*token)];
+            result.push(trace);
             continue;
         }
 
-        let merge = graph
-            .iter()
-            .flat_map(|g| g.iter().map(|r| *r as u32))
-            .collect();
-        let encoder = BytePairEncoder::new(graph, lookup);
-        match encoder.last() {
-            None => result.push(merge),
-            Some(merge) => result.push(merge),
+        trace.tokens = fallback(piece, lookup);
+        let mut encoder = BytePairEncoder::new(piece, lookup);
+        while let Some(tokens) = encoder.next() {
+            if let Some((left, right, rank)) = encoder.merged.take() {
+                trace.merges.push(Merge {
+                    left: text(&piece[left]),
+                    right: text(&piece[right]),
+                    rank,
+                    tokens: tokens.clone(),
+                });
+            }
+            trace.tokens = tokens;
         }
+        result.push(trace);
     }
     result
 }
 
+/// The bytes written in place of a token by `Policy::Replace`: the UTF-8 encoding of `U+FFFD`.
+pub const REPLACEMENT: &[u8] = "\u{FFFD}".as_bytes();
+
+/// How decoding handles token IDs that are missing from the vocabulary.
+///
+/// The CLI applies the same policies to input that cannot be parsed as a token ID.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Stop and report the first unknown token.
+    #[default]
+    Error,
+    /// Leave unknown tokens out of the output.
+    Skip,
+    /// Write the `REPLACEMENT` marker in place of unknown tokens.
+    Replace,
+}
+
+impl Policy {
+    /// Resolves the policy named by a request, `Policy::Error` if it names none.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is not a valid policy.
+    pub fn resolve(name: Option<&str>) -> Result<Policy, String> {
+        name.map_or(Ok(Policy::default()), str::parse)
+    }
+}
+
+impl std::str::FromStr for Policy {
+    type Err = String;
+
+    /// Parses a string into a `Policy`.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The string to parse. Case-insensitive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the string is not a valid policy.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Policy::Error),
+            "skip" => Ok(Policy::Skip),
+            "replace" => Ok(Policy::Replace),
+            _ => Err(format!(
+                "unknown policy: {s}. Please use one of: error, skip, replace"
+            )),
+        }
+    }
+}
+
+impl TryFrom<u8> for Policy {
+    type Error = String;
+
+    /// Converts the policy codes used over FFI: `0` for error, `1` for skip and `2` for replace.
+    fn try_from(value: u8) -> Result<Self, String> {
+        match value {
+            0 => Ok(Policy::Error),
+            1 => Ok(Policy::Skip),
+            2 => Ok(Policy::Replace),
+            _ => Err(format!("unknown policy code: {value}")),
+        }
+    }
+}
+
 /// Decodes a slice of token IDs back into a byte vector.
 ///
 /// This function reverses the encoding process:
 /// 1. It looks up each token ID in the `lookup` table to get its corresponding "safe"
 ///    Unicode characters.
-/// 2. It segments the resulting Unicode string into graphemes.
-/// 3. Each grapheme is then mapped back to its original raw byte value using the
+/// 2. Each character is then mapped back to its original raw byte value using the
 ///    `BYTES_TO_UNICODE` map.
+///
+/// # Panics
+///
+/// Panics if a token ID is not in the vocabulary. Use `decode_lenient` to handle
+/// unknown IDs instead.
 pub fn decode<T: Copy + Ord + Debug + Display>(
     tokens: &[T],
     lookup: &LazyLock<BTreeMap<T, Vec<u16>>>,
 ) -> Vec<u8> {
-    tokens
-        .iter()
-        .flat_map(|token| {
-            let unicode_chars = lookup
-                .get(token)
-                .unwrap_or_else(|| panic!("[ERROR]: Token ID {token:?} not found."));
-
-            let gpt_unicode_bytes: Vec<u8> = unicode_chars.iter().map(|&c| c as u8).collect();
-            let gpt_unicode_string = String::from_utf8(gpt_unicode_bytes).unwrap();
-
-            UnicodeSegmentation::graphemes(gpt_unicode_string.as_str(), true)
-                .map(|grapheme_str| {
-                    let grapheme_bytes = grapheme_str.as_bytes();
-                    *BYTES_TO_UNICODE.get(grapheme_bytes).unwrap_or_else(|| {
-                        panic!("[ERROR]: Decoding value for '{grapheme_str}' not found!")
-                    }) as u8
-                })
-                .collect::<Vec<u8>>()
-        })
-        .collect()
+    decode_lenient(tokens, lookup, Policy::Error).unwrap_or_else(|e| panic!("[ERROR]: {e}"))
+}
+
+/// Decodes a slice of token IDs back into a byte vector, handling unknown IDs with `policy`.
+///
+/// # Errors
+///
+/// Returns an error naming the first token ID missing from the vocabulary when
+/// `policy` is `Policy::Error`.
+pub fn decode_lenient<T: Copy + Ord + Debug + Display>(
+    tokens: &[T],
+    lookup: &BTreeMap<T, Vec<u16>>,
+    policy: Policy,
+) -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        match (lookup.get(token), policy) {
+            // Writing to a `Vec` cannot fail.
+            (Some(unicode_chars), _) => {
+                write_token(unicode_chars, &mut result).unwrap();
+            }
+            (None, Policy::Error) => return Err(format!("Token ID {token:?} not found.")),
+            (None, Policy::Skip) => continue,
+            (None, Policy::Replace) => result.extend_from_slice(REPLACEMENT),
+        };
+    }
+    Ok(result)
+}
+
+/// Decodes a slice of token IDs straight into a writer, without building the output
+/// in memory first.
+///
+/// # Arguments
+///
+/// * `tokens` - The token IDs to decode.
+/// * `lookup` - The decoding table of the vocabulary.
+/// * `writer` - Where the bytes are written, e.g. a file, a socket or a `&mut [u8]`.
+///
+/// # Returns
+///
+/// The number of bytes written.
+///
+/// # Errors
+///
+/// Returns an `InvalidInput` error if a token ID is not in the vocabulary, and any
+/// error of the writer, e.g. `WriteZero` if a `&mut [u8]` is too small. Bytes of the
+/// tokens before the failing one may already have been written.
+pub fn decode_into<T: Copy + Ord + Debug + Display, W: std::io::Write>(
+    tokens: &[T],
+    lookup: &BTreeMap<T, Vec<u16>>,
+    writer: &mut W,
+) -> std::io::Result<usize> {
+    let mut written = 0;
+    for token in tokens {
+        let unicode_chars = lookup.get(token).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Token ID {token:?} not found."))
+        })?;
+        written += write_token(unicode_chars, writer)?;
+    }
+    Ok(written)
+}
+
+/// Writes the raw bytes of one token, given the UTF-8 bytes of its GPT-unicode key.
+///
+/// The bytes are gathered in a stack buffer and written with one call per token.
+///
+/// # Panics
+///
+/// Panics if a character of the key is not in the `BYTES_TO_UNICODE` map.
+fn write_token<W: std::io::Write>(unicode_chars: &[u16], writer: &mut W) -> std::io::Result<usize> {
+    let mut buffer = [0u8; 256];
+    let (mut length, mut written, mut idx) = (0, 0, 0);
+    while idx < unicode_chars.len() {
+        let width = match unicode_chars[idx] as u8 {
+            0x00..=0x7F => 1,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            _ => 4,
+        }
+        .min(unicode_chars.len() - idx);
+        let mut symbol = [0u8; 4];
+        for (byte, &c) in symbol.iter_mut().zip(&unicode_chars[idx..idx + width]) {
+            *byte = c as u8;
+        }
+        let symbol = &symbol[..width];
+        let byte = BYTES_TO_UNICODE.get(symbol).unwrap_or_else(|| {
+            panic!("[ERROR]: Decoding value for '{}' not found!", String::from_utf8_lossy(symbol))
+        });
+        buffer[length] = *byte as u8;
+        length += 1;
+        idx += width;
+        if length == buffer.len() {
+            writer.write_all(&buffer)?;
+            (written, length) = (written + length, 0);
+        };
+    }
+    writer.write_all(&buffer[..length])?;
+    Ok(written + length)
 }
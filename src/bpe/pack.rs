@@ -0,0 +1,149 @@
+//! Packs prioritised text segments into a token budget.
+//!
+//! A prompt is assembled from segments such as a system prompt, retrieved passages
+//! and conversation history, each with a priority and a minimum and maximum number of
+//! tokens. `pack` encodes every segment on its own and, while the prompt is over the
+//! budget, takes tokens from the lowest-priority segment still in it: first down to its
+//! minimum, then by dropping it altogether. Tokens freed by a drop that were not needed
+//! are handed back from the highest priority down. Segments are only ever cut at token
+//! boundaries.
+
+use super::vocabulary::Vocabularies;
+use super::Policy;
+
+/// A segment of a prompt.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// The text of the segment.
+    pub text: String,
+    /// The priority of the segment. Lower priorities are cut first; among equal
+    /// priorities, later segments are cut first.
+    #[serde(default)]
+    pub priority: i64,
+    /// The fewest tokens the segment is useful with. A segment is dropped rather than
+    /// truncated below this.
+    #[serde(default)]
+    pub min: usize,
+    /// The most tokens the segment may take, if limited.
+    #[serde(default)]
+    pub max: Option<usize>,
+}
+
+/// What happened to a segment.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// The segment was kept whole.
+    Kept,
+    /// The segment was cut to fewer tokens.
+    Truncated,
+    /// The segment was left out.
+    Dropped,
+}
+
+/// The report of a single segment.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    /// The index of the segment in the input.
+    pub index: usize,
+    /// The priority of the segment.
+    pub priority: i64,
+    /// The number of tokens of the whole segment.
+    pub tokens: usize,
+    /// The number of tokens kept.
+    pub kept: usize,
+    /// What happened to the segment.
+    pub outcome: Outcome,
+}
+
+/// A packed prompt.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Pack {
+    /// The packed text, with the kept part of every segment in input order.
+    pub text: String,
+    /// The token IDs of the kept parts, in input order.
+    pub ids: Vec<u32>,
+    /// The number of tokens used.
+    pub tokens: usize,
+    /// The token budget.
+    pub budget: usize,
+    /// What happened to each segment, in input order.
+    pub segments: Vec<Report>,
+}
+
+/// Packs segments into a token budget.
+///
+/// # Arguments
+///
+/// * `segments` - The segments of the prompt, in the order they appear in it.
+/// * `budget` - The most tokens the packed prompt may have.
+/// * `vocabulary` - The vocabulary to count tokens with.
+///
+/// # Returns
+///
+/// The packed text and IDs, and a report of what was kept, truncated or dropped. The
+/// IDs are the concatenated encodings of the segments; re-encoding the packed text as
+/// a whole may tokenize differently where two segments meet.
+pub fn pack(segments: &[Segment], budget: usize, vocabulary: &Vocabularies) -> Pack {
+    let encoded: Vec<Vec<u32>> = segments
+        .iter()
+        .map(|segment| vocabulary.encode(segment.text.as_bytes()).concat())
+        .collect();
+    let caps: Vec<usize> = segments
+        .iter()
+        .zip(&encoded)
+        .map(|(segment, ids)| segment.max.map_or(ids.len(), |max| max.min(ids.len())))
+        .collect();
+    let floors: Vec<usize> = segments.iter().zip(&caps).map(|(segment, &cap)| segment.min.min(cap)).collect();
+
+    let mut order: Vec<usize> = (0..segments.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(segments[i].priority));
+
+    // Cut the lowest-priority segments, down to their minimum and then altogether.
+    let mut allowances: Vec<Option<usize>> = caps.iter().copied().map(Some).collect();
+    let mut used: usize = caps.iter().sum();
+    for &i in order.iter().rev() {
+        if used <= budget {
+            break;
+        };
+        let cut = (used - budget).min(caps[i] - floors[i]);
+        used -= cut;
+        if used > budget {
+            used -= floors[i];
+            allowances[i] = None;
+        } else {
+            allowances[i] = Some(caps[i] - cut);
+        };
+    }
+
+    // Hand tokens freed by the last drop back, from the highest priority down.
+    for &i in &order {
+        if let Some(allowance) = allowances[i].as_mut() {
+            let extra = (caps[i] - *allowance).min(budget - used);
+            *allowance += extra;
+            used += extra;
+        };
+    }
+
+    let mut pack = Pack { text: String::new(), ids: vec![], tokens: used, budget, segments: vec![] };
+    for (i, segment) in segments.iter().enumerate() {
+        let kept = allowances[i].unwrap_or(0);
+        let outcome = match allowances[i] {
+            None => Outcome::Dropped,
+            Some(0) if !encoded[i].is_empty() => Outcome::Dropped,
+            Some(kept) if kept < encoded[i].len() => Outcome::Truncated,
+            Some(_) => Outcome::Kept,
+        };
+        match outcome {
+            Outcome::Kept => pack.text.push_str(&segment.text),
+            Outcome::Truncated => {
+                let bytes = vocabulary.decode(&encoded[i][..kept], Policy::Replace).unwrap_or_default();
+                pack.text.push_str(&String::from_utf8_lossy(&bytes));
+            }
+            Outcome::Dropped => {}
+        };
+        pack.ids.extend(&encoded[i][..kept]);
+        pack.segments.push(Report { index: i, priority: segment.priority, tokens: encoded[i].len(), kept, outcome });
+    }
+    pack
+}
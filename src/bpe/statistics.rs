@@ -0,0 +1,201 @@
+//! Collects token statistics over a corpus.
+//!
+//! A `Statistics` accumulator is fed one line at a time, either with `encode`, which
+//! runs the line through `bpe::encode`, or with `add` for lines that are already
+//! encoded. Once the corpus has been streamed through, `summary` resolves the bytes of
+//! every token seen and reports the averages, the longest tokens, the share of
+//! byte-fallback tokens and the full frequency table.
+
+use super::encode;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+use std::sync::LazyLock;
+
+/// The number of longest tokens listed in a `Summary`.
+const LONGEST: usize = 10;
+
+/// Accumulates token counts over a stream of lines.
+///
+/// Lines are grouped into documents separated by empty lines.
+#[derive(Debug, Default, Clone)]
+pub struct Statistics {
+    /// The number of non-empty lines seen.
+    lines: usize,
+    /// The number of documents seen.
+    documents: usize,
+    /// Whether the last line seen was empty, so the next non-empty line starts a document.
+    separated: bool,
+    /// The total number of bytes seen.
+    bytes: usize,
+    /// The total number of characters seen.
+    characters: usize,
+    /// The number of times each token ID was seen.
+    frequencies: BTreeMap<u32, usize>,
+}
+
+/// A token of the frequency table.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Frequency {
+    /// The token ID.
+    pub id: u32,
+    /// The number of times the token was seen.
+    pub count: usize,
+    /// The number of bytes the token stands for.
+    pub bytes: usize,
+    /// The text of the token, with invalid UTF-8 replaced by `U+FFFD`.
+    pub text: String,
+}
+
+/// The statistics of a corpus.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Summary {
+    /// The number of non-empty lines.
+    pub lines: usize,
+    /// The number of documents, i.e. runs of lines separated by empty lines.
+    pub documents: usize,
+    /// The number of tokens.
+    pub tokens: usize,
+    /// The number of distinct tokens.
+    pub distinct: usize,
+    /// The number of bytes.
+    pub bytes: usize,
+    /// The number of characters.
+    pub characters: usize,
+    /// The average number of tokens per line.
+    pub tokens_per_line: f64,
+    /// The average number of tokens per document.
+    pub tokens_per_document: f64,
+    /// The average number of characters per token.
+    pub characters_per_token: f64,
+    /// The share of tokens that are byte-fallback tokens, i.e. not valid UTF-8 on their own.
+    pub fallback: f64,
+    /// The longest distinct tokens seen, by number of bytes.
+    pub longest: Vec<Frequency>,
+    /// Every token seen, most frequent first.
+    pub frequencies: Vec<Frequency>,
+}
+
+impl Statistics {
+    /// Creates an empty `Statistics`.
+    pub fn new() -> Self {
+        Statistics::default()
+    }
+
+    /// Records a line that has already been encoded.
+    ///
+    /// An empty line ends the current document.
+    pub fn add(&mut self, line: &[u8], ids: &[u32]) {
+        if line.is_empty() {
+            self.separated = true;
+            return;
+        };
+        if self.separated || self.documents == 0 {
+            self.documents += 1;
+            self.separated = false;
+        };
+        self.lines += 1;
+        self.bytes += line.len();
+        self.characters += String::from_utf8_lossy(line).chars().count();
+        for id in ids {
+            *self.frequencies.entry(*id).or_default() += 1;
+        }
+    }
+
+    /// Encodes a line with `bpe::encode` and records it.
+    ///
+    /// # Returns
+    ///
+    /// The token IDs of the line.
+    pub fn encode<T: Copy + Ord + Debug + Into<u32>>(
+        &mut self,
+        line: &[u8],
+        lookup: &LazyLock<BTreeMap<Vec<u8>, T>>,
+    ) -> Vec<u32> {
+        let ids = encode(line, lookup).concat();
+        self.add(line, &ids);
+        ids
+    }
+
+    /// Summarises the lines recorded so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `lookup` - The decoding table of the vocabulary the lines were encoded with,
+    ///   used to resolve the bytes of each token.
+    pub fn summary<T>(&self, lookup: &LazyLock<BTreeMap<T, Vec<u16>>>) -> Summary
+    where
+        T: TryFrom<u32> + Copy + Ord + Debug + Display,
+    {
+        let ratio = |a: usize, b: usize| if b == 0 { 0.0 } else { a as f64 / b as f64 };
+        let mut fallback = 0;
+        let mut frequencies: Vec<Frequency> = self
+            .frequencies
+            .iter()
+            .map(|(&id, &count)| {
+                let bytes = match T::try_from(id).ok() {
+                    Some(token) => super::decode_lenient(&[token], lookup, super::Policy::Replace)
+                        .expect("[ERROR]: Replacing unknown tokens cannot fail."),
+                    None => super::REPLACEMENT.to_vec(),
+                };
+                if std::str::from_utf8(&bytes).is_err() {
+                    fallback += count;
+                };
+                Frequency { id, count, bytes: bytes.len(), text: String::from_utf8_lossy(&bytes).into_owned() }
+            })
+            .collect();
+        frequencies.sort_by(|a, b| b.count.cmp(&a.count).then(a.id.cmp(&b.id)));
+
+        let mut longest = frequencies.clone();
+        longest.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.id.cmp(&b.id)));
+        longest.truncate(LONGEST);
+
+        let tokens = self.frequencies.values().sum();
+        Summary {
+            lines: self.lines,
+            documents: self.documents,
+            tokens,
+            distinct: self.frequencies.len(),
+            bytes: self.bytes,
+            characters: self.characters,
+            tokens_per_line: ratio(tokens, self.lines),
+            tokens_per_document: ratio(tokens, self.documents),
+            characters_per_token: ratio(self.characters, tokens),
+            fallback: ratio(fallback, tokens),
+            longest,
+            frequencies,
+        }
+    }
+}
+
+impl Summary {
+    /// Writes the summary as CSV.
+    ///
+    /// The totals and averages come first as `#`-prefixed comment lines, followed by
+    /// the frequency table with an `id,count,bytes,text` header.
+    pub fn to_csv(&self) -> String {
+        let quote = |text: &str| format!("\"{}\"", text.replace('"', "\"\""));
+        let mut csv = format!(
+            "# lines: {}\n# documents: {}\n# tokens: {}\n# distinct: {}\n# bytes: {}\n# characters: {}\n\
+             # tokens_per_line: {}\n# tokens_per_document: {}\n# characters_per_token: {}\n# fallback: {}\n",
+            self.lines,
+            self.documents,
+            self.tokens,
+            self.distinct,
+            self.bytes,
+            self.characters,
+            self.tokens_per_line,
+            self.tokens_per_document,
+            self.characters_per_token,
+            self.fallback
+        );
+        csv += &format!(
+            "# longest: {}\n",
+            self.longest.iter().map(|f| f.id.to_string()).collect::<Vec<_>>().join(" ")
+        );
+        csv += "id,count,bytes,text\n";
+        for frequency in &self.frequencies {
+            csv += &format!("{},{},{},{}\n", frequency.id, frequency.count, frequency.bytes, quote(&frequency.text));
+        }
+        csv
+    }
+}
@@ -0,0 +1,157 @@
+//! A byte trie over the tokens of a vocabulary.
+//!
+//! The `*_TOKENS` tables only answer exact lookups. Constrained
+//! decoding needs to know which tokens are consistent with a partial string, so this
+//! module indexes every token by its raw bytes (not its GPT-unicode key) in a trie that
+//! answers prefix enumeration, longest-match and compatibility queries.
+
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+/// A node of the trie.
+#[derive(Debug, Default, Clone)]
+struct Node {
+    /// The token ending at this node, if any.
+    token: Option<u32>,
+    /// The children of this node by their byte, sorted by byte.
+    children: Vec<(u8, usize)>,
+}
+
+/// A byte trie mapping the raw bytes of tokens to their IDs.
+#[derive(Debug, Clone)]
+pub struct Trie {
+    /// The nodes of the trie; the root is the first.
+    nodes: Vec<Node>,
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Trie { nodes: vec![Node::default()] }
+    }
+}
+
+impl Trie {
+    /// Creates an empty `Trie`.
+    pub fn new() -> Self {
+        Trie::default()
+    }
+
+    /// Builds a trie from a decoding table, converting the GPT-unicode text of each
+    /// token back to its raw bytes.
+    pub fn from_unicodes<T: Copy + Into<u32>>(lookup: &LazyLock<BTreeMap<T, Vec<u16>>>) -> Self {
+        let mut trie = Trie::new();
+        for (&token, unicodes) in lookup.iter() {
+            let key: Vec<u8> = unicodes.iter().map(|&unicode| unicode as u8).collect();
+            trie.insert(&super::raw(&String::from_utf8_lossy(&key)), token.into());
+        }
+        trie
+    }
+
+    /// Inserts a token, replacing the ID of an existing token with the same bytes.
+    pub fn insert(&mut self, bytes: &[u8], token: u32) {
+        let mut node = 0;
+        for &byte in bytes {
+            node = match self.child(node, byte) {
+                Some(child) => child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    let children = &mut self.nodes[node].children;
+                    let position = children.partition_point(|&(b, _)| b < byte);
+                    children.insert(position, (byte, child));
+                    child
+                }
+            };
+        }
+        self.nodes[node].token = Some(token);
+    }
+
+    /// Returns the child of `node` for `byte`.
+    fn child(&self, node: usize, byte: u8) -> Option<usize> {
+        let children = &self.nodes[node].children;
+        children
+            .binary_search_by_key(&byte, |&(b, _)| b)
+            .ok()
+            .map(|position| children[position].1)
+    }
+
+    /// Returns the node reached by following `bytes` from the root.
+    fn find(&self, bytes: &[u8]) -> Option<usize> {
+        bytes.iter().try_fold(0, |node, &byte| self.child(node, byte))
+    }
+
+    /// Returns the ID of the token with exactly these bytes.
+    pub fn get(&self, bytes: &[u8]) -> Option<u32> {
+        self.find(bytes).and_then(|node| self.nodes[node].token)
+    }
+
+    /// Enumerates every token that starts with `prefix`, including `prefix` itself.
+    ///
+    /// # Returns
+    ///
+    /// The bytes and ID of each token, in byte order.
+    pub fn starting_with(&self, prefix: &[u8]) -> Vec<(Vec<u8>, u32)> {
+        let mut tokens = vec![];
+        let Some(start) = self.find(prefix) else {
+            return tokens;
+        };
+        let mut stack = vec![(start, prefix.to_vec())];
+        while let Some((node, bytes)) = stack.pop() {
+            if let Some(token) = self.nodes[node].token {
+                tokens.push((bytes.clone(), token));
+            };
+            for &(byte, child) in self.nodes[node].children.iter().rev() {
+                stack.push((child, [bytes.as_slice(), &[byte]].concat()));
+            }
+        }
+        tokens
+    }
+
+    /// Returns every token that is a prefix of `bytes`.
+    ///
+    /// # Returns
+    ///
+    /// The length and ID of each token, shortest first.
+    pub fn prefixes_of(&self, bytes: &[u8]) -> Vec<(usize, u32)> {
+        let mut tokens = vec![];
+        let mut node = 0;
+        for (length, &byte) in bytes.iter().enumerate() {
+            match self.child(node, byte) {
+                Some(child) => node = child,
+                None => break,
+            };
+            if let Some(token) = self.nodes[node].token {
+                tokens.push((length + 1, token));
+            };
+        }
+        tokens
+    }
+
+    /// Returns the longest token that is a prefix of `bytes`.
+    ///
+    /// # Returns
+    ///
+    /// The length and ID of the token, or `None` if no token matches.
+    pub fn longest_match(&self, bytes: &[u8]) -> Option<(usize, u32)> {
+        self.prefixes_of(bytes).pop()
+    }
+
+    /// Returns every token consistent with a partial byte string, i.e. every token that
+    /// could come next if the output has to continue with `partial`: tokens that are a
+    /// prefix of `partial` and tokens that start with it.
+    ///
+    /// # Returns
+    ///
+    /// The IDs of the tokens in ascending order.
+    pub fn compatible(&self, partial: &[u8]) -> Vec<u32> {
+        let mut tokens: Vec<u32> = self
+            .prefixes_of(partial)
+            .into_iter()
+            .map(|(_, token)| token)
+            .chain(self.starting_with(partial).into_iter().map(|(_, token)| token))
+            .collect();
+        tokens.sort_unstable();
+        tokens.dedup();
+        tokens
+    }
+}
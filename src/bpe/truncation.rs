@@ -0,0 +1,134 @@
+//! Truncates text to a token limit without overrunning it.
+//!
+//! Cutting a string by characters and re-encoding it can change the last tokens, so a
+//! cut that looks right may still need more tokens than allowed. `truncate` instead
+//! searches for the longest cut, at grapheme boundaries, whose re-encoding with the
+//! ellipsis marker fits the limit, so the result is guaranteed to fit.
+
+use super::vocabulary::Vocabularies;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The marker inserted where text was removed.
+pub const ELLIPSIS: &str = "...";
+
+/// The side of the text to remove.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Side {
+    /// Removes text from the start, keeping the end.
+    Head,
+    /// Removes text from the end, keeping the start.
+    #[default]
+    Tail,
+    /// Removes text from the middle, keeping the start and the end.
+    Middle,
+}
+
+impl std::str::FromStr for Side {
+    type Err = String;
+
+    /// Parses a string into a `Side`.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The string to parse. Case-insensitive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the string is not a valid side.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "head" => Ok(Side::Head),
+            "tail" => Ok(Side::Tail),
+            "middle" => Ok(Side::Middle),
+            _ => Err(format!("unknown side: {s}. Please use one of: head, tail, middle")),
+        }
+    }
+}
+
+impl TryFrom<u8> for Side {
+    type Error = String;
+
+    /// Converts an FFI side code into a `Side`: `0` is `Head`, `1` is `Tail` and
+    /// `2` is `Middle`.
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        match code {
+            0 => Ok(Side::Head),
+            1 => Ok(Side::Tail),
+            2 => Ok(Side::Middle),
+            _ => Err(format!("unknown side code: {code}")),
+        }
+    }
+}
+
+/// A truncated text.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct Truncation {
+    /// The truncated text, with `ELLIPSIS` where text was removed.
+    pub text: String,
+    /// The token IDs of the truncated text.
+    pub ids: Vec<u32>,
+    /// The byte range of the input that was removed, or `None` if the input fit.
+    pub removed: Option<std::ops::Range<usize>>,
+}
+
+/// Truncates text so that it encodes to at most `max_tokens` tokens.
+///
+/// # Arguments
+///
+/// * `text` - The text to truncate.
+/// * `max_tokens` - The most tokens the result may encode to.
+/// * `side` - The side of the text to remove.
+/// * `vocabulary` - The vocabulary to count tokens with.
+///
+/// # Returns
+///
+/// The input unchanged if it already fits. Otherwise, the longest cut of the input
+/// that fits together with `ELLIPSIS`, or an empty text if not even the marker fits.
+/// The IDs are those of the returned text, so `ids.len() <= max_tokens` always holds.
+pub fn truncate(text: &str, max_tokens: usize, side: Side, vocabulary: &Vocabularies) -> Truncation {
+    let encode = |text: &str| vocabulary.encode(text.as_bytes()).concat();
+    let ids = encode(text);
+    if ids.len() <= max_tokens {
+        return Truncation { text: text.to_string(), ids, removed: None };
+    };
+
+    let boundaries: Vec<usize> = text
+        .grapheme_indices(true)
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(text.len()))
+        .collect();
+    let graphemes = boundaries.len() - 1;
+
+    // Keeps `kept` graphemes of the input and removes the rest.
+    let cut = |kept: usize| -> (String, std::ops::Range<usize>) {
+        let (start, end) = match side {
+            Side::Head => (0, boundaries[graphemes - kept]),
+            Side::Tail => (boundaries[kept], text.len()),
+            Side::Middle => (boundaries[kept.div_ceil(2)], boundaries[graphemes - kept / 2]),
+        };
+        (format!("{}{ELLIPSIS}{}", &text[..start], &text[end..]), start..end)
+    };
+
+    let (marker, removed) = cut(0);
+    let marker_ids = encode(&marker);
+    if marker_ids.len() > max_tokens {
+        return Truncation { text: String::new(), ids: vec![], removed: Some(0..text.len()) };
+    };
+
+    // Binary search for the most graphemes that still fit, only ever settling on a
+    // cut that was encoded and checked.
+    let mut best = Truncation { text: marker, ids: marker_ids, removed: Some(removed) };
+    let (mut low, mut high) = (0, graphemes - 1);
+    while low < high {
+        let middle = (low + high).div_ceil(2);
+        let (candidate, removed) = cut(middle);
+        let ids = encode(&candidate);
+        if ids.len() <= max_tokens {
+            best = Truncation { text: candidate, ids, removed: Some(removed) };
+            low = middle;
+        } else {
+            high = middle - 1;
+        };
+    }
+    best
+}
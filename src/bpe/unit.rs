@@ -177,8 +177,8 @@ pub(crate) mod tokens {
                 vec![32, 105, 111, 110, 104, 107, 114],
                 vec![59],
                 vec![110],
-                vec![32, 32, 121],
-                vec![111],
+                vec![32],
+                vec![32, 121, 111],
                 vec![32, 52, 48, 57],
                 vec![106, 111, 105],
                 vec![51, 52, 53],
@@ -322,7 +322,8 @@ pub(crate) mod tokens {
                 vec![63],
                 vec![32, 58, 60],
                 vec![114],
-                vec![9, 98],
+                vec![9],
+                vec![98],
                 vec![44],
                 vec![112, 114, 116, 118],
                 vec![51],
@@ -338,8 +339,8 @@ pub(crate) mod tokens {
                 vec![111, 121, 121],
                 vec![59],
                 vec![110],
-                vec![32, 32, 121],
-                vec![111],
+                vec![32],
+                vec![32, 121, 111],
                 vec![32, 52, 48, 57],
                 vec![106, 111, 105]
             ]
@@ -349,6 +350,43 @@ pub(crate) mod tokens {
 
 #[cfg(test)]
 pub(crate) mod encoder {
+    #[test]
+    pub (crate) fn test_encode_line_breaks() {
+        // A run of whitespace before a word leaves its last character to the word, as
+        // GPT-2's `\s+(?!\S)` does, so "world" stays one token after a line break.
+        let input = b"hello\nworld\n\tx\n";
+        let result = crate::bpe::encode(input, &crate::bpe::vocabulary::P50K_TOKENS).concat();
+        assert_eq!(result, vec![31373, 198, 6894, 198, 197, 87, 198]);
+        let tokens: Vec<u16> = result.iter().map(|&t| t as u16).collect();
+        assert_eq!(crate::bpe::decode(&tokens, &crate::bpe::vocabulary::P50K_UNICODES), input);
+        let pieces = crate::bpe::tokens(b"a  b\n\n c ");
+        assert_eq!(pieces, vec![&b"a"[..], b" ", b" b", b"\n\n", b" c", b" "]);
+    }
+
+    #[test]
+    pub (crate) fn test_encode_piece_without_merges() {
+        // Every unit of these pieces is a token, but no pair of them merges. They used to
+        // encode to the bytes of their GPT-unicode text instead of the IDs of their units.
+        let piece = crate::bpe::encode_piece(b"\ti", &crate::bpe::vocabulary::R50K_TOKENS);
+        assert_eq!(piece, vec![197, 72]);
+        let piece = crate::bpe::encode_piece(b"\nw", &crate::bpe::vocabulary::P50K_TOKENS);
+        assert_eq!(piece, vec![198, 86]);
+    }
+
+    #[test]
+    pub (crate) fn test_encode_non_ascii_round_trip() {
+        // Byte tokens are stored as decimal keys like "195". They used to load as the text
+        // "195", so pieces that are not whole tokens encoded to IDs that are not tokens.
+        let inputs = ["héllo wörld", "👋 hello 🌍.", "日本語のテキスト", "naïve café — «ok»", "\u{1F468}\u{200D}\u{1F469}"];
+        for vocabulary in crate::bpe::vocabulary::Vocabularies::iter().filter(|v| v.available()) {
+            for input in inputs {
+                let ids = vocabulary.encode(input.as_bytes()).concat();
+                let decoded = vocabulary.decode(&ids, crate::bpe::Policy::Error).unwrap();
+                assert_eq!(String::from_utf8(decoded).unwrap(), input, "{vocabulary}");
+            }
+        }
+    }
+
     #[test]
     pub (crate) fn test_encode_empty() {
         let input = b"";
@@ -368,13 +406,8 @@ pub(crate) mod encoder {
         // let input = "👋 🌍".as_bytes();
         let input = b"\xF0\x9F\x91\x8B \xF0\x9F\x8C\x8D";
         let result = crate::bpe::encode(input, &crate::bpe::vocabulary::P50K_TOKENS).concat();
-        assert_eq!(
-            result,
-            vec![
-                195, 176, 197, 129, 196, 179, 196, 173, 196, 160, 195, 176, 197, 129, 196, 174,
-                196, 175
-            ]
-        );
+        // The emoji are not whole tokens and merge from their byte tokens.
+        assert_eq!(result, vec![41840, 233, 12520, 234, 235]);
     }
 
     #[test]
@@ -383,10 +416,7 @@ pub(crate) mod encoder {
         let input = b"hello \xF0\x9F\x91\x8B world \xF0\x9F\x8C\x8D.";
         assert_eq!(
             crate::bpe::encode(input, &crate::bpe::vocabulary::P50K_TOKENS).concat(),
-            vec![
-                31373, 196, 160, 195, 176, 197, 129, 196, 179, 196, 173, 995, 196, 160, 195, 176,
-                197, 129, 196, 174, 196, 175, 46
-            ]
+            vec![31373, 50169, 233, 995, 12520, 234, 235, 13]
         );
     }
 
@@ -413,13 +443,8 @@ pub(crate) mod encoder {
         let input = b"Pneumonoultramicroscopicsilicovolcanoconiosis";
         assert_eq!(
             crate::bpe::encode(input, &crate::bpe::vocabulary::P50K_TOKENS).concat(),
-            // [Openai](https://platform.openai.com/tokenizer) tokenizer output. 
-            // But our merge table is not large enough to capture all the merges.
-            // , vec![47, 25668, 261, 25955, 859, 2500, 1416, 404, 873, 41896, 709, 349, 5171, 36221, 42960]
-            vec![
-                47, 710, 388, 261, 280, 75, 83, 859, 291, 305, 82, 22163, 873, 346, 291, 709, 349,
-                66, 272, 420, 261, 4267, 271
-            ]
+            // [Openai](https://platform.openai.com/tokenizer) tokenizer output.
+            vec![47, 25668, 261, 25955, 859, 2500, 1416, 404, 873, 41896, 709, 349, 5171, 36221, 42960]
         );
     }
 }
@@ -484,4 +509,694 @@ pub(crate) mod decoder {
             .as_bytes()
         );
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod lenient {
+    #[test]
+    pub (crate) fn test_decode_lenient_error() {
+        let result = crate::bpe::decode_lenient(
+            &[1616, 60000, 13],
+            &crate::bpe::vocabulary::P50K_UNICODES,
+            crate::bpe::Policy::Error,
+        );
+        assert_eq!(result, Err("Token ID 60000 not found.".to_string()));
+    }
+
+    #[test]
+    pub (crate) fn test_decode_lenient_skip() {
+        let result = crate::bpe::decode_lenient(
+            &[1616, 60000, 13],
+            &crate::bpe::vocabulary::P50K_UNICODES,
+            crate::bpe::Policy::Skip,
+        );
+        assert_eq!(result.unwrap(), b"let.");
+    }
+
+    #[test]
+    pub (crate) fn test_decode_lenient_replace() {
+        let result = crate::bpe::decode_lenient(
+            &[1616, 60000, 13],
+            &crate::bpe::vocabulary::P50K_UNICODES,
+            crate::bpe::Policy::Replace,
+        );
+        assert_eq!(result.unwrap(), "let\u{FFFD}.".as_bytes());
+    }
+
+    #[test]
+    #[should_panic(expected = "[ERROR]: Token ID 60000 not found.")]
+    pub (crate) fn test_decode_unknown_panics() {
+        crate::bpe::decode(&[1616, 60000], &crate::bpe::vocabulary::P50K_UNICODES);
+    }
+
+    #[test]
+    pub (crate) fn test_policy_parsing() {
+        assert_eq!("Skip".parse::<crate::bpe::Policy>(), Ok(crate::bpe::Policy::Skip));
+        assert!("drop".parse::<crate::bpe::Policy>().is_err());
+        assert_eq!(crate::bpe::Policy::try_from(2), Ok(crate::bpe::Policy::Replace));
+        assert!(crate::bpe::Policy::try_from(3).is_err());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod into {
+    #[test]
+    pub (crate) fn test_decode_into_matches_decode() {
+        let tokens = [1616, 612, 307, 1657, 13, 50159];
+        let mut output = vec![];
+        let written =
+            crate::bpe::decode_into(&tokens, &crate::bpe::vocabulary::P50K_UNICODES, &mut output).unwrap();
+        assert_eq!(output, crate::bpe::decode(&tokens, &crate::bpe::vocabulary::P50K_UNICODES));
+        assert_eq!(written, output.len());
+    }
+
+    #[test]
+    pub (crate) fn test_decode_into_slice_too_small() {
+        let mut buffer = [0u8; 8];
+        let mut slice = &mut buffer[..];
+        let result = crate::bpe::decode_into(
+            &[1616, 612, 307, 1657, 13],
+            &crate::bpe::vocabulary::P50K_UNICODES,
+            &mut slice,
+        );
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::WriteZero);
+        assert_eq!(&buffer, b"let ther");
+    }
+
+    #[test]
+    pub (crate) fn test_decode_into_unknown() {
+        let error = crate::bpe::decode_into(&[1616, 60000], &crate::bpe::vocabulary::P50K_UNICODES, &mut vec![])
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "Token ID 60000 not found.");
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod trace {
+    #[test]
+    pub (crate) fn test_trace_matches_encode() {
+        let input = b"hello \xF0\x9F\x91\x8B world \xF0\x9F\x8C\x8D. indivisible values.";
+        let traces = crate::bpe::trace(input, &crate::bpe::vocabulary::P50K_TOKENS, &crate::bpe::TOKENS);
+        let tokens: Vec<Vec<u32>> = traces.into_iter().map(|t| t.tokens).collect();
+        assert_eq!(tokens, crate::bpe::encode(input, &crate::bpe::vocabulary::P50K_TOKENS));
+    }
+
+    #[test]
+    pub (crate) fn test_trace_whole_piece() {
+        let traces = crate::bpe::trace(b" values", &crate::bpe::vocabulary::P50K_TOKENS, &crate::bpe::TOKENS);
+        assert_eq!(traces.len(), 1);
+        assert!(traces[0].whole);
+        assert!(traces[0].merges.is_empty());
+        assert_eq!(traces[0].piece, "Ġvalues");
+        assert_eq!(traces[0].tokens, vec![3815]);
+    }
+
+    #[test]
+    pub (crate) fn test_trace_merges() {
+        let traces = crate::bpe::trace(b"indivisible", &crate::bpe::vocabulary::P50K_TOKENS, &crate::bpe::TOKENS);
+        let trace = &traces[0];
+        assert!(!trace.whole);
+        assert_eq!(trace.graphemes.concat(), "indivisible");
+        assert_eq!(trace.merges.len(), 8);
+        assert_eq!((trace.merges[0].left.as_str(), trace.merges[0].right.as_str()), ("i", "n"));
+        assert_eq!(trace.merges[0].rank, 259);
+        let last = trace.merges.last().unwrap();
+        assert_eq!((last.left.as_str(), last.right.as_str()), ("is", "ible"));
+        assert_eq!(last.tokens, trace.tokens);
+        assert_eq!(trace.tokens, vec![521, 452, 12843]);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod dropout {
+    use rand::SeedableRng;
+
+    const INPUT: &[u8] = b"let there be light. indivisible values. Pneumonoultramicroscopicsilicovolcanoconiosis";
+
+    #[test]
+    pub (crate) fn test_dropout_zero_matches_encode() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(
+            crate::bpe::encode_dropout(INPUT, &crate::bpe::vocabulary::P50K_TOKENS, &crate::bpe::TOKENS, 0.0, &mut rng),
+            crate::bpe::encode(INPUT, &crate::bpe::vocabulary::P50K_TOKENS)
+        );
+    }
+
+    #[test]
+    pub (crate) fn test_dropout_decodes_to_input() {
+        for seed in 0..20 {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let tokens: Vec<u16> =
+                crate::bpe::encode_dropout(INPUT, &crate::bpe::vocabulary::P50K_TOKENS, &crate::bpe::TOKENS, 0.3, &mut rng)
+                    .concat()
+                    .iter()
+                    .map(|t| *t as u16)
+                    .collect();
+            assert_eq!(crate::bpe::decode(&tokens, &crate::bpe::vocabulary::P50K_UNICODES), INPUT);
+        }
+    }
+
+    #[test]
+    pub (crate) fn test_dropout_decodes_non_ascii() {
+        use crate::bpe::vocabulary::Vocabularies;
+        let inputs: [&[u8]; 4] =
+            ["héllo wörld".as_bytes(), "👋 hello 🌍.".as_bytes(), "日本語のテキスト".as_bytes(), "\u{1F468}\u{200D}\u{1F469} naïve".as_bytes()];
+        for vocabulary in Vocabularies::iter().filter(|v| v.available()) {
+            for input in inputs {
+                for (seed, p) in [(0, 0.0), (1, 0.3), (2, 0.7), (3, 1.0)] {
+                    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+                    let tokens = vocabulary.encode_dropout(input, p, &mut rng);
+                    let decoded = vocabulary.decode(&tokens.concat(), crate::bpe::Policy::Error).unwrap();
+                    assert_eq!(decoded, input, "{vocabulary} p = {p}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub (crate) fn test_dropout_is_seeded() {
+        let encode = |seed: u64| {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            crate::bpe::encode_dropout(INPUT, &crate::bpe::vocabulary::P50K_TOKENS, &crate::bpe::TOKENS, 0.5, &mut rng).concat()
+        };
+        assert_eq!(encode(7), encode(7));
+        assert!((0..10).any(|seed| encode(seed) != encode(seed + 10)));
+        assert!(encode(7).len() > crate::bpe::encode(INPUT, &crate::bpe::vocabulary::P50K_TOKENS).concat().len());
+    }
+
+    #[test]
+    pub (crate) fn test_dropout_one_splits_into_graphemes() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        assert_eq!(
+            crate::bpe::encode_dropout(b"light", &crate::bpe::vocabulary::P50K_TOKENS, &crate::bpe::TOKENS, 1.0, &mut rng).concat(),
+            vec![75, 72, 70, 71, 83]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "[ERROR]: Dropout probability 1.5 is not between 0 and 1.")]
+    pub (crate) fn test_dropout_invalid_probability() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        crate::bpe::encode_dropout(b"light", &crate::bpe::vocabulary::P50K_TOKENS, &crate::bpe::TOKENS, 1.5, &mut rng);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod statistics {
+    #[test]
+    pub (crate) fn test_statistics_counts() {
+        let mut statistics = crate::bpe::statistics::Statistics::new();
+        assert_eq!(
+            statistics.encode(b"hello world", &crate::bpe::vocabulary::P50K_TOKENS),
+            vec![31373, 995]
+        );
+        statistics.encode(b"Hello, world", &crate::bpe::vocabulary::P50K_TOKENS);
+        statistics.encode(b"", &crate::bpe::vocabulary::P50K_TOKENS);
+        statistics.encode(b"", &crate::bpe::vocabulary::P50K_TOKENS);
+        statistics.encode(b"let there be light.", &crate::bpe::vocabulary::P50K_TOKENS);
+        let summary = statistics.summary(&crate::bpe::vocabulary::P50K_UNICODES);
+        assert_eq!(summary.lines, 3);
+        assert_eq!(summary.documents, 2);
+        assert_eq!(summary.tokens, 10);
+        assert_eq!(summary.distinct, 9);
+        assert_eq!(summary.bytes, 42);
+        assert_eq!(summary.characters, 42);
+        assert_eq!(summary.tokens_per_line, 10.0 / 3.0);
+        assert_eq!(summary.tokens_per_document, 5.0);
+        assert_eq!(summary.characters_per_token, 4.2);
+        assert_eq!(summary.fallback, 0.0);
+        assert_eq!(
+            summary.frequencies[0],
+            crate::bpe::statistics::Frequency { id: 995, count: 2, bytes: 6, text: " world".to_string() }
+        );
+        assert_eq!(summary.frequencies.iter().map(|f| f.count).sum::<usize>(), 10);
+        let longest: Vec<u32> = summary.longest.iter().map(|f| f.id).collect();
+        assert_eq!(longest, vec![612, 995, 1657, 15496, 31373, 307, 1616, 11, 13]);
+    }
+
+    #[test]
+    pub (crate) fn test_statistics_fallback() {
+        let mut statistics = crate::bpe::statistics::Statistics::new();
+        statistics.add(b"x", &[31373, 31373, 60000]);
+        let summary = statistics.summary(&crate::bpe::vocabulary::P50K_UNICODES);
+        assert_eq!(summary.tokens, 3);
+        assert_eq!(summary.frequencies[1].text, "\u{FFFD}");
+        assert_eq!(summary.fallback, 0.0);
+
+        // The p50k_base encoding of "👋 hello": the emoji is split into the byte tokens
+        // "240,159,145" and "139", neither of which is valid UTF-8 on its own.
+        let mut statistics = crate::bpe::statistics::Statistics::new();
+        statistics.add("👋 hello".as_bytes(), &[41840, 233, 23748]);
+        statistics.add("👋 hello".as_bytes(), &[41840, 233, 23748]);
+        let summary = statistics.summary(&crate::bpe::vocabulary::P50K_UNICODES);
+        let fallback: Vec<(u32, usize, usize)> = summary
+            .frequencies
+            .iter()
+            .filter(|frequency| frequency.text == "\u{FFFD}")
+            .map(|frequency| (frequency.id, frequency.count, frequency.bytes))
+            .collect();
+        assert_eq!(fallback, vec![(233, 2, 1), (41840, 2, 3)]);
+        assert_eq!(summary.tokens, 6);
+        assert_eq!(summary.fallback, 4.0 / 6.0);
+    }
+
+    #[test]
+    pub (crate) fn test_statistics_empty() {
+        let summary = crate::bpe::statistics::Statistics::new().summary(&crate::bpe::vocabulary::P50K_UNICODES);
+        assert_eq!(summary.tokens, 0);
+        assert_eq!(summary.tokens_per_line, 0.0);
+        assert!(summary.frequencies.is_empty());
+    }
+
+    #[test]
+    pub (crate) fn test_statistics_csv() {
+        let mut statistics = crate::bpe::statistics::Statistics::new();
+        statistics.encode(b"hello \"world\"", &crate::bpe::vocabulary::P50K_TOKENS);
+        let csv = statistics.summary(&crate::bpe::vocabulary::P50K_UNICODES).to_csv();
+        assert!(csv.starts_with("# lines: 1\n# documents: 1\n# tokens: 4\n"));
+        assert!(csv.contains("\nid,count,bytes,text\n"));
+        assert!(csv.contains("\n366,1,2,\" \"\"\"\n"));
+        assert!(csv.ends_with("31373,1,5,\"hello\"\n"));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod chat {
+    use crate::bpe::chat::{render, Message, Template};
+    use crate::bpe::vocabulary::Vocabularies;
+
+    fn message(role: &str, name: Option<&str>, content: &str) -> Message {
+        Message { role: role.to_string(), name: name.map(str::to_string), content: content.to_string() }
+    }
+
+    #[test]
+    pub (crate) fn test_chat_chatml() {
+        let messages = [message("system", None, "You are helpful."), message("user", None, "Hello, world!")];
+        let chat = render(&messages, Template::ChatML, &Vocabularies::CL100K).unwrap();
+        assert_eq!(chat.messages[0].content, 4);
+        assert_eq!(chat.messages[0].overhead, 5);
+        assert_eq!(chat.messages[1].tokens, 9);
+        assert_eq!(chat.primer, 3);
+        assert_eq!(chat.total, 21);
+        assert_eq!(&chat.ids[..4], &[100264, 9125, 198, 2675]);
+        assert_eq!(&chat.ids[18..], &[100264, 78191, 198]);
+    }
+
+    #[test]
+    pub (crate) fn test_chat_name() {
+        let messages = [message("user", Some("ann"), "Hello, world!")];
+        let chat = render(&messages, Template::ChatML, &Vocabularies::CL100K).unwrap();
+        assert_eq!(&chat.ids[..3], &[100264, 1036, 198]);
+        assert_eq!(chat.messages[0].name.as_deref(), Some("ann"));
+    }
+
+    #[test]
+    pub (crate) fn test_chat_missing_special() {
+        let messages = [message("user", None, "hi")];
+        let error = render(&messages, Template::ChatML, &Vocabularies::R50K).unwrap_err();
+        assert_eq!(error, "Vocabulary R50K has no <|im_start|> token.");
+    }
+
+    #[test]
+    pub (crate) fn test_chat_plain() {
+        let messages = [message("user", None, "hi")];
+        let chat = render(&messages, Template::Plain, &Vocabularies::CL100K).unwrap();
+        assert_eq!(chat.messages[0].content, 1);
+        assert_eq!(chat.messages[0].tokens, 4);
+        assert_eq!(chat.total, chat.ids.len());
+        assert_eq!(chat.total, chat.messages[0].tokens + chat.primer);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod pack {
+    use crate::bpe::pack::{pack, Outcome, Segment};
+    use crate::bpe::vocabulary::Vocabularies;
+
+    fn segment(text: &str, priority: i64, min: usize, max: Option<usize>) -> Segment {
+        Segment { text: text.to_string(), priority, min, max }
+    }
+
+    fn segments() -> Vec<Segment> {
+        vec![
+            segment("You are a helpful assistant.\n", 10, 0, None),
+            segment("Passage one is about cats and dogs and other pets.\n", 1, 4, Some(6)),
+            segment("Passage two is long and about nothing in particular.\n", 0, 8, None),
+            segment("User: hello world\n", 5, 5, None),
+        ]
+    }
+
+    #[test]
+    pub (crate) fn test_pack_fits() {
+        let packed = pack(&segments()[..1], 100, &Vocabularies::CL100K);
+        assert_eq!(packed.text, "You are a helpful assistant.\n");
+        assert_eq!(packed.tokens, 6);
+        assert_eq!(packed.segments[0].outcome, Outcome::Kept);
+    }
+
+    #[test]
+    pub (crate) fn test_pack_drops_lowest() {
+        let packed = pack(&segments(), 20, &Vocabularies::CL100K);
+        let outcomes: Vec<Outcome> = packed.segments.iter().map(|s| s.outcome).collect();
+        assert_eq!(outcomes, vec![Outcome::Kept, Outcome::Truncated, Outcome::Dropped, Outcome::Kept]);
+        assert_eq!(packed.segments[1].kept, 6);
+        assert_eq!(packed.tokens, 17);
+        assert_eq!(packed.ids.len(), 17);
+        assert_eq!(packed.text, "You are a helpful assistant.\nPassage one is about catsUser: hello world\n");
+    }
+
+    #[test]
+    pub (crate) fn test_pack_truncates_lowest() {
+        let packed = pack(&segments(), 30, &Vocabularies::CL100K);
+        let kept: Vec<usize> = packed.segments.iter().map(|s| s.kept).collect();
+        assert_eq!(kept, vec![6, 6, 11, 5]);
+        let packed = pack(&segments(), 26, &Vocabularies::CL100K);
+        let kept: Vec<usize> = packed.segments.iter().map(|s| s.kept).collect();
+        assert_eq!(kept, vec![6, 6, 9, 5]);
+        assert_eq!(packed.segments[2].outcome, Outcome::Truncated);
+    }
+
+    #[test]
+    pub (crate) fn test_pack_within_budget() {
+        for budget in 0..35 {
+            let packed = pack(&segments(), budget, &Vocabularies::CL100K);
+            assert!(packed.tokens <= budget);
+            assert_eq!(packed.ids.len(), packed.tokens);
+            for (report, segment) in packed.segments.iter().zip(segments()) {
+                assert!(report.kept == 0 || report.kept >= segment.min);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod truncation {
+    use crate::bpe::truncation::{truncate, Side};
+    use crate::bpe::vocabulary::Vocabularies;
+
+    const TEXT: &str = "The quick brown fox jumps over the lazy dog again and again.";
+
+    #[test]
+    pub (crate) fn test_truncate_fits() {
+        let truncation = truncate("hello world", 2, Side::Tail, &Vocabularies::CL100K);
+        assert_eq!(truncation.text, "hello world");
+        assert_eq!(truncation.ids, vec![15339, 1917]);
+        assert_eq!(truncation.removed, None);
+    }
+
+    #[test]
+    pub (crate) fn test_truncate_sides() {
+        let tail = truncate(TEXT, 6, Side::Tail, &Vocabularies::CL100K);
+        assert_eq!(tail.text, "The quick brown fox jumps ...");
+        assert_eq!(tail.removed, Some(26..60));
+        let head = truncate(TEXT, 6, Side::Head, &Vocabularies::CL100K);
+        assert_eq!(head.text, "... dog again and again.");
+        let middle = truncate(TEXT, 6, Side::Middle, &Vocabularies::CL100K);
+        assert!(middle.text.starts_with("The") && middle.text.ends_with("again."));
+        let removed = middle.removed.unwrap();
+        assert_eq!(middle.text, format!("{}...{}", &TEXT[..removed.start], &TEXT[removed.end..]));
+    }
+
+    #[test]
+    pub (crate) fn test_truncate_limit() {
+        for side in [Side::Head, Side::Tail, Side::Middle] {
+            for max_tokens in 0..15 {
+                let truncation = truncate(TEXT, max_tokens, side, &Vocabularies::R50K);
+                assert!(truncation.ids.len() <= max_tokens);
+                assert_eq!(truncation.ids, Vocabularies::R50K.encode(truncation.text.as_bytes()).concat());
+            }
+        }
+    }
+
+    #[test]
+    pub (crate) fn test_truncate_marker_too_long() {
+        let truncation = truncate(TEXT, 0, Side::Tail, &Vocabularies::CL100K);
+        assert_eq!(truncation.text, "");
+        assert_eq!(truncation.removed, Some(0..TEXT.len()));
+    }
+
+    #[test]
+    pub (crate) fn test_side_parsing() {
+        assert_eq!("Middle".parse::<Side>(), Ok(Side::Middle));
+        assert_eq!(Side::try_from(0), Ok(Side::Head));
+        assert!(Side::try_from(3).is_err());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod trie {
+    use crate::bpe::trie::Trie;
+    use crate::bpe::vocabulary::{bytes, Vocabularies};
+
+    fn create_trie() -> Trie {
+        let mut trie = Trie::new();
+        for (id, token) in ["a", "ab", "abc", "abd", "b", "ba"].iter().enumerate() {
+            trie.insert(token.as_bytes(), id as u32);
+        }
+        trie
+    }
+
+    #[test]
+    pub (crate) fn test_trie_queries() {
+        let trie = create_trie();
+        assert_eq!(trie.get(b"ab"), Some(1));
+        assert_eq!(trie.get(b"abe"), None);
+        let starting: Vec<(Vec<u8>, u32)> = vec![(b"ab".to_vec(), 1), (b"abc".to_vec(), 2), (b"abd".to_vec(), 3)];
+        assert_eq!(trie.starting_with(b"ab"), starting);
+        assert_eq!(trie.prefixes_of(b"abcx"), vec![(1, 0), (2, 1), (3, 2)]);
+        assert_eq!(trie.longest_match(b"abx"), Some((2, 1)));
+        assert_eq!(trie.longest_match(b"x"), None);
+        assert_eq!(trie.compatible(b"ab"), vec![0, 1, 2, 3]);
+        assert_eq!(trie.compatible(b"bax"), vec![4, 5]);
+    }
+
+    #[test]
+    pub (crate) fn test_vocabulary_bytes() {
+        assert_eq!(bytes("Ġhello", 24748), b" hello".to_vec());
+        assert_eq!(bytes("Ã©", 978), "é".as_bytes().to_vec());
+        assert_eq!(bytes("226,128", 378), vec![226, 128]);
+        assert_eq!(bytes("252", 184), vec![252]);
+        assert_eq!(bytes("252", 12326), b"252".to_vec());
+        assert_eq!(bytes("7", 22), b"7".to_vec());
+    }
+
+    #[test]
+    pub (crate) fn test_vocabulary_trie() {
+        let trie = Vocabularies::CL100K.trie();
+        assert_eq!(trie.get(b" hello"), Some(24748));
+        assert_eq!(trie.get(&[226, 128]), Some(378));
+        assert_eq!(trie.get(b"252"), Some(12326));
+        assert_eq!(trie.longest_match(b" hello world"), Some((6, 24748)));
+        assert!((0..=255u8).all(|byte| trie.get(&[byte]).is_some()));
+        let compatible = trie.compatible(b" hel");
+        assert!(compatible.contains(&220) && compatible.contains(&11591) && compatible.contains(&24748));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod dataset {
+    use crate::bpe::dataset::{decode, prepare, Dataset, Dtype, Sampler, Tokens, METADATA};
+    use crate::bpe::vocabulary::Vocabularies;
+    use rand::SeedableRng;
+
+    fn create_directory(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("gpt3bpe-dataset-{name}-{}", std::process::id()))
+    }
+
+    fn read_u16(path: &std::path::Path) -> Vec<u16> {
+        std::fs::read(path).unwrap().chunks(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect()
+    }
+
+    #[test]
+    pub (crate) fn test_vocabulary_size() {
+        assert_eq!(Vocabularies::R50K.size(), 50257);
+        assert_eq!(Dtype::of(&Vocabularies::R50K), Dtype::Uint16);
+        assert_eq!(Dtype::of(&Vocabularies::P50K), Dtype::Uint16);
+        assert_eq!(Dtype::of(&Vocabularies::CL100K), Dtype::Uint32);
+    }
+
+    #[test]
+    pub (crate) fn test_prepare_train() {
+        let directory = create_directory("train");
+        let documents = ["hello world", "hello"].into_iter().map(|d| Ok(d.to_string()));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let metadata = prepare(documents, &Vocabularies::R50K, &directory, 0.0, None, &mut rng).unwrap();
+        assert_eq!(metadata.dtype, Dtype::Uint16);
+        assert_eq!(metadata.eot, 50256);
+        assert_eq!((metadata.train.documents, metadata.train.tokens), (2, 5));
+        assert_eq!((metadata.val.documents, metadata.val.tokens), (0, 0));
+        assert_eq!(read_u16(&directory.join("train.bin")), [31373, 995, 50256, 31373, 50256]);
+        assert!(std::fs::read(directory.join("val.bin")).unwrap().is_empty());
+
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(directory.join(METADATA)).unwrap()).unwrap();
+        assert_eq!(written["vocabulary"], "R50K");
+        assert_eq!(written["dtype"], "uint16");
+        assert_eq!(written["train"]["tokens"], 5);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub (crate) fn test_prepare_shards() {
+        let directory = create_directory("shards");
+        let documents = ["hello world", "hello"].into_iter().map(|d| Ok(d.to_string()));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let metadata = prepare(documents, &Vocabularies::CL100K, &directory, 1.0, Some(2), &mut rng).unwrap();
+        assert_eq!(metadata.dtype, Dtype::Uint32);
+        assert_eq!(metadata.val.tokens, 5);
+        let shards: Vec<(&str, usize)> = metadata.val.shards.iter().map(|s| (s.file.as_str(), s.tokens)).collect();
+        assert_eq!(shards, [("val_0000.bin", 2), ("val_0001.bin", 2), ("val_0002.bin", 1)]);
+        assert_eq!(std::fs::read(directory.join("val_0002.bin")).unwrap(), 100257u32.to_le_bytes());
+        assert_eq!(metadata.train.shards.len(), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub (crate) fn test_prepare_split_is_seeded() {
+        let split = |seed: u64, name: &str| {
+            let directory = create_directory(name);
+            let documents = (0..100).map(|i| Ok(format!("document {i}")));
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let metadata = prepare(documents, &Vocabularies::P50K, &directory, 0.5, None, &mut rng).unwrap();
+            std::fs::remove_dir_all(&directory).unwrap();
+            metadata
+        };
+        let first = split(7, "seeded-a");
+        assert_eq!(first, split(7, "seeded-b"));
+        assert_eq!(first.train.documents + first.val.documents, 100);
+        assert!(first.val.documents > 0 && first.train.documents > 0);
+    }
+
+    #[test]
+    pub (crate) fn test_prepare_errors() {
+        let directory = create_directory("errors");
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let none = || std::iter::empty::<Result<String, String>>();
+        assert!(prepare(none(), &Vocabularies::R50K, &directory, 1.5, None, &mut rng).is_err());
+        assert!(prepare(none(), &Vocabularies::R50K, &directory, 0.1, Some(0), &mut rng).is_err());
+        let failing = std::iter::once(Err("broken".to_string()));
+        assert_eq!(prepare(failing, &Vocabularies::R50K, &directory, 0.1, None, &mut rng), Err("broken".to_string()));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    fn create_dataset(name: &str, shard_tokens: Option<usize>) -> std::path::PathBuf {
+        let directory = create_directory(name);
+        let documents = ["hello world", "hello"].into_iter().map(|d| Ok(d.to_string()));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        prepare(documents, &Vocabularies::R50K, &directory, 0.0, shard_tokens, &mut rng).unwrap();
+        directory
+    }
+
+    #[test]
+    pub (crate) fn test_dataset_tokens() {
+        let directory = create_dataset("tokens", None);
+        let tokens = Tokens::open(&directory.join("train.bin"), Dtype::Uint16).unwrap();
+        assert_eq!(tokens.len(), 5);
+        assert_eq!(tokens.get(1), 995);
+        assert_eq!(tokens.window(2, 3), [50256, 31373, 50256]);
+        assert!(Tokens::open(&directory.join("train.bin"), Dtype::Uint32).is_err());
+        assert!(Tokens::open(&directory.join("missing.bin"), Dtype::Uint16).is_err());
+
+        let dataset = Dataset::open(&directory, "train").unwrap();
+        assert_eq!(dataset.vocabulary(), Ok(Vocabularies::R50K));
+        assert_eq!(dataset.shards.len(), 1);
+        assert!(Dataset::open(&directory, "test").err().unwrap().starts_with("unknown split"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub (crate) fn test_sampler_windows() {
+        let directory = create_dataset("sampler", None);
+        let dataset = Dataset::open(&directory, "train").unwrap();
+        let sample = |seed: u64| {
+            let rng = rand::rngs::StdRng::seed_from_u64(seed);
+            Sampler::new(&dataset, 2, rng).unwrap().batch(16)
+        };
+        let batch = sample(3);
+        assert_eq!(batch, sample(3));
+        assert_eq!(batch.contexts.len(), 16);
+        let tokens = [31373, 995, 50256, 31373, 50256];
+        for (context, target) in batch.contexts.iter().zip(&batch.targets) {
+            let start = (0..3).find(|&start| tokens[start..start + 2] == context[..]).unwrap();
+            assert_eq!(target[..], tokens[start + 1..start + 3]);
+        }
+
+        assert!(Sampler::new(&dataset, 5, rand::rngs::StdRng::seed_from_u64(0)).is_err());
+        assert!(Sampler::new(&dataset, 0, rand::rngs::StdRng::seed_from_u64(0)).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub (crate) fn test_sampler_shards() {
+        let directory = create_dataset("sampler-shards", Some(2));
+        let dataset = Dataset::open(&directory, "train").unwrap();
+        assert_eq!(dataset.shards.len(), 3);
+        let rng = rand::rngs::StdRng::seed_from_u64(0);
+        let batch = Sampler::new(&dataset, 1, rng).unwrap().batch(32);
+        let windows: std::collections::BTreeSet<(u32, u32)> =
+            batch.contexts.iter().zip(&batch.targets).map(|(c, t)| (c[0], t[0])).collect();
+        assert_eq!(windows, [(31373, 995), (50256, 31373)].into_iter().collect());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub (crate) fn test_decode_window() {
+        assert_eq!(decode(&[31373, 995, 50256], &Vocabularies::R50K), "hello world<|endoftext|>");
+        assert_eq!(decode(&[50256, 31373, 50256], &Vocabularies::R50K), "<|endoftext|>hello<|endoftext|>");
+        assert_eq!(decode(&[60000], &Vocabularies::R50K), "\u{FFFD}");
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod chunk {
+    use crate::bpe::chunk::chunk;
+    use crate::bpe::vocabulary::Vocabularies;
+
+    #[test]
+    pub (crate) fn test_chunk_pieces() {
+        let text = "let there be light.";
+        let chunks = chunk(text, 2, &Vocabularies::P50K).unwrap();
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["let there", " be light", "."]);
+        assert_eq!(chunks[1].ids, [307, 1657]);
+        assert_eq!((chunks[1].start, chunks[1].end), (9, 18));
+        let ids: Vec<u32> = chunks.iter().flat_map(|c| c.ids.clone()).collect();
+        assert_eq!(ids, Vocabularies::P50K.encode(text.as_bytes()).concat());
+        assert_eq!(chunk(text, 100, &Vocabularies::P50K).unwrap().len(), 1);
+    }
+
+    #[test]
+    pub (crate) fn test_chunk_long_piece() {
+        let text = "a".repeat(64);
+        let ids = Vocabularies::CL100K.encode(text.as_bytes()).concat();
+        assert!(ids.len() > 2);
+        let chunks = chunk(&text, 2, &Vocabularies::CL100K).unwrap();
+        assert!(chunks.iter().all(|c| c.ids.len() <= 2));
+        assert_eq!(chunks.iter().map(|c| c.text.as_str()).collect::<String>(), text);
+        assert_eq!(chunks.iter().flat_map(|c| c.ids.clone()).collect::<Vec<u32>>(), ids);
+    }
+
+    #[test]
+    pub (crate) fn test_chunk_non_ascii() {
+        for text in ["👋🏽👋🏽👋🏽", "naïve café, 東京 и Москва"] {
+            for vocabulary in [Vocabularies::R50K, Vocabularies::P50K, Vocabularies::CL100K] {
+                let chunks = chunk(text, 2, &vocabulary).unwrap();
+                assert!(chunks.iter().all(|c| c.ids.len() <= 2 && c.start <= c.end));
+                assert_eq!((chunks[0].start, chunks.last().unwrap().end), (0, text.len()));
+                assert!(chunks.windows(2).all(|pair| pair[0].end == pair[1].start));
+                for c in &chunks {
+                    let decoded = vocabulary.decode(&c.ids, crate::bpe::Policy::Error).unwrap();
+                    assert_eq!(decoded, &text.as_bytes()[c.start..c.end]);
+                }
+                let ids: Vec<u32> = chunks.iter().flat_map(|c| c.ids.clone()).collect();
+                assert_eq!(ids, vocabulary.encode(text.as_bytes()).concat());
+            }
+        }
+    }
+
+    #[test]
+    pub (crate) fn test_chunk_edges() {
+        assert!(chunk("", 2, &Vocabularies::P50K).unwrap().is_empty());
+        assert!(chunk("hello", 0, &Vocabularies::P50K).is_err());
+    }
+}
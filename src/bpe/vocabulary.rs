@@ -6,6 +6,8 @@
 //! filesystem at runtime, with their location configurable via the `VOCABULARY`
 //! environment variable.
 
+use super::trie::Trie;
+use regex::bytes::Regex;
 use serde::de::DeserializeOwned;
 use std::collections::BTreeMap;
 use std::fmt::Debug;
@@ -14,13 +16,14 @@ use std::sync::LazyLock;
 /// Loads a vocabulary from a `.jsonl` file into a `BTreeMap`.
 ///
 /// Each line of the file is expected to be a JSON object representing a single
-/// token, like `{"<|endoftext|>": 50256}`. The string key is converted to bytes.
+/// token, like `{"<|endoftext|>": 50256}`. The key is converted to the raw bytes it
+/// stands for with `bytes`, so that encoding never has to map bytes to GPT-unicode.
 ///
 /// # Panics
 /// Panics if the file cannot be read or if a line cannot be parsed as valid JSON.
-fn load_vocabulary<T>(file_path: &str) -> BTreeMap<Vec<u8>, T>
+pub fn load_vocabulary<T>(file_path: &str) -> BTreeMap<Vec<u8>, T>
 where
-    T: DeserializeOwned + Ord + Send + Sync + 'static,
+    T: DeserializeOwned + Ord + Copy + Into<u32> + Send + Sync + 'static,
     BTreeMap<String, T>: DeserializeOwned,
 {
     let file = std::fs::File::open(file_path)
@@ -36,7 +39,7 @@ where
         })
         .flat_map(|data| {
             data.into_iter()
-                .map(|(key, token)| (key.into_bytes(), token))
+                .map(|(key, token)| (bytes(&key, token.into()), token))
         })
         .collect()
 }
@@ -44,14 +47,16 @@ where
 /// Generates a reverse mapping from a token ID to its original Unicode sequence.
 ///
 /// This function reads the same `.jsonl` file as `load_vocabulary` but creates a
-/// map suitable for decoding, where the token ID maps to the `u16` code points
-/// of the original token string.
+/// map suitable for decoding, where the token ID maps to the UTF-8 bytes of the
+/// GPT-unicode text of the token, one `u16` per byte. Keys are resolved with `bytes`
+/// first, so a byte token stored as `"195"` decodes to the byte `0xC3`, not to the
+/// text "195".
 ///
 /// # Panics
 /// Panics if the file cannot be read or if a line cannot be parsed as valid JSON.
-fn generate_unicodes<T>(file_path: &str) -> BTreeMap<T, Vec<u16>>
+pub fn generate_unicodes<T>(file_path: &str) -> BTreeMap<T, Vec<u16>>
 where
-    T: DeserializeOwned + Ord + Send + Sync + 'static + Debug,
+    T: DeserializeOwned + Ord + Copy + Into<u32> + Send + Sync + 'static + Debug,
     BTreeMap<String, T>: DeserializeOwned,
 {
     let file = std::fs::File::open(file_path)
@@ -67,91 +72,111 @@ where
         })
         .flat_map(|data| {
             data.into_iter().map(|(key, token)| {
-                let unicode_sequence = key.bytes().map(|b| b as u16).collect();
+                let unicode_sequence = super::unicode(&bytes(&key, token.into()))
+                    .into_iter()
+                    .map(u16::from)
+                    .collect();
                 (token, unicode_sequence)
             })
         })
         .collect()
 }
 
-/// Lazily loaded `r50k_base` (GPT-2) vocabulary mapping token bytes to token IDs.
+/// Resolves the raw bytes a vocabulary key stands for.
+///
+/// Keys are GPT-unicode strings, except for tokens whose bytes are not valid UTF-8 on
+/// their own: those are written as their decimal byte values separated by commas, e.g.
+/// `"226,128"`, as are a few valid ones like the byte order mark `"239,187,191"`. A
+/// single decimal number is a raw byte only for the 256 byte tokens (IDs below 256),
+/// since longer tokens like `"252"` are plain digits, and only if it is not valid
+/// UTF-8, since the byte tokens `"0"` to `"9"` are digits too.
+pub fn bytes(key: &str, id: u32) -> Vec<u8> {
+    if key.contains(',') || id < 256 {
+        let decimal: Result<Vec<u8>, _> = key.split(',').map(str::parse::<u8>).collect();
+        if let Some(bytes) = decimal.ok().filter(|bytes| key.contains(',') || std::str::from_utf8(bytes).is_err()) {
+            return bytes;
+        };
+    };
+    super::raw(key)
+}
+
+/// Resolves the path of a vocabulary file.
+///
+/// Files are read from `src/bpe/vocabulary` unless the `VOCABULARY` environment
+/// variable points at another directory.
+pub fn location(file: &str) -> String {
+    match std::env::var("VOCABULARY") {
+        Ok(l) => l + "/" + file,
+        Err(_) => format!("src/bpe/vocabulary/{file}"),
+    }
+}
+
+/// Lazily loaded `r50k_base` (GPT-2) vocabulary mapping raw token bytes to token IDs.
 ///
 /// The file path can be overridden by the `VOCABULARY` environment variable.
-pub(crate) static R50K_TOKENS: LazyLock<BTreeMap<Vec<u8>, u16>> =
-    LazyLock::new(|| match std::env::var("VOCABULARY") {
-        Ok(l) => load_vocabulary(&(l + "/r50k.jsonl")),
-        Err(_) => load_vocabulary("src/bpe/vocabulary/r50k.jsonl"),
-    });
+pub static R50K_TOKENS: LazyLock<BTreeMap<Vec<u8>, u16>> =
+    LazyLock::new(|| load_vocabulary(&location("r50k.jsonl")));
 
 /// Lazily loaded `r50k_base` reverse mapping from token IDs to Unicode code points for decoding.
 ///
 /// The file path can be overridden by the `VOCABULARY` environment variable.
-pub(crate) static R50K_UNICODES: LazyLock<BTreeMap<u16, Vec<u16>>> =
-    LazyLock::new(|| match std::env::var("VOCABULARY") {
-        Ok(l) => generate_unicodes(&(l + "/r50k.jsonl")),
-        Err(_) => generate_unicodes("src/bpe/vocabulary/r50k.jsonl"),
-    });
+pub static R50K_UNICODES: LazyLock<BTreeMap<u16, Vec<u16>>> =
+    LazyLock::new(|| generate_unicodes(&location("r50k.jsonl")));
 
-/// Lazily loaded `p50k_base` vocabulary mapping token bytes to token IDs.
+/// Lazily loaded `p50k_base` vocabulary mapping raw token bytes to token IDs.
 ///
 /// Used by models like `text-davinci-002`.
 /// The file path can be overridden by the `VOCABULARY` environment variable.
-pub(crate) static P50K_TOKENS: LazyLock<BTreeMap<Vec<u8>, u16>> =
-    LazyLock::new(|| match std::env::var("VOCABULARY") {
-        Ok(l) => load_vocabulary(&(l + "/p50k.jsonl")),
-        Err(_) => load_vocabulary("src/bpe/vocabulary/p50k.jsonl"),
-    });
+pub static P50K_TOKENS: LazyLock<BTreeMap<Vec<u8>, u16>> =
+    LazyLock::new(|| load_vocabulary(&location("p50k.jsonl")));
 
 /// Lazily loaded `p50k_base` reverse mapping from token IDs to Unicode code points for decoding.
 ///
 /// The file path can be overridden by the `VOCABULARY` environment variable.
-pub(crate) static P50K_UNICODES: LazyLock<BTreeMap<u16, Vec<u16>>> =
-    LazyLock::new(|| match std::env::var("VOCABULARY") {
-        Ok(l) => generate_unicodes(&(l + "/p50k.jsonl")),
-        Err(_) => generate_unicodes("src/bpe/vocabulary/p50k.jsonl"),
-    });
+pub static P50K_UNICODES: LazyLock<BTreeMap<u16, Vec<u16>>> =
+    LazyLock::new(|| generate_unicodes(&location("p50k.jsonl")));
 
-/// Lazily loaded `cl100k_base` vocabulary mapping token bytes to token IDs.
+/// Lazily loaded `cl100k_base` vocabulary mapping raw token bytes to token IDs.
 ///
 /// Used by models like `gpt-3.5-turbo` and `gpt-4`.
 /// The file path can be overridden by the `VOCABULARY` environment variable.
-pub(crate) static CL100K_TOKENS: LazyLock<BTreeMap<Vec<u8>, u32>> =
-    LazyLock::new(|| match std::env::var("VOCABULARY") {
-        Ok(l) => load_vocabulary(&(l + "/cl100k.jsonl")),
-        Err(_) => load_vocabulary("src/bpe/vocabulary/cl100k.jsonl"),
-    });
+pub static CL100K_TOKENS: LazyLock<BTreeMap<Vec<u8>, u32>> =
+    LazyLock::new(|| load_vocabulary(&location("cl100k.jsonl")));
 
 /// Lazily loaded `cl100k_base` reverse mapping from token IDs to Unicode code points for decoding.
 ///
 /// The file path can be overridden by the `VOCABULARY` environment variable.
-pub(crate) static CL100K_UNICODES: LazyLock<BTreeMap<u32, Vec<u16>>> =
-    LazyLock::new(|| match std::env::var("VOCABULARY") {
-        Ok(l) => generate_unicodes(&(l + "/cl100k.jsonl")),
-        Err(_) => generate_unicodes("src/bpe/vocabulary/cl100k.jsonl"),
-    });
+pub static CL100K_UNICODES: LazyLock<BTreeMap<u32, Vec<u16>>> =
+    LazyLock::new(|| generate_unicodes(&location("cl100k.jsonl")));
 
-/// Lazily loaded `o200k_base` vocabulary mapping token bytes to token IDs.
+/// Lazily loaded `o200k_base` vocabulary mapping raw token bytes to token IDs.
 ///
 /// Used by models like `gpt-4o`.
 /// The file path can be overridden by the `VOCABULARY` environment variable.
-pub(crate) static O200K_TOKENS: LazyLock<BTreeMap<Vec<u8>, u32>> =
-    LazyLock::new(|| match std::env::var("VOCABULARY") {
-        Ok(l) => load_vocabulary(&(l + "/o200k.jsonl")),
-        Err(_) => load_vocabulary("src/bpe/vocabulary/o200k.jsonl"),
-    });
+pub static O200K_TOKENS: LazyLock<BTreeMap<Vec<u8>, u32>> =
+    LazyLock::new(|| load_vocabulary(&location("o200k.jsonl")));
 
 /// Lazily loaded `o200k_base` reverse mapping from token IDs to Unicode code points for decoding.
 ///
 /// The file path can be overridden by the `VOCABULARY` environment variable.
-pub(crate) static O200K_UNICODES: LazyLock<BTreeMap<u32, Vec<u16>>> =
-    LazyLock::new(|| match std::env::var("VOCABULARY") {
-        Ok(l) => generate_unicodes(&(l + "/o200k.jsonl")),
-        Err(_) => generate_unicodes("src/bpe/vocabulary/o200k.jsonl"),
-    });
+pub static O200K_UNICODES: LazyLock<BTreeMap<u32, Vec<u16>>> =
+    LazyLock::new(|| generate_unicodes(&location("o200k.jsonl")));
+
+/// Lazily built byte trie over the raw bytes of the `r50k_base` tokens.
+pub static R50K_TRIE: LazyLock<Trie> = LazyLock::new(|| Trie::from_unicodes(&R50K_UNICODES));
+
+/// Lazily built byte trie over the raw bytes of the `p50k_base` tokens.
+pub static P50K_TRIE: LazyLock<Trie> = LazyLock::new(|| Trie::from_unicodes(&P50K_UNICODES));
+
+/// Lazily built byte trie over the raw bytes of the `cl100k_base` tokens.
+pub static CL100K_TRIE: LazyLock<Trie> = LazyLock::new(|| Trie::from_unicodes(&CL100K_UNICODES));
+
+/// Lazily built byte trie over the raw bytes of the `o200k_base` tokens.
+pub static O200K_TRIE: LazyLock<Trie> = LazyLock::new(|| Trie::from_unicodes(&O200K_UNICODES));
 
 /// An enumeration of the supported BPE vocabularies.
-#[derive(Debug, PartialEq, Eq, Default)]
-pub(crate) enum Vocabularies {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Vocabularies {
     #[default]
     /// `p50k_base` vocabulary, used by `text-davinci-002`.
     P50K,
@@ -163,9 +188,27 @@ pub(crate) enum Vocabularies {
     O200K,
 }
 
+/// Decodes `u32` token IDs with a `u16` vocabulary, treating out-of-range IDs as unknown.
+fn decode_narrowed(
+    tokens: &[u32],
+    lookup: &LazyLock<BTreeMap<u16, Vec<u16>>>,
+    policy: super::Policy,
+) -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        match (u16::try_from(*token), policy) {
+            (Ok(narrow), _) => result.extend(super::decode_lenient(&[narrow], lookup, policy)?),
+            (Err(_), super::Policy::Error) => return Err(format!("Token ID {token} not found.")),
+            (Err(_), super::Policy::Skip) => {}
+            (Err(_), super::Policy::Replace) => result.extend_from_slice(super::REPLACEMENT),
+        };
+    }
+    Ok(result)
+}
+
 impl Vocabularies {
     /// Returns an iterator over all available `Vocabularies` variants.
-    pub fn iter() -> std::slice::Iter<'static, Vocabularies> {
+        pub fn iter() -> std::slice::Iter<'static, Vocabularies> {
         static VOCABULARIES: [Vocabularies; 4] = [
             Vocabularies::P50K,
             Vocabularies::R50K,
@@ -174,6 +217,169 @@ impl Vocabularies {
         ];
         VOCABULARIES.iter()
     }
+
+    /// Returns the pattern this vocabulary splits text with before merging, as in `tiktoken`.
+    pub fn pattern(&self) -> &'static Regex {
+        match self {
+            Vocabularies::R50K | Vocabularies::P50K => &super::TOKENS,
+            Vocabularies::CL100K => &super::CL100K,
+            Vocabularies::O200K => &super::O200K,
+        }
+    }
+
+    /// Splits a byte slice into the pieces this vocabulary encodes one by one, using `bpe::split`.
+    pub fn tokens<'a>(&self, slice: &'a [u8]) -> Vec<&'a [u8]> {
+        super::split(slice, self.pattern())
+    }
+
+    /// Encodes a byte slice with this vocabulary using `bpe::encode_with`.
+    ///
+    /// # Returns
+    ///
+    /// The token IDs of each regex piece.
+    pub fn encode(&self, slice: &[u8]) -> Vec<Vec<u32>> {
+        let pattern = self.pattern();
+        match self {
+            Vocabularies::R50K => super::encode_with(slice, &R50K_TOKENS, pattern),
+            Vocabularies::P50K => super::encode_with(slice, &P50K_TOKENS, pattern),
+            Vocabularies::CL100K => super::encode_with(slice, &CL100K_TOKENS, pattern),
+            Vocabularies::O200K => super::encode_with(slice, &O200K_TOKENS, pattern),
+        }
+    }
+
+    /// Encodes a byte slice with this vocabulary using `bpe::encode_dropout`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `p` is not between `0.0` and `1.0`.
+    pub fn encode_dropout(&self, slice: &[u8], p: f64, rng: &mut rand::rngs::StdRng) -> Vec<Vec<u32>> {
+        let pattern = self.pattern();
+        match self {
+            Vocabularies::R50K => super::encode_dropout(slice, &R50K_TOKENS, pattern, p, rng),
+            Vocabularies::P50K => super::encode_dropout(slice, &P50K_TOKENS, pattern, p, rng),
+            Vocabularies::CL100K => super::encode_dropout(slice, &CL100K_TOKENS, pattern, p, rng),
+            Vocabularies::O200K => super::encode_dropout(slice, &O200K_TOKENS, pattern, p, rng),
+        }
+    }
+
+    /// Traces the merges applied to every piece of a byte slice using `bpe::trace`.
+    pub fn trace(&self, slice: &[u8]) -> Vec<super::Trace> {
+        let pattern = self.pattern();
+        match self {
+            Vocabularies::R50K => super::trace(slice, &R50K_TOKENS, pattern),
+            Vocabularies::P50K => super::trace(slice, &P50K_TOKENS, pattern),
+            Vocabularies::CL100K => super::trace(slice, &CL100K_TOKENS, pattern),
+            Vocabularies::O200K => super::trace(slice, &O200K_TOKENS, pattern),
+        }
+    }
+
+    /// Decodes token IDs with this vocabulary using `bpe::decode_lenient`.
+    ///
+    /// IDs that do not fit the vocabulary's ID type are unknown IDs and are handled
+    /// with `policy` like any other.
+        pub fn decode(&self, tokens: &[u32], policy: super::Policy) -> Result<Vec<u8>, String> {
+        match self {
+            Vocabularies::R50K => decode_narrowed(tokens, &R50K_UNICODES, policy),
+            Vocabularies::P50K => decode_narrowed(tokens, &P50K_UNICODES, policy),
+            Vocabularies::CL100K => super::decode_lenient(tokens, &CL100K_UNICODES, policy),
+            Vocabularies::O200K => super::decode_lenient(tokens, &O200K_UNICODES, policy),
+        }
+    }
+
+    /// Returns one more than the largest token ID of this vocabulary, special tokens
+    /// included, i.e. the number of rows an embedding table needs.
+        pub fn size(&self) -> u32 {
+        let largest = match self {
+            Vocabularies::R50K => R50K_TOKENS.values().max().map(|&id| u32::from(id)),
+            Vocabularies::P50K => P50K_TOKENS.values().max().map(|&id| u32::from(id)),
+            Vocabularies::CL100K => CL100K_TOKENS.values().max().copied(),
+            Vocabularies::O200K => O200K_TOKENS.values().max().copied(),
+        };
+        let special = self.special().iter().map(|&(_, id)| id).max();
+        largest.max(special).map_or(0, |id| id + 1)
+    }
+
+    /// Returns the byte trie over the tokens of this vocabulary.
+        pub fn trie(&self) -> &'static Trie {
+        match self {
+            Vocabularies::R50K => &R50K_TRIE,
+            Vocabularies::P50K => &P50K_TRIE,
+            Vocabularies::CL100K => &CL100K_TRIE,
+            Vocabularies::O200K => &O200K_TRIE,
+        }
+    }
+
+    /// Returns the special tokens of this vocabulary and their IDs.
+    ///
+    /// Special tokens are not part of the `.jsonl` files. The IDs are those used by
+    /// `tiktoken`, including the ChatML tokens of the chat models.
+    pub fn special(&self) -> &'static [(&'static str, u32)] {
+        match self {
+            Vocabularies::R50K | Vocabularies::P50K => &[("<|endoftext|>", 50256)],
+            Vocabularies::CL100K => &[
+                ("<|endoftext|>", 100257),
+                ("<|fim_prefix|>", 100258),
+                ("<|fim_middle|>", 100259),
+                ("<|fim_suffix|>", 100260),
+                ("<|im_start|>", 100264),
+                ("<|im_end|>", 100265),
+                ("<|im_sep|>", 100266),
+                ("<|endofprompt|>", 100276),
+            ],
+            Vocabularies::O200K => &[
+                ("<|endoftext|>", 199999),
+                ("<|endofprompt|>", 200018),
+                ("<|im_start|>", 200264),
+                ("<|im_end|>", 200265),
+                ("<|im_sep|>", 200266),
+            ],
+        }
+    }
+
+    /// Returns the ID of the special token `name`, if this vocabulary has it.
+        pub fn special_token(&self, name: &str) -> Option<u32> {
+        self.special()
+            .iter()
+            .find(|(token, _)| *token == name)
+            .map(|(_, id)| *id)
+    }
+
+    /// Returns the name of the `.jsonl` file backing this vocabulary.
+    pub fn file(&self) -> &'static str {
+        match self {
+            Vocabularies::R50K => "r50k.jsonl",
+            Vocabularies::P50K => "p50k.jsonl",
+            Vocabularies::CL100K => "cl100k.jsonl",
+            Vocabularies::O200K => "o200k.jsonl",
+        }
+    }
+
+    /// Returns `true` if the vocabulary file can be found on disk.
+    ///
+    /// The vocabulary statics panic when their file is missing, so callers that walk
+    /// every vocabulary should check this first.
+        pub fn available(&self) -> bool {
+        std::path::Path::new(&location(self.file())).exists()
+    }
+
+    /// Resolves the vocabulary named by a request, falling back to `default`.
+    ///
+    /// The server and the JSON-RPC mode both take vocabularies by name from their
+    /// clients, who must get an error, not a panic, for a vocabulary whose file is missing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is unknown or the vocabulary file is missing.
+        pub fn resolve(name: Option<&str>, default: Vocabularies) -> Result<Vocabularies, String> {
+        let vocabulary = match name {
+            Some(name) => name.parse()?,
+            None => default,
+        };
+        match vocabulary.available() {
+            true => Ok(vocabulary),
+            false => Err(format!("Vocabulary {vocabulary} is not available.")),
+        }
+    }
 }
 
 impl std::str::FromStr for Vocabularies {
@@ -183,13 +389,14 @@ impl std::str::FromStr for Vocabularies {
     ///
     /// # Arguments
     ///
-    /// * `s` - The string to parse. Case-insensitive.
+    /// * `s` - The string to parse. Case-insensitive. The `_base` names used by
+    ///   `tiktoken` (e.g. `cl100k_base`) are accepted too.
     ///
     /// # Errors
     ///
     /// Returns an error if the string is not a valid vocabulary identifier.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
+        match s.to_lowercase().trim_end_matches("_base") {
             "r50k" => Ok(Vocabularies::R50K),
             "p50k" => Ok(Vocabularies::P50K),
             "cl100k" => Ok(Vocabularies::CL100K),
@@ -201,13 +408,13 @@ impl std::str::FromStr for Vocabularies {
     }
 }
 
-impl ToString for Vocabularies {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Vocabularies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Vocabularies::R50K => "R50K".to_owned(),
-            Vocabularies::P50K => "P50K".to_owned(),
-            Vocabularies::CL100K => "CL100K".to_owned(),
-            Vocabularies::O200K => "O200K".to_owned(),
+            Vocabularies::R50K => write!(f, "R50K"),
+            Vocabularies::P50K => write!(f, "P50K"),
+            Vocabularies::CL100K => write!(f, "CL100K"),
+            Vocabularies::O200K => write!(f, "O200K"),
         }
     }
 }
@@ -22,12 +22,196 @@ pub(crate) struct GraphemeCommand {}
 #[argh(subcommand, name = "embed")]
 pub(crate) struct EmbedCommand {}
 
+/// Compares token counts and boundaries across every vocabulary.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "compare")]
+pub(crate) struct CompareCommand {
+    /// print the comparison as json instead of a table.
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// Renders each token with alternating colours to inspect tokenization.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "show")]
+pub(crate) struct ShowCommand {
+    /// write an HTML report with token id tooltips to this file instead.
+    #[argh(option)]
+    pub html: Option<String>,
+}
+
+/// Shows the merges that produce the tokens of each piece.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "explain")]
+pub(crate) struct ExplainCommand {
+    /// print the merge trace as json.
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// Checks encodings against reference fixture files and reports mismatches.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "verify")]
+pub(crate) struct VerifyCommand {
+    /// fixture files with one {"vocabulary", "text", "ids"} json object per line.
+    #[argh(positional)]
+    pub files: Vec<String>,
+}
+
+/// Reports token statistics and an id-frequency table for a corpus read from stdin.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "stats")]
+pub(crate) struct StatsCommand {
+    /// write the statistics as csv instead of json.
+    #[argh(switch)]
+    pub csv: bool,
+}
+
+/// Counts the tokens of a JSON array of chat messages read from stdin.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "chat")]
+pub(crate) struct ChatCommand {
+    /// chat template to render the messages in (chatml, plain) [default: chatml].
+    #[argh(option, default = "bpe::chat::Template::default()")]
+    pub template: bpe::chat::Template,
+}
+
+/// Packs a JSON array of prioritised segments read from stdin into a token budget.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "pack")]
+pub(crate) struct PackCommand {
+    /// the most tokens the packed prompt may have.
+    #[argh(option)]
+    pub budget: usize,
+}
+
+/// Truncates each line to a token limit, marking the cut with an ellipsis.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "truncate")]
+pub(crate) struct TruncateCommand {
+    /// the most tokens a line may encode to.
+    #[argh(option)]
+    pub max_tokens: usize,
+    /// side of the line to remove text from (head, tail, middle) [default: tail].
+    #[argh(option, default = "bpe::truncation::Side::default()")]
+    pub side: bpe::truncation::Side,
+    /// print the truncated text, its ids and the removed byte range as json.
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// Lists the tokens consistent with each line read as a partial string.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "prefix")]
+pub(crate) struct PrefixCommand {
+    /// print the exact and longest matches and the compatible tokens as json.
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// Encodes a corpus into train and validation shards of token IDs for training.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "prepare")]
+pub(crate) struct PrepareCommand {
+    /// directory to write the .bin shards and meta.json to.
+    #[argh(positional)]
+    pub directory: String,
+    /// probability of assigning a document to the validation split [default: 0.1].
+    #[argh(option, default = "0.1")]
+    pub validation: f64,
+    /// read each line as a json object and use this string field as the document.
+    #[argh(option)]
+    pub field: Option<String>,
+    /// the most tokens a shard may hold; without it each split is written to one file.
+    #[argh(option)]
+    pub shard_tokens: Option<usize>,
+}
+
+/// Samples random token windows from a prepared dataset to inspect them.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "sample")]
+pub(crate) struct SampleCommand {
+    /// directory holding the meta.json and .bin shards written by prepare.
+    #[argh(positional)]
+    pub directory: String,
+    /// split to sample from (train, val) [default: train].
+    #[argh(option, default = "String::from(\"train\")")]
+    pub split: String,
+    /// the number of tokens of each window.
+    #[argh(option)]
+    pub context: usize,
+    /// the number of windows to sample [default: 1].
+    #[argh(option, default = "1")]
+    pub batch: usize,
+}
+
+/// Serves the tokenizer as JSON endpoints over HTTP/1.1.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "serve")]
+pub(crate) struct ServeCommand {
+    /// address to listen on [default: 127.0.0.1].
+    #[argh(option, default = "String::from(\"127.0.0.1\")")]
+    pub host: String,
+    /// port to listen on [default: 8080].
+    #[argh(option, default = "8080")]
+    pub port: u16,
+    /// number of worker threads [default: one per cpu].
+    #[argh(option)]
+    pub workers: Option<usize>,
+}
+
 /// An enumeration of all available subcommands.
 #[derive(FromArgs, Debug)]
 #[argh(subcommand)]
 pub(crate) enum Command {
     Grapheme(GraphemeCommand),
-    Embed(EmbedCommand)
+    Embed(EmbedCommand),
+    Compare(CompareCommand),
+    Show(ShowCommand),
+    Explain(ExplainCommand),
+    Verify(VerifyCommand),
+    Stats(StatsCommand),
+    Chat(ChatCommand),
+    Pack(PackCommand),
+    Truncate(TruncateCommand),
+    Prefix(PrefixCommand),
+    Prepare(PrepareCommand),
+    Sample(SampleCommand),
+    Serve(ServeCommand),
+}
+
+/// The format of the output of the CLI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Format {
+    /// The human-readable output of each mode.
+    #[default]
+    Text,
+    /// One JSON array holding a record for each line.
+    Json,
+    /// One JSON record per line, written as soon as the line is read.
+    Ndjson,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    /// Parses a string into a `Format`.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The string to parse. Case-insensitive.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the string is not a valid format.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            _ => Err(format!("unknown format: {s}. Please use one of: text, json, ndjson")),
+        }
+    }
 }
 
 /// A command-line utility for the GPT Byte-Pair-Encoder.
@@ -38,6 +222,18 @@ pub(crate) enum Command {
 ///   - decode: Decodes a sequence of tokens back into a string.
 ///   - grapheme: Splits a string into GPT unicode grapheme characters.
 ///   - embed: Returns vector representation of text embedding.
+///   - compare: Compares tokenization across every vocabulary.
+///   - show: Renders each token with alternating colours.
+///   - explain: Shows the merges that produce each token.
+///   - verify: Checks encodings against reference fixture files.
+///   - stats: Reports token statistics for a corpus.
+///   - chat: Counts the tokens of chat messages.
+///   - pack: Packs prioritised segments into a token budget.
+///   - truncate: Truncates each line to a token limit.
+///   - prefix: Lists the tokens consistent with a partial string.
+///   - prepare: Writes a corpus as binary token shards for training.
+///   - sample: Samples token windows from prepared shards.
+///   - serve: Serves the tokenizer over HTTP.
 ///
 /// Input should be piped to the command via stdin.
 /// For example:
@@ -72,11 +268,186 @@ pub struct Arguments {
     )]
     pub vocabulary: bpe::vocabulary::Vocabularies,
 
+    /// How to decode token IDs missing from the vocabulary.
+    #[argh(
+        option,
+        description = "how to decode unknown token ids (error, skip, replace) [default: error]",
+        default = "bpe::Policy::Error"
+    )]
+    pub unknown: bpe::Policy,
+
+    /// How to decode input that is not a token ID.
+    #[argh(
+        option,
+        description = "how to decode input that is not a token id (error, skip, replace) [default: skip]",
+        default = "bpe::Policy::Skip"
+    )]
+    pub unparsable: bpe::Policy,
+
+    /// Encode with BPE-dropout, skipping each merge with this probability.
+    #[argh(
+        option,
+        description = "encode with BPE-dropout, skipping each merge with this probability (0 to 1)"
+    )]
+    pub dropout: Option<f64>,
+
+    /// The seed of the random number generator used by BPE-dropout.
+    #[argh(
+        option,
+        description = "seed for BPE-dropout [default: 0]",
+        default = "0"
+    )]
+    pub seed: u64,
+
+    /// The format of the output.
+    #[argh(
+        option,
+        description = "output format (text, json, ndjson) [default: text]",
+        default = "Format::default()"
+    )]
+    pub(crate) format: Format,
+
+    /// Read all of the input as one document instead of line by line.
+    #[argh(
+        switch,
+        description = "read all of the input as one document, keeping its line breaks"
+    )]
+    pub whole: bool,
+
+    /// The files to read instead of stdin.
+    #[argh(
+        option,
+        description = "read this file instead of stdin; may be repeated to read several files in order"
+    )]
+    pub input: Vec<String>,
+
+    /// The file to write to instead of stdout.
+    #[argh(
+        option,
+        description = "write to this file instead of stdout"
+    )]
+    pub output: Option<String>,
+
+    /// Answer JSON-RPC 2.0 requests, one per line, instead of processing the input.
+    #[argh(
+        switch,
+        description = "answer json-rpc 2.0 requests read line by line until the input ends"
+    )]
+    pub rpc: bool,
+
     /// an optional subcommand to execute (e.g., `grapheme`).
     #[argh(subcommand)]
     pub(crate) command: Option<Command>,
 }
 
+/// A reader that ends its input with a line break if it does not end with one, so
+/// that the last line of a file does not run into the first line of the next.
+#[derive(Debug)]
+pub (crate) struct Terminated<R> {
+    /// The reader of the file.
+    reader: R,
+    /// The last byte read, or `None` if nothing was read yet.
+    last: Option<u8>,
+    /// Whether the line break was added.
+    done: bool,
+}
+
+impl<R: std::io::Read> Terminated<R> {
+    /// Wraps a reader.
+    pub (crate) fn new(reader: R) -> Self {
+        Terminated { reader, last: None, done: false }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for Terminated<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if buffer.is_empty() || self.done {
+            return Ok(0);
+        };
+        let read = self.reader.read(buffer)?;
+        match (read, self.last) {
+            (0, Some(last)) if last != b'\n' => {
+                self.done = true;
+                buffer[0] = b'\n';
+                Ok(1)
+            }
+            (0, _) => Ok(0),
+            (read, _) => {
+                self.last = Some(buffer[read - 1]);
+                Ok(read)
+            }
+        }
+    }
+}
+
+/// Opens the input of the CLI: the `--input` files one after another, or stdin.
+///
+/// Every file but the last is ended with a line break if it does not end with one, so
+/// that files are never joined in the middle of a line. The last file is read as is,
+/// so that `--whole` keeps a single file byte for byte.
+///
+/// # Returns
+///
+/// A reader over all of the input, or an error if an input file cannot be opened.
+pub (crate) fn input(args: &Arguments) -> Result<Box<dyn std::io::BufRead>, String> {
+    let mut reader: Box<dyn std::io::Read> = match args.input.is_empty() {
+        true => Box::new(std::io::stdin().lock()),
+        false => Box::new(std::io::empty()),
+    };
+    for (index, path) in args.input.iter().enumerate() {
+        let file = std::fs::File::open(path).map_err(|e| format!("Could not open input {path}: {e}"))?;
+        reader = match index + 1 == args.input.len() {
+            true => Box::new(std::io::Read::chain(reader, file)),
+            false => Box::new(std::io::Read::chain(reader, Terminated::new(file))),
+        };
+    }
+    Ok(Box::new(std::io::BufReader::new(reader)))
+}
+
+/// Splits the input of the CLI into the units every mode works on.
+///
+/// # Returns
+///
+/// An iterator over the lines of the input without their line breaks, or, with
+/// `--whole`, over the whole input as a single document with its line breaks intact.
+/// Input that is not valid UTF-8 yields an error naming the line it is on.
+pub (crate) fn documents(
+    reader: Box<dyn std::io::BufRead>,
+    args: &Arguments,
+) -> Box<dyn Iterator<Item = std::io::Result<String>>> {
+    let invalid = |error: std::io::Error, place: String| match error.kind() {
+        std::io::ErrorKind::InvalidData => {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{place} is not valid UTF-8"))
+        }
+        _ => error,
+    };
+    match args.whole {
+        true => Box::new(std::iter::once(
+            std::io::read_to_string(reader).map_err(move |e| invalid(e, "The input".to_string())),
+        )),
+        false => Box::new(
+            std::io::BufRead::lines(reader)
+                .enumerate()
+                .map(move |(index, line)| line.map_err(|e| invalid(e, format!("Line {}", index + 1)))),
+        ),
+    }
+}
+
+/// Opens the output of the CLI: the `--output` file, or stdout.
+///
+/// # Returns
+///
+/// A writer to the output, or an error if the output file cannot be created.
+pub (crate) fn output(args: &Arguments) -> Result<Box<dyn std::io::Write>, String> {
+    match &args.output {
+        Some(path) => {
+            let file = std::fs::File::create(path).map_err(|e| format!("Could not create output {path}: {e}"))?;
+            Ok(Box::new(std::io::BufWriter::new(file)))
+        }
+        None => Ok(Box::new(std::io::stdout().lock())),
+    }
+}
+
 /// Splits an input string into its constituent GPT-style graphemes and writes them to a writer.
 ///
 /// Graphemes are space-separated in the output.
@@ -100,72 +471,959 @@ pub (crate) fn grapheme(line: String) -> String {
 /// Decodes a space-separated string of token IDs into text and writes it to a writer.
 ///
 /// The function selects the appropriate decoding map based on the vocabulary
-/// specified in the `Arguments`. It gracefully handles empty input and ignores
-/// any non-numeric parts of the input string.
+/// specified in the `Arguments`. It gracefully handles empty input. Token IDs
+/// missing from the vocabulary are handled with the `--unknown` policy, and parts
+/// of the input that are not numbers with the `--unparsable` policy.
 ///
 /// # Arguments
 ///
 /// * `line` - The input `String` of space-separated token IDs.
-/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary
+///   and the decoding policies.
 ///
 /// # Returns
 ///
-/// An `io::Result<()>` indicating the success or failure of the write operation.
-pub (crate) fn decode(line: String, args: &Arguments) -> Vec<u8> {
+/// The decoded bytes, or an error if a policy is `error` and the input breaks it.
+pub (crate) fn decode(line: String, args: &Arguments) -> Result<Vec<u8>, String> {
+    decode_ids(line, args).map(|(_, bytes)| bytes)
+}
+
+/// Decodes a line of token IDs like `decode`, also returning the IDs that were decoded.
+///
+/// IDs dropped or replaced by the `--unknown` and `--unparsable` policies are not
+/// among them.
+fn decode_ids(line: String, args: &Arguments) -> Result<(Vec<u32>, Vec<u8>), String> {
     if line.trim().is_empty() {
-        return vec![]
+        return Ok((vec![], vec![]))
     };
     match args.vocabulary {
         bpe::vocabulary::Vocabularies::R50K => {
-            let tokens: Vec<u16> = line
-                .split_whitespace()
-                .filter_map(|s| s.parse().ok())
-                .collect();
-            bpe::decode(&tokens, &bpe::vocabulary::R50K_UNICODES)
+            decode_words(&line, &bpe::vocabulary::R50K_UNICODES, args)
         }
         bpe::vocabulary::Vocabularies::P50K => {
-            let tokens: Vec<u16> = line
-                .split_whitespace()
-                .filter_map(|s| s.parse().ok())
-                .collect();
-            bpe::decode(&tokens, &bpe::vocabulary::P50K_UNICODES)
+            decode_words(&line, &bpe::vocabulary::P50K_UNICODES, args)
         }
         bpe::vocabulary::Vocabularies::CL100K => {
-            let tokens: Vec<u32> = line
-                .split_whitespace()
-                .filter_map(|s| s.parse().ok())
-                .collect();
-            bpe::decode(&tokens, &bpe::vocabulary::CL100K_UNICODES)
+            decode_words(&line, &bpe::vocabulary::CL100K_UNICODES, args)
         }
         bpe::vocabulary::Vocabularies::O200K => {
-            let tokens: Vec<u32> = line
-                .split_whitespace()
-                .filter_map(|s| s.parse().ok())
+            decode_words(&line, &bpe::vocabulary::O200K_UNICODES, args)
+        }
+    }
+}
+
+/// Decodes each whitespace-separated word of `line`, applying the decoding policies.
+///
+/// Numbers that do not fit the vocabulary's ID type are treated as unknown token IDs.
+/// Returns the IDs that were decoded along with the decoded bytes.
+fn decode_words<T>(
+    line: &str,
+    lookup: &std::sync::LazyLock<std::collections::BTreeMap<T, Vec<u16>>>,
+    args: &Arguments,
+) -> Result<(Vec<u32>, Vec<u8>), String>
+where
+    T: TryFrom<u32> + Copy + Ord + std::fmt::Debug + std::fmt::Display,
+{
+    let mut ids = vec![];
+    let mut result = vec![];
+    for word in line.split_whitespace() {
+        let token: u32 = match (word.parse(), args.unparsable) {
+            (Ok(token), _) => token,
+            (Err(_), bpe::Policy::Error) => return Err(format!("Could not parse token {word:?}.")),
+            (Err(_), bpe::Policy::Skip) => continue,
+            (Err(_), bpe::Policy::Replace) => {
+                result.extend_from_slice(bpe::REPLACEMENT);
+                continue;
+            }
+        };
+        match (T::try_from(token).ok().filter(|id| lookup.contains_key(id)), args.unknown) {
+            (Some(id), _) => {
+                bpe::decode_into(&[id], lookup, &mut result).map_err(|e| e.to_string())?;
+                ids.push(token);
+            }
+            (None, bpe::Policy::Error) => return Err(format!("Token ID {token} not found.")),
+            (None, bpe::Policy::Skip) => continue,
+            (None, bpe::Policy::Replace) => result.extend_from_slice(bpe::REPLACEMENT),
+        }
+    }
+    Ok((ids, result))
+}
+
+/// Encodes a byte slice with the given vocabulary.
+///
+/// # Arguments
+///
+/// * `slice` - The bytes to encode.
+/// * `vocabulary` - The vocabulary to encode with.
+///
+/// # Returns
+///
+/// The token IDs of each regex piece, as returned by `bpe::encode`.
+pub (crate) fn encode(slice: &[u8], vocabulary: &bpe::vocabulary::Vocabularies) -> Vec<Vec<u32>> {
+    vocabulary.encode(slice)
+}
+
+/// Encodes a byte slice with BPE-dropout using the given vocabulary.
+///
+/// # Arguments
+///
+/// * `slice` - The bytes to encode.
+/// * `vocabulary` - The vocabulary to encode with.
+/// * `p` - The probability of skipping each merge.
+/// * `rng` - The random number generator, shared across lines so each line is split differently.
+///
+/// # Returns
+///
+/// The token IDs of each regex piece, as returned by `bpe::encode_dropout`.
+pub (crate) fn encode_dropout(
+    slice: &[u8],
+    vocabulary: &bpe::vocabulary::Vocabularies,
+    p: f64,
+    rng: &mut rand::rngs::StdRng,
+) -> Vec<Vec<u32>> {
+    vocabulary.encode_dropout(slice, p, rng)
+}
+
+/// Decodes each token on its own, returning the bytes every token stands for.
+///
+/// # Arguments
+///
+/// * `tokens` - The token IDs to decode.
+/// * `vocabulary` - The vocabulary the tokens belong to.
+///
+/// # Errors
+///
+/// Returns an error if a token is not in the vocabulary.
+pub (crate) fn pieces(tokens: &[u32], vocabulary: &bpe::vocabulary::Vocabularies) -> Result<Vec<Vec<u8>>, String> {
+    tokens.iter().map(|token| vocabulary.decode(&[*token], bpe::Policy::Error)).collect()
+}
+
+/// Returns the byte offset at which each token starts.
+fn offsets(pieces: &[Vec<u8>]) -> Vec<usize> {
+    pieces
+        .iter()
+        .scan(0, |offset, piece| {
+            let start = *offset;
+            *offset += piece.len();
+            Some(start)
+        })
+        .collect()
+}
+
+/// The tokenization of a line with a single vocabulary.
+#[derive(serde::Serialize, Debug)]
+pub(crate) struct Comparison {
+    /// The vocabulary used.
+    pub vocabulary: String,
+    /// The token IDs.
+    pub ids: Vec<u32>,
+    /// The number of tokens.
+    pub count: usize,
+    /// The number of characters per token.
+    pub ratio: f64,
+    /// The byte offset at which each token starts.
+    pub offsets: Vec<usize>,
+}
+
+/// Encodes a line with every available vocabulary.
+fn comparisons(line: &str) -> Result<Vec<Comparison>, String> {
+    let characters = line.chars().count();
+    bpe::vocabulary::Vocabularies::iter()
+        .filter(|vocabulary| vocabulary.available())
+        .map(|vocabulary| {
+            let ids = encode(line.as_bytes(), vocabulary).concat();
+            let offsets = offsets(&pieces(&ids, vocabulary)?);
+            let ratio = if ids.is_empty() { 0.0 } else { characters as f64 / ids.len() as f64 };
+            Ok(Comparison { vocabulary: vocabulary.to_string(), count: ids.len(), ids, ratio, offsets })
+        })
+        .collect()
+}
+
+/// Encodes a line with every available vocabulary and reports the differences.
+///
+/// Vocabularies whose files cannot be found are skipped. The table lists the token
+/// count and characters per token of each vocabulary, followed by the line with a `^`
+/// under every character a token starts in, so that boundaries line up by byte offset.
+///
+/// # Arguments
+///
+/// * `line` - The input `String` to compare.
+/// * `command` - The parsed `compare` subcommand.
+///
+/// # Returns
+///
+/// The comparison as a table, or as a JSON object when `--json` is set.
+pub (crate) fn compare(line: String, command: &CompareCommand) -> Result<String, String> {
+    let comparisons = comparisons(&line)?;
+    if command.json {
+        return Ok(serde_json::json!({ "input": line, "vocabularies": comparisons }).to_string());
+    };
+
+    let text: String = line.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+    let mut table = format!("{:<10} {:>6} {:>11}  {}\n", "vocabulary", "tokens", "chars/token", text);
+    for comparison in &comparisons {
+        let mut starts = comparison.offsets.iter().peekable();
+        let marks: String = line
+            .char_indices()
+            .map(|(index, c)| {
+                let mut mark = ' ';
+                while starts.next_if(|&&start| start < index + c.len_utf8()).is_some() {
+                    mark = '^';
+                }
+                mark
+            })
+            .collect();
+        table += &format!(
+            "{:<10} {:>6} {:>11.2}  {}\n",
+            comparison.vocabulary,
+            comparison.count,
+            comparison.ratio,
+            marks.trim_end()
+        );
+    }
+    Ok(table.trim_end().to_string())
+}
+
+/// Traces the merges applied to every piece of a line with the given vocabulary.
+fn traces(line: &str, vocabulary: &bpe::vocabulary::Vocabularies) -> Vec<bpe::Trace> {
+    vocabulary.trace(line.as_bytes())
+}
+
+/// Traces the merges applied to every piece of a line.
+///
+/// For each piece, the text output lists the graphemes the encoder starts from,
+/// then one row per merge with its rank, the pair merged and the resulting token IDs.
+///
+/// # Arguments
+///
+/// * `line` - The input `String` to explain.
+/// * `command` - The parsed `explain` subcommand.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// The merge trace as text, or as a JSON array when `--json` is set.
+pub (crate) fn explain(line: String, command: &ExplainCommand, args: &Arguments) -> String {
+    let traces = traces(&line, &args.vocabulary);
+    if command.json {
+        return serde_json::to_string(&traces).unwrap();
+    };
+
+    let ids = |tokens: &[u32]| {
+        tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" ")
+    };
+    let mut output = vec![];
+    for trace in &traces {
+        output.push(format!("{:?} {}", trace.piece, trace.graphemes.join(" ")));
+        if trace.whole {
+            output.push("  whole piece found in vocabulary".to_string());
+        };
+        for (step, merge) in trace.merges.iter().enumerate() {
+            output.push(format!(
+                "  {:>3}. rank {:>6}  {} + {} -> [{}]",
+                step + 1,
+                merge.rank,
+                merge.left,
+                merge.right,
+                ids(&merge.tokens)
+            ));
+        }
+        output.push(format!("  => [{}]", ids(&trace.tokens)));
+    }
+    output.join("\n")
+}
+
+/// A reference encoding, as produced by the reference tokenizer.
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct Fixture {
+    /// The vocabulary, e.g. `cl100k` or `cl100k_base`.
+    pub vocabulary: String,
+    /// The text to encode.
+    pub text: String,
+    /// The expected token IDs.
+    pub ids: Vec<u32>,
+    /// Whether the encoder is known not to match this fixture yet.
+    #[serde(default)]
+    pub xfail: bool,
+}
+
+/// The outcome of running a fixture file.
+#[derive(Debug, Default)]
+pub(crate) struct Verification {
+    /// The number of fixtures whose encoding matched.
+    pub passed: usize,
+    /// The number of fixtures whose vocabulary file is not available.
+    pub skipped: usize,
+    /// The line number and a report of each fixture whose encoding did not match, or
+    /// matched although it is marked `xfail`.
+    pub mismatches: Vec<(usize, String)>,
+    /// The line number and a report of each fixture marked `xfail` that still fails.
+    pub known: Vec<(usize, String)>,
+}
+
+impl Verification {
+    /// The verification of a fixture file as a record of the `json` and `ndjson` formats.
+    pub(crate) fn record(&self, path: &str) -> serde_json::Value {
+        let reports = |reports: &[(usize, String)]| -> Vec<serde_json::Value> {
+            reports
+                .iter()
+                .map(|(line, report)| serde_json::json!({ "line": line, "report": report }))
+                .collect()
+        };
+        serde_json::json!({
+            "input": path,
+            "passed": self.passed,
+            "failed": self.mismatches.len(),
+            "known": self.known.len(),
+            "skipped": self.skipped,
+            "mismatches": reports(&self.mismatches),
+            "known_mismatches": reports(&self.known),
+        })
+    }
+}
+
+/// Diffs two token sequences.
+///
+/// Tokens common to both are written as is, tokens only in `expected` are prefixed
+/// with `-` and tokens only in `actual` with `+`.
+fn diff(expected: &[u32], actual: &[u32]) -> String {
+    // Longest common subsequence lengths of every pair of suffixes.
+    let mut lengths = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lengths[i][j] = if expected[i] == actual[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut result = vec![];
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            result.push(expected[i].to_string());
+            (i, j) = (i + 1, j + 1);
+        } else if j == actual.len() || (i < expected.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            result.push(format!("-{}", expected[i]));
+            i += 1;
+        } else {
+            result.push(format!("+{}", actual[j]));
+            j += 1;
+        }
+    }
+    result.join(" ")
+}
+
+/// Encodes every fixture in a file and compares the result with the expected IDs.
+///
+/// Fixtures whose vocabulary file is not available are skipped. Fixtures marked
+/// `xfail` are known failures: they are reported apart from the mismatches while they
+/// fail, and as a mismatch once they pass, so that the marker is removed.
+///
+/// # Arguments
+///
+/// * `path` - The path of a file with one JSON `Fixture` per line. Empty lines and
+///   lines starting with `#` are ignored.
+///
+/// # Returns
+///
+/// The `Verification` of the file, or an error if the file cannot be read or a
+/// line is not a valid fixture.
+pub (crate) fn verify(path: &str) -> Result<Verification, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read fixtures {path}: {e}"))?;
+    let mut verification = Verification::default();
+    for (idx, line) in contents.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        };
+        let fixture: Fixture = serde_json::from_str(line)
+            .map_err(|e| format!("Could not parse fixture {path}:{}: {e}", idx + 1))?;
+        let vocabulary: bpe::vocabulary::Vocabularies = fixture
+            .vocabulary
+            .parse()
+            .map_err(|e| format!("Could not parse fixture {path}:{}: {e}", idx + 1))?;
+        if !vocabulary.available() {
+            verification.skipped += 1;
+            continue;
+        };
+
+        let actual = encode(fixture.text.as_bytes(), &vocabulary).concat();
+        if actual == fixture.ids {
+            match fixture.xfail {
+                true => verification.mismatches.push((
+                    idx + 1,
+                    format!("{path}:{} [{vocabulary}] {:?}\n  passes, please remove its xfail marker", idx + 1, fixture.text),
+                )),
+                false => verification.passed += 1,
+            };
+            continue;
+        };
+        let ids = |tokens: &[u32]| {
+            tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(" ")
+        };
+        let reports = match fixture.xfail {
+            true => &mut verification.known,
+            false => &mut verification.mismatches,
+        };
+        reports.push((
+            idx + 1,
+            format!(
+                "{path}:{} [{vocabulary}] {:?}\n  expected: {}\n  actual:   {}\n  diff:     {}",
+                idx + 1,
+                fixture.text,
+                ids(&fixture.ids),
+                ids(&actual),
+                diff(&fixture.ids, &actual)
+            ),
+        ));
+    }
+    Ok(verification)
+}
+
+/// Streams a corpus through the encoder and summarises its tokens.
+///
+/// Every line is encoded on its own; empty lines separate documents.
+///
+/// # Arguments
+///
+/// * `reader` - The corpus.
+/// * `command` - The parsed `stats` subcommand.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// The `bpe::statistics::Summary` as JSON, or as CSV when `--csv` is set, or an error if
+/// the corpus cannot be read or `--csv` is combined with a format other than `text`.
+pub (crate) fn stats<R: std::io::BufRead>(
+    reader: R,
+    command: &StatsCommand,
+    args: &Arguments,
+) -> Result<String, String> {
+    if command.csv && args.format != Format::Text {
+        return Err("--csv only applies to the text format.".to_string());
+    };
+    let mut statistics = bpe::statistics::Statistics::new();
+    for line in reader.lines() {
+        let line = line.map_err(|e| format!("Could not read corpus: {e}"))?;
+        match args.vocabulary {
+            bpe::vocabulary::Vocabularies::R50K => {
+                statistics.encode(line.as_bytes(), &bpe::vocabulary::R50K_TOKENS)
+            },
+            bpe::vocabulary::Vocabularies::P50K => {
+                statistics.encode(line.as_bytes(), &bpe::vocabulary::P50K_TOKENS)
+            },
+            bpe::vocabulary::Vocabularies::CL100K => {
+                statistics.encode(line.as_bytes(), &bpe::vocabulary::CL100K_TOKENS)
+            },
+            bpe::vocabulary::Vocabularies::O200K => {
+                statistics.encode(line.as_bytes(), &bpe::vocabulary::O200K_TOKENS)
+            }
+        };
+    }
+    let summary = match args.vocabulary {
+        bpe::vocabulary::Vocabularies::R50K => statistics.summary(&bpe::vocabulary::R50K_UNICODES),
+        bpe::vocabulary::Vocabularies::P50K => statistics.summary(&bpe::vocabulary::P50K_UNICODES),
+        bpe::vocabulary::Vocabularies::CL100K => statistics.summary(&bpe::vocabulary::CL100K_UNICODES),
+        bpe::vocabulary::Vocabularies::O200K => statistics.summary(&bpe::vocabulary::O200K_UNICODES),
+    };
+    match command.csv {
+        true => Ok(summary.to_csv()),
+        false => Ok(serde_json::to_string(&summary).unwrap()),
+    }
+}
+
+/// Renders a JSON array of chat messages in a chat template and counts their tokens.
+///
+/// # Arguments
+///
+/// * `input` - A JSON array of `{"role", "name", "content"}` objects; `name` is optional.
+/// * `command` - The parsed `chat` subcommand.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// The `bpe::chat::Chat` as JSON, or an error if the input is not a valid list of
+/// messages or the vocabulary lacks the template's special tokens.
+pub (crate) fn chat(input: &str, command: &ChatCommand, args: &Arguments) -> Result<String, String> {
+    let messages: Vec<bpe::chat::Message> = serde_json::from_str(input)
+        .map_err(|e| format!("Could not parse messages: {e}"))?;
+    let chat = bpe::chat::render(&messages, command.template, &args.vocabulary)?;
+    Ok(serde_json::to_string(&chat).unwrap())
+}
+
+/// Packs a JSON array of prioritised segments into a token budget.
+///
+/// # Arguments
+///
+/// * `input` - A JSON array of `{"text", "priority", "min", "max"}` objects; all but
+///   `text` are optional.
+/// * `command` - The parsed `pack` subcommand.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// The `bpe::pack::Pack` as JSON, or an error if the input is not a valid list of segments.
+pub (crate) fn pack(input: &str, command: &PackCommand, args: &Arguments) -> Result<String, String> {
+    let segments: Vec<bpe::pack::Segment> = serde_json::from_str(input)
+        .map_err(|e| format!("Could not parse segments: {e}"))?;
+    let pack = bpe::pack::pack(&segments, command.budget, &args.vocabulary);
+    Ok(serde_json::to_string(&pack).unwrap())
+}
+
+/// Encodes a corpus and writes it as train and validation shards.
+///
+/// Every non-empty line of the input is a document, or, with `--field`, the string
+/// field of the JSON object on every non-empty line. With `--whole`, every `--input`
+/// file, or stdin, is one document instead. Documents are split with a random number
+/// generator seeded by `--seed`, so the same corpus always gives the same shards.
+///
+/// # Arguments
+///
+/// * `reader` - The input, used unless `--whole` is set.
+/// * `command` - The parsed `prepare` subcommand.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// The `bpe::dataset::Metadata` as JSON, or an error if a document cannot be read or
+/// parsed, or a shard cannot be written.
+pub (crate) fn prepare(
+    reader: Box<dyn std::io::BufRead>,
+    command: &PrepareCommand,
+    args: &Arguments,
+) -> Result<String, String> {
+    use rand::SeedableRng;
+    let documents: Box<dyn Iterator<Item = Result<String, String>>> = match (args.whole, args.input.is_empty()) {
+        (true, true) => Box::new(std::iter::once(
+            std::io::read_to_string(reader).map_err(|e| format!("Could not read input: {e}")),
+        )),
+        (true, false) => Box::new(args.input.iter().map(|path| {
+            std::fs::read_to_string(path).map_err(|e| format!("Could not read input {path}: {e}"))
+        })),
+        (false, _) => {
+            let field = command.field.clone();
+            let lines = std::io::BufRead::lines(reader)
+                .map(|line| line.map_err(|e| format!("Could not read input: {e}")))
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()));
+            Box::new(lines.map(move |line| match &field {
+                Some(field) => line.and_then(|line| {
+                    let value: serde_json::Value = serde_json::from_str(&line)
+                        .map_err(|e| format!("Could not parse document: {e}"))?;
+                    value
+                        .get(field)
+                        .and_then(|value| value.as_str())
+                        .map(str::to_string)
+                        .ok_or_else(|| format!("Document has no string field {field}: {line}"))
+                }),
+                None => line,
+            }))
+        }
+    };
+    let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+    let metadata = bpe::dataset::prepare(
+        documents,
+        &args.vocabulary,
+        std::path::Path::new(&command.directory),
+        command.validation,
+        command.shard_tokens,
+        &mut rng,
+    )?;
+    Ok(serde_json::to_string(&metadata).unwrap())
+}
+
+/// Samples random windows from a split written by `prepare`.
+///
+/// The windows are drawn with a random number generator seeded by `--seed` and decoded
+/// with the vocabulary recorded in `meta.json`, whatever `--vocabulary` says.
+///
+/// # Arguments
+///
+/// * `command` - The parsed `sample` subcommand.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the seed and format.
+///
+/// # Returns
+///
+/// One decoded window per line with `U+FFFD` for bytes that do not form valid UTF-8, or,
+/// unless the format is `text`, the `bpe::dataset::Batch` as JSON together with the
+/// decoded `texts`. An error if the dataset cannot be opened or holds no window.
+pub (crate) fn sample(command: &SampleCommand, args: &Arguments) -> Result<String, String> {
+    use rand::SeedableRng;
+    let dataset = bpe::dataset::Dataset::open(std::path::Path::new(&command.directory), &command.split)?;
+    let vocabulary = dataset.vocabulary()?;
+    let rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+    let batch = bpe::dataset::Sampler::new(&dataset, command.context, rng)?.batch(command.batch);
+    let texts = batch.decode(&vocabulary);
+    match args.format {
+        Format::Text => Ok(texts.join("\n")),
+        _ => Ok(serde_json::json!({
+            "vocabulary": vocabulary.to_string(),
+            "contexts": batch.contexts,
+            "targets": batch.targets,
+            "texts": texts,
+        })
+        .to_string()),
+    }
+}
+
+/// Truncates a line to a token limit.
+///
+/// # Arguments
+///
+/// * `line` - The line to truncate.
+/// * `command` - The parsed `truncate` subcommand.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// The truncated line, or the `bpe::truncation::Truncation` as JSON if `--json` is set.
+pub (crate) fn truncate(line: String, command: &TruncateCommand, args: &Arguments) -> String {
+    let truncation = bpe::truncation::truncate(&line, command.max_tokens, command.side, &args.vocabulary);
+    match command.json {
+        true => serde_json::to_string(&truncation).unwrap(),
+        false => truncation.text,
+    }
+}
+
+/// A token consistent with a partial string.
+#[derive(serde::Serialize, Debug)]
+pub (crate) struct Candidate {
+    /// The token ID.
+    id: u32,
+    /// The text of the token, with invalid UTF-8 replaced by `U+FFFD`.
+    text: String,
+}
+
+/// Lists the tokens consistent with a line read as a partial string, using the byte
+/// trie of the vocabulary.
+///
+/// # Arguments
+///
+/// * `line` - The partial string.
+/// * `command` - The parsed `prefix` subcommand.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// The IDs of the compatible tokens separated by spaces, or, if `--json` is set, an
+/// object with the token that is exactly the line, the longest token matching the
+/// start of the line and the compatible tokens with their text.
+pub (crate) fn prefix(line: String, command: &PrefixCommand, args: &Arguments) -> String {
+    if !command.json {
+        let compatible = args.vocabulary.trie().compatible(line.as_bytes());
+        return compatible.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(" ");
+    };
+    matches(&line, args).to_string()
+}
+
+/// Looks a partial string up in the byte trie of the vocabulary.
+///
+/// # Returns
+///
+/// An object with the token that is exactly the line, the longest token matching the
+/// start of the line and the compatible tokens with their text.
+fn matches(line: &str, args: &Arguments) -> serde_json::Value {
+    let trie = args.vocabulary.trie();
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    let mut candidates: Vec<Candidate> = trie
+        .prefixes_of(line.as_bytes())
+        .into_iter()
+        .map(|(length, id)| Candidate { id, text: text(&line.as_bytes()[..length]) })
+        .chain(trie.starting_with(line.as_bytes()).into_iter().map(|(bytes, id)| Candidate { id, text: text(&bytes) }))
+        .collect();
+    candidates.sort_by_key(|candidate| candidate.id);
+    candidates.dedup_by_key(|candidate| candidate.id);
+    let longest = trie
+        .longest_match(line.as_bytes())
+        .map(|(length, id)| Candidate { id, text: text(&line.as_bytes()[..length]) });
+    let exact = trie.get(line.as_bytes());
+    serde_json::json!({ "input": line, "exact": exact, "longest": longest, "compatible": candidates })
+}
+
+/// Makes the text of a token visible.
+///
+/// Spaces, tabs and line breaks are replaced with visible symbols, other control
+/// characters are escaped, and byte-fallback tokens that are not valid UTF-8 on
+/// their own are written as hex bytes like `<F0>`.
+///
+/// # Returns
+///
+/// The visible text and whether the token is a byte-fallback token.
+fn visible(piece: &[u8]) -> (String, bool) {
+    match std::str::from_utf8(piece) {
+        Ok(text) => {
+            let text = text
+                .chars()
+                .map(|c| match c {
+                    ' ' => "·".to_string(),
+                    '\t' => "→".to_string(),
+                    '\n' => "↵".to_string(),
+                    '\r' => "␍".to_string(),
+                    c if c.is_control() => c.escape_default().to_string(),
+                    c => c.to_string(),
+                })
+                .collect();
+            (text, false)
+        }
+        Err(_) => (piece.iter().map(|b| format!("<{b:02X}>")).collect(), true),
+    }
+}
+
+/// Renders every token of a line with alternating background colours.
+///
+/// Byte-fallback tokens are rendered on red.
+///
+/// # Arguments
+///
+/// * `line` - The input `String` to render.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// The line with ANSI colour escapes around each token, or an error if a token
+/// cannot be decoded.
+pub (crate) fn show(line: String, args: &Arguments) -> Result<String, String> {
+    let ids = encode(line.as_bytes(), &args.vocabulary).concat();
+    let mut output = String::new();
+    for (index, piece) in pieces(&ids, &args.vocabulary)?.iter().enumerate() {
+        let (text, fallback) = visible(piece);
+        let colour = match (fallback, index % 2) {
+            (true, _) => "41",
+            (false, 0) => "44",
+            (false, _) => "45",
+        };
+        output += &format!("\x1b[{colour};97m{text}\x1b[0m");
+    }
+    Ok(output)
+}
+
+/// Escapes text for use in HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders every token of a line as an HTML `span` with the token ID as its tooltip.
+///
+/// # Arguments
+///
+/// * `line` - The input `String` to render.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the vocabulary.
+///
+/// # Returns
+///
+/// A `div` holding one `span` per token, to be wrapped by `report`, or an error if a
+/// token cannot be decoded.
+pub (crate) fn html(line: String, args: &Arguments) -> Result<String, String> {
+    let ids = encode(line.as_bytes(), &args.vocabulary).concat();
+    let spans: String = ids
+        .iter()
+        .zip(pieces(&ids, &args.vocabulary)?)
+        .enumerate()
+        .map(|(index, (id, piece))| {
+            let (text, fallback) = visible(&piece);
+            let class = if fallback { "fallback" } else if index % 2 == 0 { "even" } else { "odd" };
+            format!("<span class=\"{class}\" title=\"{id}\">{}</span>", escape(&text))
+        })
+        .collect();
+    Ok(format!("<div class=\"line\">{spans}</div>"))
+}
+
+/// Wraps the lines rendered by `html` into a standalone HTML document.
+pub (crate) fn report(lines: &[String], args: &Arguments) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>gpt3bpe {}</title>\n<style>\n\
+         .line {{ font-family: monospace; white-space: pre; margin: 0.25em 0; }}\n\
+         .even {{ background: #cfe2ff; }}\n\
+         .odd {{ background: #f8d7da; }}\n\
+         .fallback {{ background: #dc3545; color: #fff; }}\n\
+         </style>\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        args.vocabulary,
+        lines.join("\n")
+    )
+}
+
+/// Builds the record of a line for the `json` and `ndjson` formats.
+///
+/// Every record holds the `input` line and, in the modes that use one, the
+/// `vocabulary`. The modes that produce tokens add their `ids` and `count` and, where
+/// the bytes of every token are known, the byte `offsets` at which the tokens start.
+///
+/// # Arguments
+///
+/// * `line` - The input line.
+/// * `args` - A reference to the parsed `Arguments`, used to determine the mode and the vocabulary.
+/// * `rng` - The random number generator of BPE-dropout.
+///
+/// # Returns
+///
+/// The record, or an error if the line cannot be decoded or the mode reads all of
+/// stdin at once.
+pub (crate) fn record(
+    line: String,
+    args: &Arguments,
+    rng: &mut rand::rngs::StdRng,
+) -> Result<serde_json::Value, String> {
+    let vocabulary = args.vocabulary.to_string();
+    let record = match &args.command {
+        Some(Command::Grapheme(_)) => {
+            let graphemes: Vec<String> = bpe::grapheme(line.trim().as_bytes())
+                .iter()
+                .map(|g| String::from_utf8_lossy(g).into_owned())
                 .collect();
-            bpe::decode(&tokens, &bpe::vocabulary::O200K_UNICODES)
+            serde_json::json!({ "input": line, "graphemes": graphemes, "count": graphemes.len() })
+        }
+        Some(Command::Compare(_)) => {
+            serde_json::json!({ "input": line, "vocabularies": comparisons(&line)? })
+        }
+        Some(Command::Explain(_)) => {
+            let traces = traces(&line, &args.vocabulary);
+            let ids: Vec<u32> = traces.iter().flat_map(|trace| trace.tokens.iter().copied()).collect();
+            serde_json::json!({
+                "input": line,
+                "vocabulary": vocabulary,
+                "ids": ids,
+                "count": ids.len(),
+                "traces": traces,
+            })
+        }
+        Some(Command::Truncate(command)) => {
+            let truncation = bpe::truncation::truncate(&line, command.max_tokens, command.side, &args.vocabulary);
+            serde_json::json!({
+                "input": line,
+                "vocabulary": vocabulary,
+                "ids": truncation.ids,
+                "count": truncation.ids.len(),
+                "text": truncation.text,
+                "removed": truncation.removed,
+            })
+        }
+        Some(Command::Prefix(_)) => {
+            let mut record = matches(&line, args);
+            record["vocabulary"] = vocabulary.into();
+            record
+        }
+        Some(Command::Show(_)) => {
+            let ids = encode(line.as_bytes(), &args.vocabulary).concat();
+            let pieces = pieces(&ids, &args.vocabulary)?;
+            let tokens: Vec<String> = pieces.iter().map(|piece| visible(piece).0).collect();
+            serde_json::json!({
+                "input": line,
+                "vocabulary": vocabulary,
+                "ids": ids,
+                "count": ids.len(),
+                "offsets": offsets(&pieces),
+                "tokens": tokens,
+            })
+        }
+        Some(Command::Embed(_)) => {
+            #[cfg(all(feature = "embeddings", feature = "neural"))]
+            return Ok(serde_json::json!({
+                "input": line,
+                "vocabulary": vocabulary,
+                "embedding": embed::<{ embeddings::DIMENSIONS }, 75, { embeddings::PADDING }>(line.clone(), args),
+            }));
+            #[cfg(not(all(feature = "embeddings", feature = "neural")))]
+            return Err("`embed` command can only be used if the `embeddings` and `neural` feature is enabled.".to_string());
+        }
+        Some(_) => return Err("This command reads all of stdin at once and has no records per line.".to_string()),
+        None if args.decode => {
+            let (ids, bytes) = decode_ids(line.clone(), args)?;
+            serde_json::json!({
+                "input": line,
+                "vocabulary": vocabulary,
+                "ids": ids,
+                "count": ids.len(),
+                "text": String::from_utf8_lossy(&bytes),
+            })
+        }
+        None => {
+            let ids = match args.dropout {
+                Some(p) => encode_dropout(line.as_bytes(), &args.vocabulary, p, rng),
+                None => encode(line.as_bytes(), &args.vocabulary),
+            }
+            .concat();
+            let offsets = offsets(&pieces(&ids, &args.vocabulary)?);
+            serde_json::json!({
+                "input": line,
+                "vocabulary": vocabulary,
+                "ids": ids,
+                "count": ids.len(),
+                "offsets": offsets,
+            })
         }
+    };
+    Ok(record)
+}
+
+/// Writes the records of the `json` and `ndjson` formats.
+///
+/// `ndjson` writes every record on its own line as soon as it is pushed, so that a
+/// pipeline can consume it while input is still being read; `json` collects the
+/// records and writes them as one array once finished.
+#[derive(Debug)]
+pub(crate) struct Records {
+    /// The output format.
+    format: Format,
+    /// The records collected for the `json` format.
+    records: Vec<serde_json::Value>,
+}
+
+impl Records {
+    /// Creates an empty set of records for the given format.
+    pub(crate) fn new(format: Format) -> Self {
+        Records { format, records: vec![] }
     }
+
+    /// Writes a record, or collects it for the `json` format.
+    pub(crate) fn push<W: std::io::Write>(&mut self, record: serde_json::Value, writer: &mut W) -> std::io::Result<()> {
+        match self.format {
+            Format::Ndjson => writeln!(writer, "{record}"),
+            _ => {
+                self.records.push(record);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the collected records as one array for the `json` format.
+    pub(crate) fn finish<W: std::io::Write>(self, writer: &mut W) -> std::io::Result<()> {
+        match self.format {
+            Format::Json => writeln!(writer, "{}", serde_json::Value::Array(self.records)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the result of a mode that reads all of its input at once, such as `stats` or
+/// `chat`.
+///
+/// The result is written as is in the `text` format. In the `json` and `ndjson` formats
+/// it must be a JSON object, which becomes the only record.
+pub(crate) fn single<W: std::io::Write>(result: &str, format: Format, writer: &mut W) -> std::io::Result<()> {
+    if format == Format::Text {
+        return writeln!(writer, "{}", result.trim_end());
+    };
+    let record = serde_json::from_str(result).expect("[ERROR]: Records must be valid JSON.");
+    let mut records = Records::new(format);
+    records.push(record, writer)?;
+    records.finish(writer)
 }
 
+#[cfg_attr(
+    not(all(feature = "embeddings", feature = "neural")),
+    allow(dead_code, unused_mut, unused_variables)
+)]
 pub (crate) fn embed<const DIMENSIONS: usize, const TOKENS: usize, const PADDING: usize>(line: String, args: &Arguments) -> Vec<f32> {
     if line.trim().is_empty() {
         return vec![]
     };
     // The default operation is encoding.
-    let sequence = match args.vocabulary {
-        bpe::vocabulary::Vocabularies::R50K => {
-            bpe::encode(line.as_bytes(), &bpe::vocabulary::R50K_TOKENS)
-        },
-        bpe::vocabulary::Vocabularies::P50K => {
-            bpe::encode(line.as_bytes(), &bpe::vocabulary::P50K_TOKENS)
-        },
-        bpe::vocabulary::Vocabularies::CL100K => {
-            bpe::encode(line.as_bytes(), &bpe::vocabulary::CL100K_TOKENS)
-        },
-        bpe::vocabulary::Vocabularies::O200K => {
-            bpe::encode(line.as_bytes(), &bpe::vocabulary::O200K_TOKENS)
-        }
-    };
+    let sequence = args.vocabulary.encode(line.as_bytes());
     let pos: usize = 0;
     let mut context = Vec::with_capacity(PADDING);
     let mut result = vec![];
@@ -179,7 +1437,7 @@ pub (crate) fn embed<const DIMENSIONS: usize, const TOKENS: usize, const PADDING
             .join(" ");
 
         #[cfg(feature = "embeddings")]
-        if let Ok(rows) = embeddings::search::<DIMENSIONS,PADDING>(&embeddings::connection(None), &decode(word, args), PADDING.try_into().unwrap()) {
+        if let Ok(rows) = embeddings::search::<DIMENSIONS,PADDING>(&embeddings::connection(None), &decode(word, args).unwrap_or_default(), PADDING.try_into().unwrap()) {
             if rows.is_empty() {
                 continue;
             }
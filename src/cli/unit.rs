@@ -3,6 +3,12 @@
 //! These tests verify the correctness of the command-line argument parsing
 //! and the core logic for encoding, decoding, and grapheme splitting.
 
+/// Parses `arguments` as the command line of `gpt3bpe`, so that the tests of every mode
+/// start from the same defaults as the binary.
+#[cfg(test)]
+pub(crate) fn create_args(arguments: &[&str]) -> crate::cli::Arguments {
+    <crate::cli::Arguments as argh::FromArgs>::from_args(&["gpt3bpe"], arguments).unwrap()
+}
 
 #[cfg(test)]
 pub(crate) mod grapheme {
@@ -49,19 +55,14 @@ pub(crate) mod decoder {
     ///
     /// An `Arguments` struct configured for decoding with the specified vocabulary.
     fn create_decode_args(vocab: crate::bpe::vocabulary::Vocabularies) -> crate::cli::Arguments {
-        crate::cli::Arguments  {
-            encode: false,
-            decode: true,
-            vocabulary: vocab,
-            command: None,
-        }
+        super::create_args(&["-d", "-v", &vocab.to_string()])
     }
 
     #[test]
     pub (crate) fn test_decode_p50k() {
         // Test decoding "Hello, world!" with the p50k vocabulary
         let args = create_decode_args(crate::bpe::vocabulary::Vocabularies::P50K);
-        let buffer = crate::cli::decode("15496 11 995".to_string(), &args);
+        let buffer = crate::cli::decode("15496 11 995".to_string(), &args).unwrap();
         assert_eq!(
             String::from_utf8(buffer).unwrap(),
             "Hello, world"
@@ -72,7 +73,7 @@ pub(crate) mod decoder {
     pub (crate) fn test_decode_cl100k() {
         // Test decoding "Hello, world!" with the cl100k vocabulary
         let args = create_decode_args(crate::bpe::vocabulary::Vocabularies::CL100K);
-        let buffer = crate::cli::decode("9906 11 1917".to_string(), &args);
+        let buffer = crate::cli::decode("9906 11 1917".to_string(), &args).unwrap();
         assert_eq!(
             String::from_utf8(buffer).unwrap(),
             "Hello, world"
@@ -83,24 +84,56 @@ pub(crate) mod decoder {
     pub (crate) fn test_decode_empty_input() {
         // Test that decoding an empty string results in just a newline
         let args = create_decode_args(crate::bpe::vocabulary::Vocabularies::P50K);
-        let buffer = crate::cli::decode("".to_string(), &args);
+        let buffer = crate::cli::decode("".to_string(), &args).unwrap();
         assert_eq!(String::from_utf8(buffer).unwrap(), "");
     }
     #[test]
     pub (crate) fn test_decode_input_with_whitespace() {
         // Test that decoding a string with leading/trailing whitespace works correctly
         let args = create_decode_args(crate::bpe::vocabulary::Vocabularies::P50K);
-        let buffer = crate::cli::decode("  15496 11 995  ".to_string(), &args);
+        let buffer = crate::cli::decode("  15496 11 995  ".to_string(), &args).unwrap();
         assert_eq!(
             String::from_utf8(buffer).unwrap(),
             "Hello, world"
         );
     }
+    #[test]
+    pub (crate) fn test_decode_unknown_token_policies() {
+        let mut args = create_decode_args(crate::bpe::vocabulary::Vocabularies::P50K);
+        assert_eq!(
+            crate::cli::decode("15496 60000 11".to_string(), &args),
+            Err("Token ID 60000 not found.".to_string())
+        );
+        assert_eq!(
+            crate::cli::decode("15496 70000 11".to_string(), &args),
+            Err("Token ID 70000 not found.".to_string())
+        );
+        args.unknown = crate::bpe::Policy::Skip;
+        let buffer = crate::cli::decode("15496 60000 70000 11".to_string(), &args).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "Hello,");
+        args.unknown = crate::bpe::Policy::Replace;
+        let buffer = crate::cli::decode("15496 60000 70000 11".to_string(), &args).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "Hello\u{FFFD}\u{FFFD},");
+    }
+
+    #[test]
+    pub (crate) fn test_decode_unparsable_token_policies() {
+        let mut args = create_decode_args(crate::bpe::vocabulary::Vocabularies::CL100K);
+        args.unparsable = crate::bpe::Policy::Error;
+        assert_eq!(
+            crate::cli::decode("9906 hello 11".to_string(), &args),
+            Err("Could not parse token \"hello\".".to_string())
+        );
+        args.unparsable = crate::bpe::Policy::Replace;
+        let buffer = crate::cli::decode("9906 hello 11".to_string(), &args).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "Hello\u{FFFD},");
+    }
+
     #[test]
     pub (crate) fn test_decode_invalid_token_is_ignored() {
         // Test that non-numeric tokens are gracefully ignored instead of panicking
         let args = create_decode_args(crate::bpe::vocabulary::Vocabularies::P50K);
-        let buffer = crate::cli::decode("15496 not_a_token 11 995".to_string(), &args);
+        let buffer = crate::cli::decode("15496 not_a_token 11 995".to_string(), &args).unwrap();
         assert_eq!(
             String::from_utf8(buffer).unwrap(),
             "Hello, world"
@@ -125,5 +158,533 @@ pub(crate) mod arguments {
         assert!(!args.decode);
         assert!(!args.encode); // The default operation is encode, but the switch is false unless specified.
         assert_eq!(args.vocabulary, crate::bpe::vocabulary::Vocabularies::P50K);
+        assert_eq!(args.unknown, crate::bpe::Policy::Error);
+        assert_eq!(args.unparsable, crate::bpe::Policy::Skip);
+        assert_eq!(args.dropout, None);
+        assert_eq!(args.seed, 0);
+
+        // Verify decoding policies are parsed
+        let args: crate::cli::Arguments = crate::cli::Arguments::from_args(
+            &["gpt3bpe"],
+            &["-d", "--unknown", "replace", "--unparsable", "error"],
+        )
+        .unwrap();
+        assert_eq!(args.unknown, crate::bpe::Policy::Replace);
+        assert_eq!(args.unparsable, crate::bpe::Policy::Error);
+
+        // Verify BPE-dropout options are parsed
+        let args: crate::cli::Arguments = crate::cli::Arguments::from_args(
+            &["gpt3bpe"],
+            &["--dropout", "0.1", "--seed", "42"],
+        )
+        .unwrap();
+        assert_eq!(args.dropout, Some(0.1));
+        assert_eq!(args.seed, 42);
+    }
+}
+#[cfg(test)]
+pub(crate) mod compare {
+    #[test]
+    pub (crate) fn test_compare_json() {
+        let command = crate::cli::CompareCommand { json: true };
+        let output = crate::cli::compare("Hello, world".to_string(), &command).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["input"], "Hello, world");
+        let p50k = value["vocabularies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|v| v["vocabulary"] == "P50K")
+            .unwrap();
+        assert_eq!(p50k["ids"], serde_json::json!([15496, 11, 995]));
+        assert_eq!(p50k["count"], 3);
+        assert_eq!(p50k["ratio"], 4.0);
+        assert_eq!(p50k["offsets"], serde_json::json!([0, 5, 6]));
+    }
+
+    #[test]
+    pub (crate) fn test_compare_table() {
+        let command = crate::cli::CompareCommand { json: false };
+        let output = crate::cli::compare("Hello, world".to_string(), &command).unwrap();
+        let mut lines = output.lines();
+        assert!(lines.next().unwrap().ends_with("Hello, world"));
+        let p50k = lines.find(|l| l.starts_with("P50K")).unwrap();
+        assert!(p50k.ends_with("4.00  ^    ^^"));
+    }
+
+    #[test]
+    pub (crate) fn test_compare_skips_missing_vocabularies() {
+        let command = crate::cli::CompareCommand { json: true };
+        let output = crate::cli::compare("hello".to_string(), &command).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let count = crate::bpe::vocabulary::Vocabularies::iter()
+            .filter(|v| v.available())
+            .count();
+        assert_eq!(value["vocabularies"].as_array().unwrap().len(), count);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod show {
+    #[test]
+    pub (crate) fn test_visible_whitespace() {
+        assert_eq!(crate::cli::visible(b" world"), ("·world".to_string(), false));
+        assert_eq!(crate::cli::visible(b"\n\t"), ("↵→".to_string(), false));
+        assert_eq!(crate::cli::visible(b"\x07"), ("\\u{7}".to_string(), false));
+    }
+
+    #[test]
+    pub (crate) fn test_visible_byte_fallback() {
+        assert_eq!(crate::cli::visible(b"\xF0\x9F"), ("<F0><9F>".to_string(), true));
+    }
+
+    #[test]
+    pub (crate) fn test_show_alternates_colours() {
+        let output = crate::cli::show("Hello, world".to_string(), &super::create_args(&["-v", "p50k"])).unwrap();
+        assert_eq!(
+            output,
+            "\x1b[44;97mHello\x1b[0m\x1b[45;97m,\x1b[0m\x1b[44;97m·world\x1b[0m"
+        );
+    }
+
+    #[test]
+    pub (crate) fn test_pieces_unknown_id() {
+        let vocabulary = crate::bpe::vocabulary::Vocabularies::P50K;
+        assert_eq!(crate::cli::pieces(&[31373, 995], &vocabulary).unwrap(), vec![b"hello".to_vec(), b" world".to_vec()]);
+        assert!(crate::cli::pieces(&[31373, 70000], &vocabulary).is_err());
+        assert!(crate::cli::pieces(&[60000], &vocabulary).is_err());
+    }
+
+    #[test]
+    pub (crate) fn test_html_tooltips() {
+        let args = super::create_args(&["-v", "p50k"]);
+        let output = crate::cli::html("a<b".to_string(), &args).unwrap();
+        assert_eq!(
+            output,
+            "<div class=\"line\"><span class=\"even\" title=\"64\">a</span><span class=\"odd\" title=\"27\">&lt;</span><span class=\"even\" title=\"65\">b</span></div>"
+        );
+        let report = crate::cli::report(&[output], &args);
+        assert!(report.starts_with("<!DOCTYPE html>"));
+        assert!(report.contains("title=\"27\""));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod explain {
+    #[test]
+    pub (crate) fn test_explain_text() {
+        let command = crate::cli::ExplainCommand { json: false };
+        let output = crate::cli::explain("indivisible values".to_string(), &command, &super::create_args(&["-v", "p50k"]));
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "\"indivisible\" i n d i v i s i b l e");
+        assert_eq!(lines[1], "    1. rank    259  i + n -> [259 67 72 85 72 82 72 65 75 68]");
+        assert_eq!(lines[9], "  => [521 452 12843]");
+        assert_eq!(&lines[10..], &["\"Ġvalues\" Ġ v a l u e s", "  whole piece found in vocabulary", "  => [3815]"]);
+    }
+
+    #[test]
+    pub (crate) fn test_explain_json() {
+        let command = crate::cli::ExplainCommand { json: true };
+        let output = crate::cli::explain("indivisible".to_string(), &command, &super::create_args(&["-v", "p50k"]));
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value[0]["merges"][0]["left"], "i");
+        assert_eq!(value[0]["merges"][0]["rank"], 259);
+        assert_eq!(value[0]["tokens"], serde_json::json!([521, 452, 12843]));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod verify {
+    #[test]
+    pub (crate) fn test_diff() {
+        assert_eq!(crate::cli::diff(&[1, 2, 3], &[1, 2, 3]), "1 2 3");
+        assert_eq!(crate::cli::diff(&[1, 2, 3], &[1, 4, 5, 3]), "1 -2 +4 +5 3");
+        assert_eq!(crate::cli::diff(&[], &[7]), "+7");
+        assert_eq!(crate::cli::diff(&[7], &[]), "-7");
+    }
+
+    #[test]
+    pub (crate) fn test_verify_reference_fixtures() {
+        let verification = crate::cli::verify("src/bpe/fixtures/reference.jsonl").unwrap();
+        assert!(verification.mismatches.is_empty(), "{:?}", verification.mismatches);
+        assert!(verification.known.is_empty(), "{:?}", verification.known);
+        let fixtures = std::fs::read_to_string("src/bpe/fixtures/reference.jsonl").unwrap();
+        let count = fixtures.lines().filter(|line| line.starts_with('{')).count();
+        assert_eq!(verification.passed + verification.skipped, count);
+    }
+
+    #[test]
+    pub (crate) fn test_verify_mismatch_and_skip() {
+        let path = std::env::temp_dir().join("gpt3bpe_verify_fixtures.jsonl");
+        std::fs::write(
+            &path,
+            "{\"vocabulary\": \"p50k\", \"text\": \"hello world\", \"ids\": [31373, 996]}\n\
+             {\"vocabulary\": \"r50k\", \"text\": \"hello world\", \"ids\": [31373, 995]}\n",
+        )
+        .unwrap();
+        let verification = crate::cli::verify(path.to_str().unwrap()).unwrap();
+        assert_eq!(verification.passed, 1);
+        assert_eq!(verification.mismatches.len(), 1);
+        assert_eq!(verification.mismatches[0].0, 1);
+        assert!(verification.mismatches[0].1.ends_with("diff:     31373 -996 +995"));
+    }
+
+    #[test]
+    pub (crate) fn test_verify_known_failures() {
+        let path = std::env::temp_dir().join("gpt3bpe_verify_known.jsonl");
+        std::fs::write(
+            &path,
+            "{\"vocabulary\": \"p50k\", \"text\": \"hello world\", \"ids\": [31373, 996], \"xfail\": true}\n\
+             {\"vocabulary\": \"p50k\", \"text\": \"hello world\", \"ids\": [31373, 995], \"xfail\": true}\n",
+        )
+        .unwrap();
+        let verification = crate::cli::verify(path.to_str().unwrap()).unwrap();
+        assert_eq!(verification.passed, 0);
+        assert_eq!(verification.known.len(), 1);
+        assert!(verification.known[0].1.ends_with("diff:     31373 -996 +995"));
+        // A known failure that passes is a mismatch until its marker is removed.
+        assert_eq!(verification.mismatches.len(), 1);
+        assert_eq!(verification.mismatches[0].0, 2);
+        assert!(verification.mismatches[0].1.ends_with("please remove its xfail marker"));
+    }
+
+    #[test]
+    pub (crate) fn test_verify_invalid_fixture() {
+        let path = std::env::temp_dir().join("gpt3bpe_verify_invalid.jsonl");
+        std::fs::write(&path, "{\"vocabulary\": \"x50k\", \"text\": \"\", \"ids\": []}\n").unwrap();
+        let error = crate::cli::verify(path.to_str().unwrap()).unwrap_err();
+        assert!(error.contains(":1: unknown vocabulary: x50k"));
+        assert!(crate::cli::verify("does/not/exist.jsonl").is_err());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod stats {
+    #[test]
+    pub (crate) fn test_stats_json() {
+        let corpus = std::io::Cursor::new("Hello, world\n\nhello world\n");
+        let command = crate::cli::StatsCommand { csv: false };
+        let output = crate::cli::stats(corpus, &command, &super::create_args(&["-v", "cl100k"])).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["documents"], 2);
+        assert_eq!(value["tokens"], 5);
+        assert_eq!(value["frequencies"][0]["id"], 1917);
+        assert_eq!(value["frequencies"][0]["count"], 2);
+    }
+
+    #[test]
+    pub (crate) fn test_stats_csv() {
+        let corpus = std::io::Cursor::new("hello world\n");
+        let command = crate::cli::StatsCommand { csv: true };
+        let output = crate::cli::stats(corpus, &command, &super::create_args(&["-v", "cl100k"])).unwrap();
+        assert!(output.ends_with("id,count,bytes,text\n1917,1,6,\" world\"\n15339,1,5,\"hello\"\n"));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod chat {
+    #[test]
+    pub (crate) fn test_chat_json() {
+        let input = r#"[{"role": "user", "content": "Hello, world!"}]"#;
+        let command = crate::cli::ChatCommand { template: crate::bpe::chat::Template::ChatML };
+        let output = crate::cli::chat(input, &command, &super::create_args(&["-v", "cl100k"])).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["total"], 12);
+        assert_eq!(value["messages"][0]["overhead"], 5);
+        assert_eq!(value["messages"][0]["name"], serde_json::Value::Null);
+    }
+
+    #[test]
+    pub (crate) fn test_chat_invalid() {
+        let command = crate::cli::ChatCommand { template: crate::bpe::chat::Template::ChatML };
+        let error = crate::cli::chat(r#"{"role": "user"}"#, &command, &super::create_args(&["-v", "cl100k"])).unwrap_err();
+        assert!(error.starts_with("Could not parse messages:"));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod pack {
+    #[test]
+    pub (crate) fn test_pack_json() {
+        let input = r#"[{"text": "hello world", "priority": 1}, {"text": "goodbye world"}]"#;
+        let command = crate::cli::PackCommand { budget: 3 };
+        let output = crate::cli::pack(input, &command, &super::create_args(&["-v", "cl100k"])).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["text"], "hello worldgood");
+        assert_eq!(value["tokens"], 3);
+        assert_eq!(value["segments"][1]["outcome"], "truncated");
+    }
+
+    #[test]
+    pub (crate) fn test_pack_invalid() {
+        let command = crate::cli::PackCommand { budget: 3 };
+        let error = crate::cli::pack(r#"[{"priority": 1}]"#, &command, &super::create_args(&["-v", "cl100k"])).unwrap_err();
+        assert!(error.starts_with("Could not parse segments:"));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod truncate {
+    #[test]
+    pub (crate) fn test_truncate_text() {
+        let command = crate::cli::TruncateCommand {
+            max_tokens: 4,
+            side: crate::bpe::truncation::Side::Head,
+            json: false,
+        };
+        let output = crate::cli::truncate("one two three four five".to_string(), &command, &super::create_args(&["-v", "cl100k"]));
+        assert_eq!(output, "...ree four five");
+    }
+
+    #[test]
+    pub (crate) fn test_truncate_json() {
+        let command = crate::cli::TruncateCommand {
+            max_tokens: 4,
+            side: crate::bpe::truncation::Side::Tail,
+            json: true,
+        };
+        let output = crate::cli::truncate("one two three four five".to_string(), &command, &super::create_args(&["-v", "cl100k"]));
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["text"], "one two three ...");
+        assert_eq!(value["removed"]["start"], 14);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod prefix {
+    #[test]
+    pub (crate) fn test_prefix_ids() {
+        let command = crate::cli::PrefixCommand { json: false };
+        let output = crate::cli::prefix(" hello".to_string(), &command, &super::create_args(&["-v", "cl100k"]));
+        let ids: Vec<&str> = output.split(' ').collect();
+        assert!(ids.contains(&"220") && ids.contains(&"24748"));
+    }
+
+    #[test]
+    pub (crate) fn test_prefix_json() {
+        let command = crate::cli::PrefixCommand { json: true };
+        let output = crate::cli::prefix(" hel".to_string(), &command, &super::create_args(&["-v", "cl100k"]));
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["exact"], 11591);
+        assert_eq!(value["longest"]["id"], 11591);
+        assert_eq!(value["compatible"][0]["id"], 220);
+        assert_eq!(value["compatible"][0]["text"], " ");
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod format {
+    use argh::FromArgs;
+    use rand::SeedableRng;
+
+    #[test]
+    pub (crate) fn test_format_parsing() {
+        assert_eq!(super::create_args(&[]).format, crate::cli::Format::Text);
+        assert_eq!(super::create_args(&["--format", "NDJSON"]).format, crate::cli::Format::Ndjson);
+        assert!(crate::cli::Arguments::from_args(&["gpt3bpe"], &["--format", "yaml"]).is_err());
+    }
+
+    #[test]
+    pub (crate) fn test_record_encode() {
+        let args = super::create_args(&["--format", "json"]);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+        let record = crate::cli::record("let there be light.".to_string(), &args, &mut rng).unwrap();
+        assert_eq!(
+            record,
+            serde_json::json!({
+                "input": "let there be light.",
+                "vocabulary": "P50K",
+                "ids": [1616, 612, 307, 1657, 13],
+                "count": 5,
+                "offsets": [0, 3, 9, 12, 18],
+            })
+        );
+    }
+
+    #[test]
+    pub (crate) fn test_record_decode() {
+        let args = super::create_args(&["-d", "-v", "r50k", "--format", "ndjson"]);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+        let record = crate::cli::record("31373 995".to_string(), &args, &mut rng).unwrap();
+        assert_eq!(record["text"], "hello world");
+        assert_eq!(record["ids"], serde_json::json!([31373, 995]));
+        assert_eq!(record["count"], 2);
+
+        let args = super::create_args(&["--format", "json", "stats"]);
+        assert!(crate::cli::record("hello".to_string(), &args, &mut rng).is_err());
+    }
+
+    #[test]
+    pub (crate) fn test_record_decode_policies() {
+        let args = super::create_args(&["-d", "-v", "r50k", "--format", "ndjson"]);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+        assert!(crate::cli::record("31373 99999 995".to_string(), &args, &mut rng).is_err());
+        let record = crate::cli::record("31373 abc 995".to_string(), &args, &mut rng).unwrap();
+        assert_eq!(record["ids"], serde_json::json!([31373, 995]));
+
+        let args = super::create_args(&["-d", "-v", "r50k", "--format", "ndjson", "--unparsable", "error"]);
+        assert!(crate::cli::record("31373 abc 995".to_string(), &args, &mut rng).is_err());
+
+        let args = super::create_args(&["-d", "-v", "r50k", "--format", "ndjson", "--unknown", "skip", "--unparsable", "replace"]);
+        let record = crate::cli::record("31373 abc 99999 995".to_string(), &args, &mut rng).unwrap();
+        assert_eq!(record["text"], "hello\u{FFFD} world");
+        assert_eq!(record["ids"], serde_json::json!([31373, 995]));
+        assert_eq!(record["count"], 2);
+    }
+
+    #[test]
+    pub (crate) fn test_records() {
+        let mut output = vec![];
+        let mut records = crate::cli::Records::new(crate::cli::Format::Ndjson);
+        records.push(serde_json::json!({ "count": 1 }), &mut output).unwrap();
+        records.push(serde_json::json!({ "count": 2 }), &mut output).unwrap();
+        records.finish(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"count\":1}\n{\"count\":2}\n");
+
+        let mut output = vec![];
+        let mut records = crate::cli::Records::new(crate::cli::Format::Json);
+        records.push(serde_json::json!({ "count": 1 }), &mut output).unwrap();
+        assert!(output.is_empty());
+        records.finish(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "[{\"count\":1}]\n");
+    }
+
+    #[test]
+    pub (crate) fn test_single() {
+        let result = r#"{"tokens":5,"documents":1}"#;
+        let mut output = vec![];
+        crate::cli::single(result, crate::cli::Format::Text, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{result}\n"));
+        let mut output = vec![];
+        crate::cli::single(result, crate::cli::Format::Ndjson, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"documents\":1,\"tokens\":5}\n");
+        let mut output = vec![];
+        crate::cli::single(result, crate::cli::Format::Json, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "[{\"documents\":1,\"tokens\":5}]\n");
+
+        let corpus = std::io::Cursor::new("hello\n");
+        let command = crate::cli::StatsCommand { csv: true };
+        let args = super::create_args(&["--format", "ndjson"]);
+        assert!(crate::cli::stats(corpus, &command, &args).unwrap_err().contains("--csv"));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod input {
+    use std::io::Write;
+
+    fn documents(text: &'static str, args: &crate::cli::Arguments) -> Vec<String> {
+        let reader: Box<dyn std::io::BufRead> = Box::new(std::io::Cursor::new(text));
+        crate::cli::documents(reader, args).map(|document| document.unwrap()).collect()
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub (crate) fn test_documents() {
+        let args = super::create_args(&[]);
+        assert_eq!(documents("hello\nworld\n", &args), ["hello", "world"]);
+
+        let args = super::create_args(&["--whole"]);
+        assert_eq!(documents("hello\nworld\n", &args), ["hello\nworld\n"]);
+        assert_eq!(documents("", &args), [""]);
+    }
+
+    #[test]
+    pub (crate) fn test_documents_invalid_utf8() {
+        let read = |args: &crate::cli::Arguments| {
+            let reader: Box<dyn std::io::BufRead> = Box::new(std::io::Cursor::new(b"hello\n\xFF\xFE\n".to_vec()));
+            crate::cli::documents(reader, args).map(|d| d.map_err(|e| e.to_string())).collect::<Vec<_>>()
+        };
+        let documents = read(&super::create_args(&[]));
+        assert_eq!(documents[0], Ok("hello".to_string()));
+        assert_eq!(documents[1], Err("Line 2 is not valid UTF-8".to_string()));
+        assert_eq!(read(&super::create_args(&["--whole"])), [Err("The input is not valid UTF-8".to_string())]);
+    }
+
+    #[test]
+    pub (crate) fn test_input_files() {
+        let directory = std::env::temp_dir().join(format!("gpt3bpe-input-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let first = directory.join("first.txt");
+        let second = directory.join("second.txt");
+        std::fs::write(&first, "hello\n").unwrap();
+        std::fs::write(&second, "world\n").unwrap();
+
+        let args = super::create_args(&[
+            "--whole",
+            "--input",
+            first.to_str().unwrap(),
+            "--input",
+            second.to_str().unwrap(),
+        ]);
+        let reader = crate::cli::input(&args).unwrap();
+        let documents: Vec<String> = crate::cli::documents(reader, &args).map(|d| d.unwrap()).collect();
+        assert_eq!(documents, ["hello\nworld\n"]);
+
+        std::fs::write(&first, "a").unwrap();
+        std::fs::write(&second, "b").unwrap();
+        let args = super::create_args(&["--input", first.to_str().unwrap(), "--input", second.to_str().unwrap()]);
+        let reader = crate::cli::input(&args).unwrap();
+        let documents: Vec<String> = crate::cli::documents(reader, &args).map(|d| d.unwrap()).collect();
+        assert_eq!(documents, ["a", "b"]);
+        let args = super::create_args(&["--whole", "--input", first.to_str().unwrap(), "--input", second.to_str().unwrap()]);
+        let reader = crate::cli::input(&args).unwrap();
+        let documents: Vec<String> = crate::cli::documents(reader, &args).map(|d| d.unwrap()).collect();
+        assert_eq!(documents, ["a\nb"]);
+
+        let missing = directory.join("missing.txt");
+        let args = super::create_args(&["--input", missing.to_str().unwrap()]);
+        assert!(crate::cli::input(&args).err().unwrap().starts_with("Could not open input"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub (crate) fn test_prepare_fields() {
+        let directory = std::env::temp_dir().join(format!("gpt3bpe-prepare-{}", std::process::id()));
+        let args = super::create_args(&["prepare", directory.to_str().unwrap(), "--validation", "0", "--field", "text"]);
+        let Some(crate::cli::Command::Prepare(ref command)) = args.command else { unreachable!() };
+        let input = "{\"text\": \"hello world\"}\n\n{\"text\": \"hello\", \"id\": 2}\n";
+        let reader: Box<dyn std::io::BufRead> = Box::new(std::io::Cursor::new(input));
+        let metadata: serde_json::Value = serde_json::from_str(&crate::cli::prepare(reader, command, &args).unwrap()).unwrap();
+        assert_eq!(metadata["train"]["documents"], 2);
+        assert_eq!(metadata["train"]["tokens"], 5);
+        assert_eq!(metadata["eot"], 50256);
+        assert_eq!(std::fs::read(directory.join("train.bin")).unwrap().len(), 10);
+
+        let reader: Box<dyn std::io::BufRead> = Box::new(std::io::Cursor::new("{\"body\": \"hello\"}\n"));
+        assert!(crate::cli::prepare(reader, command, &args).err().unwrap().contains("no string field text"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub (crate) fn test_sample_windows() {
+        let directory = std::env::temp_dir().join(format!("gpt3bpe-sample-{}", std::process::id()));
+        let args = super::create_args(&["prepare", directory.to_str().unwrap(), "--validation", "0"]);
+        let Some(crate::cli::Command::Prepare(ref command)) = args.command else { unreachable!() };
+        let reader: Box<dyn std::io::BufRead> = Box::new(std::io::Cursor::new("hello world\n"));
+        crate::cli::prepare(reader, command, &args).unwrap();
+
+        let args = super::create_args(&["--format", "json", "sample", directory.to_str().unwrap(), "--context", "2"]);
+        let Some(crate::cli::Command::Sample(ref command)) = args.command else { unreachable!() };
+        let sample: serde_json::Value = serde_json::from_str(&crate::cli::sample(command, &args).unwrap()).unwrap();
+        assert_eq!(sample["vocabulary"], "P50K");
+        assert_eq!(sample["contexts"][0], serde_json::json!([31373, 995]));
+        assert_eq!(sample["targets"][0], serde_json::json!([995, 50256]));
+        assert_eq!(sample["texts"][0], "hello world");
+
+        let args = super::create_args(&["sample", directory.to_str().unwrap(), "--context", "2", "--batch", "2"]);
+        let Some(crate::cli::Command::Sample(ref command)) = args.command else { unreachable!() };
+        assert_eq!(crate::cli::sample(command, &args).unwrap(), "hello world\nhello world");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    pub (crate) fn test_output_file() {
+        let path = std::env::temp_dir().join(format!("gpt3bpe-output-{}.txt", std::process::id()));
+        let args = super::create_args(&["--output", path.to_str().unwrap()]);
+        let mut output = crate::cli::output(&args).unwrap();
+        writeln!(output, "31373 995").unwrap();
+        drop(output);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "31373 995\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
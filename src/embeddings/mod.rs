@@ -17,9 +17,9 @@ use rusqlite::{ffi::sqlite3_auto_extension, Connection};
 use sqlite_vec::sqlite3_vec_init;
 use std::sync::Once;
 use zerocopy::AsBytes;
-pub(crate) const PADDING: usize = 3; // The fixed size for token padding.
-pub(crate) const TOKEN_LIMIT: usize = 5; // The fixed size for sequence padding.
-pub(crate) const DIMENSIONS: usize = 300;
+pub const PADDING: usize = 3; // The fixed size for token padding.
+pub const TOKEN_LIMIT: usize = 5; // The fixed size for sequence padding.
+pub const DIMENSIONS: usize = 300;
 
 /// Pads or truncates a vector of tokens to a fixed-size array.
 ///
@@ -39,7 +39,7 @@ pub(crate) const DIMENSIONS: usize = 300;
 /// # Generic Parameters
 ///
 /// * `P`: A `const` generic representing the desired size of the output array.
-pub(crate) fn padding<const P: usize>(input: &Vec<u32>) -> Result<[u32; P], &'static str> {
+pub fn padding<const P: usize>(input: &Vec<u32>) -> Result<[u32; P], &'static str> {
     let mut result = [0u32; P];
     if input.len() > P || input.is_empty() {
         return Err("Invalid token.");
@@ -63,23 +63,10 @@ pub(crate) fn padding<const P: usize>(input: &Vec<u32>) -> Result<[u32; P], &'st
 /// - The original text as a `String` label.
 /// - The resulting token vectors (`Vec<Vec<u32>>`).
 /// Returns `None` if no vocabulary can encode the slice into a valid, paddable sequence.
-pub (crate) fn encode(slice: &[u8]) -> Option<(&bpe::vocabulary::Vocabularies, String, Vec<Vec<u32>>)> {
+pub fn encode(slice: &[u8]) -> Option<(&bpe::vocabulary::Vocabularies, String, Vec<Vec<u32>>)> {
     let mut result = None;
     for vocab in bpe::vocabulary::Vocabularies::iter() {
-        let tokens = match vocab {
-            bpe::vocabulary::Vocabularies::R50K => {
-                bpe::encode(slice, &bpe::vocabulary::R50K_TOKENS)
-            }
-            bpe::vocabulary::Vocabularies::P50K => {
-                bpe::encode(slice, &bpe::vocabulary::P50K_TOKENS)
-            }
-            bpe::vocabulary::Vocabularies::CL100K => {
-                bpe::encode(slice, &bpe::vocabulary::CL100K_TOKENS)
-            }
-            bpe::vocabulary::Vocabularies::O200K => {
-                bpe::encode(slice, &bpe::vocabulary::O200K_TOKENS)
-            }
-        };
+        let tokens = vocab.encode(slice);
         let label = String::from_utf8(slice.to_vec()).expect("[ERROR]: Not a valid utf-8 string.");
         if let Err(_) = padding::<PADDING>(&tokens.concat()) {
             #[cfg(debug_assertions)]
@@ -114,7 +101,7 @@ static SQLITE_VEC_INIT: Once = Once::new();
 ///
 /// # Panics
 /// This function will panic if it fails to open the database or execute the schema.
-pub(crate) fn connection(location: Option<&str>) -> Connection {
+pub fn connection(location: Option<&str>) -> Connection {
     SQLITE_VEC_INIT.call_once(|| {
         // This should only be called once per process.
         // SAFETY: `sqlite3_vec_init` is a valid extension entry point.
@@ -166,7 +153,7 @@ pub(crate) fn connection(location: Option<&str>) -> Connection {
 ///
 /// # Panics
 /// Panics if `slice` is empty or the `vector` length does not match `D`.
-pub(crate) fn insert<const D: usize>(
+pub fn insert<const D: usize>(
     conn: &Connection,
     slice: &[u8],
     vector: &[f32; D],
@@ -192,10 +179,71 @@ pub(crate) fn insert<const D: usize>(
     Ok(())
 }
 
+/// Deletes a word label and its embedding vector from the database.
+///
+/// The `word_embeddings` view only deletes from `words` and the `embeddings` virtual
+/// table does not cascade, so both rows are deleted here in one transaction.
+///
+/// # Arguments
+///
+/// * `conn`: A reference to the `rusqlite::Connection`.
+/// * `slice`: The byte slice of the word to delete.
+///
+/// # Returns
+///
+/// A `Result` containing the number of words deleted, `0` or `1`, or a
+/// `rusqlite::Error` on failure.
+pub fn delete(conn: &Connection, slice: &[u8]) -> Result<usize, rusqlite::Error> {
+    let label = String::from_utf8_lossy(slice);
+    let transaction = conn.unchecked_transaction()?;
+    transaction.execute(
+        "DELETE FROM embeddings WHERE rid IN (SELECT rid FROM words WHERE label = ?)",
+        rusqlite::params![label],
+    )?;
+    let deleted = transaction.execute("DELETE FROM words WHERE label = ?", rusqlite::params![label])?;
+    transaction.commit()?;
+    Ok(deleted)
+}
+
+/// A database connection kept open across calls, handed to FFI callers as an opaque handle.
+///
+/// `connection(None)` opens the database named by `GPT3BPE_WORDS`, or a fresh in-memory
+/// one, and runs the schema on every call. A `Store` is opened once at an explicit
+/// location instead; the connection sits behind a `Mutex`, so one handle can be shared
+/// by many threads.
+#[derive(Debug)]
+pub struct Store {
+    /// The open connection.
+    connection: std::sync::Mutex<Connection>,
+}
+
+impl Store {
+    /// Opens a store at `location`, which may be `:memory:`, creating the schema if needed.
+    ///
+    /// # Panics
+    /// Panics if the database cannot be opened or the schema cannot be executed.
+    pub fn open(location: &str) -> Self {
+        Store { connection: std::sync::Mutex::new(connection(Some(location))) }
+    }
+
+    /// Opens the store named by `GPT3BPE_WORDS`, or an in-memory one if it is unset.
+    ///
+    /// # Panics
+    /// Panics if the database cannot be opened or the schema cannot be executed.
+    pub fn global() -> Self {
+        Store { connection: std::sync::Mutex::new(connection(None)) }
+    }
+
+    /// Locks the connection for the duration of one call.
+    pub fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
 /// Represents a row returned from a vector similarity search.
 #[derive(Debug, Clone)]
 #[repr(align(16))]
-pub(crate) struct Row<const D: usize> {
+pub struct Row<const D: usize> {
     /// The row ID from the database.
     pub rid: u16,
     /// The text label of the word.
@@ -222,7 +270,7 @@ pub(crate) struct Row<const D: usize> {
 ///
 /// # Returns
 /// A fixed-size array `[f32; D]` representing the positional encoding vector for the given position.
-pub(crate) fn position<const D: usize>(position: usize) -> [f32; D] {
+pub fn position<const D: usize>(position: usize) -> [f32; D] {
     let mut pe = [0.0; D];
     let position = position as f32;
     let inv_base = 1.0 / 10000.0_f32;
@@ -261,7 +309,7 @@ pub(crate) fn position<const D: usize>(position: usize) -> [f32; D] {
 ///
 /// # Panics
 /// Panics if `k` is zero, `slice` is empty, or `slice` is not a valid UTF-8 string.
-pub(crate) fn search<const D: usize, const L: usize>(
+pub fn search<const D: usize, const L: usize>(
     conn: &Connection,
     slice: &[u8],
     k: u8,
@@ -329,7 +377,7 @@ pub(crate) fn search<const D: usize, const L: usize>(
 ///
 /// # Panics
 /// Panics if `k` is zero or the `vector` length does not match `D`.
-pub(crate) fn nearest<const D: usize>(
+pub fn nearest<const D: usize>(
     conn: &Connection,
     vector: &[f32; D],
     k: u8,
@@ -30,7 +30,7 @@ pub(crate) mod padding {
 }
 
 #[cfg(test)]
-static VECTORS: std::sync::LazyLock<Vec<(&str, [f32; 300])>> = {
+pub(crate) static VECTORS: std::sync::LazyLock<Vec<(&str, [f32; 300])>> = {
     std::sync::LazyLock::new(|| {
         vec![
             (
@@ -332,4 +332,18 @@ pub(crate) mod nearest {
             assert_eq!(result[0].label, row.0);
         };
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod delete {
+    #[test]
+    pub(crate) fn test_delete() {
+        let conn = crate::embeddings::connection(None);
+        let row = crate::embeddings::unit::VECTORS[0];
+        crate::embeddings::insert(&conn, row.0.as_bytes(), &row.1).unwrap();
+        assert_eq!(crate::embeddings::delete(&conn, row.0.as_bytes()).unwrap(), 1);
+        assert_eq!(crate::embeddings::delete(&conn, row.0.as_bytes()).unwrap(), 0);
+        let result = crate::embeddings::nearest::<{ crate::embeddings::DIMENSIONS }>(&conn, &row.1, 10).unwrap();
+        assert!(result.is_empty());
+        crate::embeddings::insert(&conn, row.0.as_bytes(), &row.1).unwrap();
+    }
+}
@@ -4,98 +4,6 @@ mod flamegraph {
     #[test]
     #[ignore]
     fn generate_flamegraphs() {
-        {
-            let grapheme_guard = pprof::ProfilerGuardBuilder::default()
-                .frequency(100000)
-                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-                .build()
-                .unwrap();
-            crate::bpe::unit::grapheme::test_grapheme_ascii();
-            crate::bpe::unit::grapheme::test_grapheme_empty();
-            crate::bpe::unit::grapheme::test_grapheme_with_numbers();
-            crate::bpe::unit::grapheme::test_grapheme_special_chars();
-            crate::bpe::unit::grapheme::test_grapheme_unicode();
-            crate::bpe::unit::grapheme::test_grapheme_mixed();
-            crate::bpe::unit::grapheme::test_grapheme_repeated();
-            crate::bpe::unit::grapheme::test_grapheme_let_there_be_light();
-            crate::bpe::unit::grapheme::test_grapheme_indivisible_values();
-            crate::bpe::unit::grapheme::test_grapheme_pneumonoultramicroscopicsilicovolcanoconiosis();
-            if let Ok(report) = grapheme_guard.report().build() {
-                let file = std::fs::File::create("src/bpe/flamegraph/grapheme.svg").unwrap();
-                let mut options = pprof::flamegraph::Options::default();
-                options.image_width = Some(2500);
-                report.flamegraph_with_options(file, &mut options).unwrap();
-                println!("✅ Grapheme: flamegraph saved");
-            } else {
-                eprintln!("⚠️ Grapheme: Could not build report");
-            }
-        }
-
-        {
-            let tokens_guard = pprof::ProfilerGuardBuilder::default()
-                .frequency(10000)
-                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-                .build()
-                .unwrap();
-            crate::bpe::unit::tokens::test_tokens_contraction();
-            crate::bpe::unit::tokens::test_tokens_multiple_words();
-            crate::bpe::unit::tokens::test_tokens_unicode();
-            crate::bpe::unit::tokens::test_tokens_mixed();
-            crate::bpe::unit::tokens::test_tokens_static();
-            if let Ok(report) = tokens_guard.report().build() {
-                let file = std::fs::File::create("src/bpe/flamegraph/tokens.svg").unwrap();
-                let mut options = pprof::flamegraph::Options::default();
-                options.image_width = Some(2500);
-                report.flamegraph_with_options(file, &mut options).unwrap();
-                println!("✅ Tokens: flamegraph saved");
-            } else {
-                eprintln!("⚠️ Tokens: Could not build report");
-            }
-        }
-        {
-            let encoder_guard = pprof::ProfilerGuardBuilder::default()
-                .frequency(1000)
-                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-                .build()
-                .unwrap();
-            crate::bpe::unit::encoder::test_encode_empty();
-            crate::bpe::unit::encoder::test_encode_ascii();
-            crate::bpe::unit::encoder::test_encode_unicode();
-            crate::bpe::unit::encoder::test_encode_mixed();
-            crate::bpe::unit::encoder::test_encode_let_there_be_light();
-            crate::bpe::unit::encoder::test_encode_indivisible_values();
-            crate::bpe::unit::encoder::test_encode_pneumonoultramicroscopicsilicovolcanoconiosis();
-            if let Ok(report) = encoder_guard.report().build() {
-                let file = std::fs::File::create("src/bpe/flamegraph/encoder.svg").unwrap();
-                let mut options = pprof::flamegraph::Options::default();
-                options.image_width = Some(2500);
-                report.flamegraph_with_options(file, &mut options).unwrap();
-                println!("✅ Encoder: flamegraph saved");
-            } else {
-                eprintln!("⚠️ Encoder: Could not build report");
-            }
-        }
-        {
-            let decoder_guard = pprof::ProfilerGuardBuilder::default()
-                .frequency(100000)
-                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-                .build()
-                .unwrap();
-            // super::decoder::test_decode_mixed();
-            crate::bpe::unit::decoder::test_decode_let_there_be_light();
-            crate::bpe::unit::decoder::test_decode_indivisible_values();
-            crate::bpe::unit::decoder::test_decode_pneumonoultramicroscopicsilicovolcanoconiosis();
-            if let Ok(report) = decoder_guard.report().build() {
-                let file = std::fs::File::create("src/bpe/flamegraph/decoder.svg").unwrap();
-                let mut options = pprof::flamegraph::Options::default();
-                options.image_width = Some(2500);
-                report.flamegraph_with_options(file, &mut options).unwrap();
-                println!("✅ Decoder: flamegraph saved");
-            } else {
-                eprintln!("⚠️ Decoder: Could not build report");
-            }
-        }
-
         {
             let grapheme_guard = pprof::ProfilerGuardBuilder::default()
                 .frequency(100000)
@@ -155,86 +63,6 @@ mod flamegraph {
                 eprintln!("⚠️ Arguments: Could not build report");
             }
         }
-        #[cfg(feature = "embeddings")]
-        {
-            let padding_guard = pprof::ProfilerGuardBuilder::default()
-                .frequency(100000)
-                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-                .build()
-                .unwrap();
-            crate::embeddings::unit::padding::test_padding_empty();
-            crate::embeddings::unit::padding::test_padding_smaller();
-            crate::embeddings::unit::padding::test_padding_equal();
-            crate::embeddings::unit::padding::test_padding_larger();
-            if let Ok(report) = padding_guard.report().build() {
-                let file = std::fs::File::create("src/embeddings/flamegraph/padding.svg").unwrap();
-                let mut options = pprof::flamegraph::Options::default();
-                options.image_width = Some(2500);
-                report.flamegraph_with_options(file, &mut options).unwrap();
-                println!("✅ Padding: flamegraph saved");
-            } else {
-                eprintln!("⚠️ Padding: Could not build report");
-            }
-        }
-        // #[cfg(feature = "embeddings")]
-        // {
-        //     let insert_guard = pprof::ProfilerGuardBuilder::default()
-        //         .frequency(100000)
-        //         .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-        //         .build()
-        //         .unwrap();
-        //     crate::embeddings::unit::insert::test_insert_empty_string();
-        //     crate::embeddings::unit::insert::test_insert_constraint_violation();
-        //     if let Ok(report) = insert_guard.report().build() {
-        //         let file = std::fs::File::create("src/embeddings/flamegraph/insert.svg").unwrap();
-        //         let mut options = pprof::flamegraph::Options::default();
-        //         options.image_width = Some(2500);
-        //         report.flamegraph_with_options(file, &mut options).unwrap();
-        //         println!("✅ Insert: flamegraph saved");
-        //     } else {
-        //         eprintln!("⚠️ Insert: Could not build report");
-        //     }
-        // }
-
-        #[cfg(feature = "embeddings")]
-        {
-            let search_guard = pprof::ProfilerGuardBuilder::default()
-                .frequency(100000)
-                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-                .build()
-                .unwrap();
-            // crate::embeddings::unit::search::test_search_empty_string();
-            // crate::embeddings::unit::search::test_search_zero_k();
-            crate::embeddings::unit::search::test_search();
-            if let Ok(report) = search_guard.report().build() {
-                let file = std::fs::File::create("src/embeddings/flamegraph/search.svg").unwrap();
-                let mut options = pprof::flamegraph::Options::default();
-                options.image_width = Some(100000);
-                report.flamegraph_with_options(file, &mut options).unwrap();
-                println!("✅ Search: flamegraph saved");
-            } else {
-                eprintln!("⚠️ Search: Could not build report");
-            }
-        }
-        #[cfg(feature = "embeddings")]
-        {
-            let nearest_guard = pprof::ProfilerGuardBuilder::default()
-                .frequency(100000)
-                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
-                .build()
-                .unwrap();
-            // crate::embeddings::unit::nearest::test_nearest_zero_k();
-            crate::embeddings::unit::nearest::test_nearest();
-            if let Ok(report) = nearest_guard.report().build() {
-                let file = std::fs::File::create("src/embeddings/flamegraph/nearest.svg").unwrap();
-                let mut options = pprof::flamegraph::Options::default();
-                options.image_width = Some(100000);
-                report.flamegraph_with_options(file, &mut options).unwrap();
-                println!("✅ Nearest: flamegraph saved");
-            } else {
-                eprintln!("⚠️ Nearest: Could not build report");
-            }
-        }
         #[cfg(feature = "neural")]
         {
             let tensor_guard = pprof::ProfilerGuardBuilder::default()
@@ -278,6 +106,160 @@ mod flamegraph {
                 eprintln!("⚠️ Neural: Could not build report");
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod throughput {
+    use crate::bpe::vocabulary::Vocabularies;
+    use std::collections::BTreeMap;
+
+    /// The corpus encoded by the benchmark: this repository's README, line by line.
+    const CORPUS: &str = include_str!("../README.md");
+
+    /// Encodes `CORPUS` with `encode` for two seconds and prints its throughput.
+    fn measure(name: &str, encode: impl Fn(&[u8]) -> Vec<Vec<u32>>) {
+        // Load the vocabulary before timing.
+        encode(b"warm up");
+        let start = std::time::Instant::now();
+        let mut tokens = 0;
+        let mut rounds = 0;
+        while start.elapsed() < std::time::Duration::from_secs(2) {
+            for line in CORPUS.lines() {
+                tokens += encode(line.as_bytes()).concat().len();
+            }
+            rounds += 1;
+        }
+        let seconds = start.elapsed().as_secs_f64();
+        let bytes = (CORPUS.len() * rounds) as f64;
+        println!("{name}: {:.3} MB/s, {:.0} tokens/s", bytes / seconds / 1e6, tokens as f64 / seconds);
+    }
+
+    /// Measures the encoding throughput of every available vocabulary.
+    ///
+    /// Run with `cargo test --release throughput -- --ignored --nocapture --test-threads=1`.
+    #[test]
+    #[ignore]
+    fn encode_throughput() {
+        for vocabulary in Vocabularies::iter().filter(|v| v.available()) {
+            measure(&vocabulary.to_string(), |slice| vocabulary.encode(slice));
+        }
+    }
+
+    /// Measures the encoder the byte-level one replaced, on the same corpus.
+    ///
+    /// Run next to `encode_throughput` to compare the two on one machine.
+    #[test]
+    #[ignore]
+    fn encode_throughput_baseline() {
+        for vocabulary in Vocabularies::iter().filter(|v| v.available()) {
+            let lookup = baseline::lookup(vocabulary);
+            measure(&format!("{vocabulary} (baseline)"), |slice| {
+                baseline::encode(slice, vocabulary.pattern().as_str(), &lookup)
+            });
+        }
+    }
+
+    /// The encoder as it was before encoding worked on raw bytes.
+    ///
+    /// It compiles the pattern on every call, splits each piece into GPT-unicode
+    /// graphemes, copies the whole vocabulary into a new map for every piece that is not
+    /// a token, and concatenates graphemes for every lookup. The original also copied
+    /// the merge table into that map, so it was somewhat slower than this copy.
+    mod baseline {
+        use super::{BTreeMap, Vocabularies};
 
+        /// The vocabulary keyed by GPT-unicode text, as the encoder looked it up.
+        pub(super) fn lookup(vocabulary: &Vocabularies) -> BTreeMap<Vec<u8>, u32> {
+            use crate::bpe::vocabulary::*;
+            let text = |unicodes: &Vec<u16>| unicodes.iter().map(|&c| c as u8).collect::<Vec<u8>>();
+            match vocabulary {
+                Vocabularies::R50K => R50K_UNICODES.iter().map(|(id, u)| (text(u), u32::from(*id))).collect(),
+                Vocabularies::P50K => P50K_UNICODES.iter().map(|(id, u)| (text(u), u32::from(*id))).collect(),
+                Vocabularies::CL100K => CL100K_UNICODES.iter().map(|(id, u)| (text(u), *id)).collect(),
+                Vocabularies::O200K => O200K_UNICODES.iter().map(|(id, u)| (text(u), *id)).collect(),
+            }
+        }
+
+        struct BytePairEncoder {
+            grapheme: Vec<Vec<u8>>,
+            pairs: Vec<(usize, u32)>,
+            encoder: BTreeMap<Vec<u8>, u32>,
+        }
+
+        impl BytePairEncoder {
+            fn new(grapheme: Vec<Vec<u8>>, lookup: &BTreeMap<Vec<u8>, u32>) -> BytePairEncoder {
+                let mut encoder = BTreeMap::new();
+                for (key, value) in lookup.iter() {
+                    encoder.insert(key.to_vec(), *value);
+                }
+                let mut pairs: Vec<(usize, u32)> = (0..grapheme.len()).map(|i| (i, u32::MAX)).collect();
+                for i in 0..pairs.len() - 1 {
+                    if let Some(rank) = encoder.get(&grapheme[pairs[i].0..pairs[i + 1].0 + 1].concat()) {
+                        pairs[i].1 = *rank;
+                    }
+                }
+                BytePairEncoder { grapheme, pairs, encoder }
+            }
+
+            fn get_rank(&self, start_idx: usize, length: usize) -> Option<u32> {
+                if start_idx + length <= self.pairs.len() {
+                    let end = self.pairs[start_idx + length - 1].0 + 1;
+                    self.encoder.get(&self.grapheme[self.pairs[start_idx].0..end].concat()).copied()
+                } else {
+                    None
+                }
+            }
+        }
+
+        impl Iterator for BytePairEncoder {
+            type Item = Vec<u32>;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                if self.pairs.len() == 1 {
+                    return None;
+                }
+                let mut rank: (u32, usize) = (u32::MAX, 0);
+                for (idx, &(_, r)) in self.pairs[..self.pairs.len() - 1].iter().enumerate() {
+                    if r < rank.0 {
+                        rank = (r, idx);
+                    }
+                }
+                if rank.0 == u32::MAX {
+                    return None;
+                }
+                if rank.1 < self.pairs.len() - 2 {
+                    self.pairs[rank.1].1 = self.get_rank(rank.1, 3).unwrap_or(u32::MAX);
+                } else {
+                    self.pairs[rank.1].1 = u32::MAX;
+                }
+                if rank.1 > 0 {
+                    self.pairs[rank.1 - 1].1 = self.get_rank(rank.1 - 1, 2).unwrap_or(u32::MAX);
+                }
+                self.pairs.remove(rank.1 + 1);
+
+                let mut result = Vec::with_capacity(self.pairs.len());
+                for i in 0..self.pairs.len() {
+                    let start = self.pairs[i].0;
+                    let end = if i < self.pairs.len() - 1 { self.pairs[i + 1].0 } else { self.grapheme.len() };
+                    result.push(*self.encoder.get(&self.grapheme[start..end].concat())?);
+                }
+                Some(result)
+            }
+        }
+
+        pub(super) fn encode(slice: &[u8], pattern: &str, lookup: &BTreeMap<Vec<u8>, u32>) -> Vec<Vec<u32>> {
+            let mut result = vec![];
+            for piece in regex::bytes::Regex::new(pattern).unwrap().find_iter(slice) {
+                let graph = crate::bpe::grapheme(piece.as_bytes());
+                if let Some(token) = lookup.get(&graph.concat()) {
+                    result.push(vec![*token]);
+                    continue;
+                }
+                let merge = graph.iter().flat_map(|g| g.iter().map(|r| *r as u32)).collect();
+                result.push(BytePairEncoder::new(graph, lookup).last().unwrap_or(merge));
+            }
+            result
+        }
     }
 }
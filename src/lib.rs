@@ -23,7 +23,21 @@
 //! memory regions of the specified length. The library includes assertions to catch null
 //! pointers and potential overflows, but the ultimate responsibility for memory safety
 //! lies with the caller.
-mod bpe;
+//!
+//! ## Errors
+//!
+//! No export unwinds into the caller. Every export catches panics, such as a failed
+//! assertion on a null pointer or an unknown token ID, and reports the failure in its
+//! return value: functions without another result return a `Status`, `bool` functions
+//! return `false`, length-returning functions return `usize::MAX` and handle-returning
+//! functions return null. `gpt3bpe_last_error` then describes the failure.
+use std::cell::RefCell;
+use std::ffi::{c_char, CString};
+pub mod bpe;
+#[cfg(test)]
+mod flamegraph;
+mod tokenizer;
+pub(crate) mod unit;
 
 /// Safely reads data from a raw pointer into a static slice.
 ///
@@ -69,6 +83,108 @@ fn read<T>(pointer: *const T, length: usize) -> &'static [T] {
     slice
 }
 
+/// Safely borrows a caller-allocated output buffer as a static mutable slice.
+///
+/// This is the counterpart of `read` for buffers the library writes into. A null
+/// `pointer` is allowed if `length` is `0`, so callers can query the required length
+/// without allocating.
+///
+/// # Panics
+///
+/// This function will panic if the pointer is null while `length` is not `0`, if it is
+/// not properly aligned for type `T`, or if the requested length could lead to a buffer
+/// overflow.
+///
+/// # Safety
+///
+/// The caller must guarantee that the `pointer` is valid for writes of `length`
+/// elements of type `T` and that the memory is not accessed otherwise until the
+/// exported function returns.
+fn write<T>(pointer: *mut T, length: usize) -> &'static mut [T] {
+    if length == 0 {
+        return &mut [];
+    };
+    assert!(!pointer.is_null(), "[ERROR]: pointer is null.");
+    assert!(
+        pointer.is_aligned(),
+        "[ERROR]: pointer not properly aligned for type T."
+    );
+    assert!(
+        length < (usize::MAX / std::mem::size_of::<T>()) / 16,
+        "[ERROR]: buffer overflow."
+    );
+    unsafe { std::slice::from_raw_parts_mut(pointer, length) }
+}
+
+/// The status codes returned by exports that have no other result.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The call succeeded.
+    Ok = 0,
+    /// The call failed; `gpt3bpe_last_error` describes why.
+    Error = 1,
+}
+
+thread_local! {
+    /// The message of the last failed call on this thread.
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Records the message of a failed call for `gpt3bpe_last_error`.
+fn fail(message: &str) {
+    let message = message.trim_start_matches("[ERROR]: ").replace('\0', "");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).unwrap_or_default());
+}
+
+/// Runs the body of an export, so that no panic unwinds across the FFI boundary.
+///
+/// # Returns
+///
+/// The result of `body`, or `failure` if it returns an error or panics, in which case
+/// the message is recorded for `gpt3bpe_last_error`.
+fn guard<R>(failure: R, body: impl FnOnce() -> Result<R, String>) -> R {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)) {
+        Ok(Ok(result)) => result,
+        Ok(Err(message)) => {
+            fail(&message);
+            failure
+        }
+        Err(payload) => {
+            let message = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+                (Some(message), _) => message.to_string(),
+                (_, Some(message)) => message.clone(),
+                _ => "unknown panic".to_string(),
+            };
+            fail(&message);
+            failure
+        }
+    }
+}
+
+/// Returns the message of the last failed call on the calling thread.
+///
+/// # Returns
+///
+/// A NUL-terminated UTF-8 string, empty if no call has failed on this thread. It is
+/// owned by the library and stays valid until the next failed call on this thread.
+#[no_mangle]
+pub extern "C" fn gpt3bpe_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Narrows the token IDs of a `u16` vocabulary to the type of the FFI.
+///
+/// # Errors
+///
+/// Returns an error if an ID does not fit in a `u16`.
+fn narrow(encoding: Vec<u32>) -> Result<Vec<u16>, String> {
+    encoding
+        .into_iter()
+        .map(|value| u16::try_from(value).map_err(|_| format!("Token ID {value} does not fit in 16 bits.")))
+        .collect()
+}
+
 /// Splits a byte buffer into grapheme clusters.
 ///
 /// # Arguments
@@ -78,16 +194,72 @@ fn read<T>(pointer: *const T, length: usize) -> &'static [T] {
 /// * `callback` - A C-compatible function that is called for each byte of the resulting
 ///   grapheme clusters. It receives the index and the byte value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` bytes.
 #[no_mangle]
-pub extern "C" fn grapheme(buffer: *const u8, length: usize, callback: extern "C" fn(usize, u8)) {
-    let slice = read(buffer, length);
+pub extern "C" fn grapheme(buffer: *const u8, length: usize, callback: extern "C" fn(usize, u8)) -> Status {
+    guard(Status::Error, || {
+        let slice = read(buffer, length);
 
-    let grapheme = bpe::grapheme(slice);
-    for (idx, value) in grapheme.concat().drain(..).enumerate() {
-        callback(idx, value)
-    }
+        let grapheme = bpe::grapheme(slice);
+        for (idx, value) in grapheme.concat().drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
+}
+
+/// Splits a byte buffer into grapheme clusters, reporting each cluster on its own.
+///
+/// Unlike `grapheme`, which flattens the clusters into one stream of bytes, the callback
+/// is called once per cluster with enough information to map it back to the input. Each
+/// invalid UTF-8 sequence is a cluster of its own.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer.
+/// * `length` - The length of the buffer.
+/// * `callback` - A C-compatible function that is called for each grapheme cluster. It
+///   receives the index of the cluster, its byte offset in the input, a pointer to and
+///   the length of its original bytes, and a pointer to and the length of its
+///   GPT-unicode bytes. The pointers are only valid during the call.
+///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes.
+#[no_mangle]
+pub extern "C" fn grapheme_clusters(
+    buffer: *const u8,
+    length: usize,
+    callback: extern "C" fn(usize, usize, *const u8, usize, *const u8, usize),
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read(buffer, length);
+
+        let (mut index, mut offset) = (0, 0);
+        let mut report = |cluster: &[u8]| {
+            let unicode = bpe::unicode(cluster);
+            callback(index, offset, cluster.as_ptr(), cluster.len(), unicode.as_ptr(), unicode.len());
+            index += 1;
+            offset += cluster.len();
+        };
+        for chunk in slice.utf8_chunks() {
+            for cluster in unicode_segmentation::UnicodeSegmentation::graphemes(chunk.valid(), true) {
+                report(cluster.as_bytes());
+            }
+            if !chunk.invalid().is_empty() {
+                report(chunk.invalid());
+            };
+        }
+        Ok(Status::Ok)
+    })
 }
 
 /// Encodes a byte buffer using the r50k vocabulary.
@@ -99,6 +271,11 @@ pub extern "C" fn grapheme(buffer: *const u8, length: usize, callback: extern "C
 /// * `callback` - A C-compatible function that is called for each resulting token. It
 ///   receives the index and the token value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` without calling `callback` if a token ID does not
+/// fit in 16 bits or the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` bytes.
 #[no_mangle]
@@ -106,12 +283,15 @@ pub extern "C" fn encode_r50k(
     buffer: *const u8,
     length: usize,
     callback: extern "C" fn(usize, u16),
-) {
-    let slice = read::<u8>(buffer, length);
-    let mut encoding = bpe::encode(slice, &crate::bpe::vocabulary::R50K_TOKENS).concat();
-    for (idx, value) in encoding.drain(..).enumerate() {
-        callback(idx, value.try_into().unwrap())
-    }
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u8>(buffer, length);
+        let mut encoding = narrow(bpe::encode(slice, &crate::bpe::vocabulary::R50K_TOKENS).concat())?;
+        for (idx, value) in encoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
 }
 
 /// Decodes a buffer of r50k tokens into bytes.
@@ -123,6 +303,10 @@ pub extern "C" fn encode_r50k(
 /// * `callback` - A C-compatible function that is called for each resulting byte. It
 ///   receives the index and the byte value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` `u16` tokens.
 #[no_mangle]
@@ -130,13 +314,16 @@ pub extern "C" fn decode_r50k(
     buffer: *const u16,
     length: usize,
     callback: extern "C" fn(usize, u8),
-) {
-    let slice = read::<u16>(buffer, length);
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u16>(buffer, length);
 
-    let mut decoding = bpe::decode(slice, &crate::bpe::vocabulary::R50K_UNICODES);
-    for (idx, value) in decoding.drain(..).enumerate() {
-        callback(idx, value)
-    }
+        let mut decoding = bpe::decode(slice, &crate::bpe::vocabulary::R50K_UNICODES);
+        for (idx, value) in decoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
 }
 
 /// Encodes a byte buffer using the p50k vocabulary.
@@ -148,6 +335,11 @@ pub extern "C" fn decode_r50k(
 /// * `callback` - A C-compatible function that is called for each resulting token. It
 ///   receives the index and the token value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` without calling `callback` if a token ID does not
+/// fit in 16 bits or the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` bytes.
 #[no_mangle]
@@ -155,13 +347,16 @@ pub extern "C" fn encode_p50k(
     buffer: *const u8,
     length: usize,
     callback: extern "C" fn(usize, u16),
-) {
-    let slice = read::<u8>(buffer, length);
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u8>(buffer, length);
 
-    let mut encoding = bpe::encode(slice, &crate::bpe::vocabulary::P50K_TOKENS).concat();
-    for (idx, value) in encoding.drain(..).enumerate() {
-        callback(idx, value.try_into().unwrap())
-    }
+        let mut encoding = narrow(bpe::encode(slice, &crate::bpe::vocabulary::P50K_TOKENS).concat())?;
+        for (idx, value) in encoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
 }
 
 
@@ -174,6 +369,10 @@ pub extern "C" fn encode_p50k(
 /// * `callback` - A C-compatible function that is called for each resulting byte. It
 ///   receives the index and the byte value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` `u16` tokens.
 #[no_mangle]
@@ -181,13 +380,16 @@ pub extern "C" fn decode_p50k(
     buffer: *const u16,
     length: usize,
     callback: extern "C" fn(usize, u8),
-) {
-    let slice = read::<u16>(buffer, length);
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u16>(buffer, length);
 
-    let mut decoding = bpe::decode(slice, &crate::bpe::vocabulary::P50K_UNICODES);
-    for (idx, value) in decoding.drain(..).enumerate() {
-        callback(idx, value)
-    }
+        let mut decoding = bpe::decode(slice, &crate::bpe::vocabulary::P50K_UNICODES);
+        for (idx, value) in decoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
 }
 
 /// Encodes a byte buffer using the cl100k vocabulary.
@@ -199,6 +401,10 @@ pub extern "C" fn decode_p50k(
 /// * `callback` - A C-compatible function that is called for each resulting token. It
 ///   receives the index and the token value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` bytes.
 #[no_mangle]
@@ -206,13 +412,16 @@ pub extern "C" fn encode_cl100k(
     buffer: *const u8,
     length: usize,
     callback: extern "C" fn(usize, u32),
-) {
-    let slice = read::<u8>(buffer, length);
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u8>(buffer, length);
 
-    let mut encoding = bpe::encode(slice, &crate::bpe::vocabulary::CL100K_TOKENS).concat();
-    for (idx, value) in encoding.drain(..).enumerate() {
-        callback(idx, value)
-    }
+        let mut encoding = bpe::vocabulary::Vocabularies::CL100K.encode(slice).concat();
+        for (idx, value) in encoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
 }
 
 
@@ -225,6 +434,10 @@ pub extern "C" fn encode_cl100k(
 /// * `callback` - A C-compatible function that is called for each resulting byte. It
 ///   receives the index and the byte value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` `u32` tokens.
 #[no_mangle]
@@ -232,13 +445,16 @@ pub extern "C" fn decode_cl100k(
     buffer: *const u32,
     length: usize,
     callback: extern "C" fn(usize, u8),
-) {
-    let slice = read::<u32>(buffer, length);
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u32>(buffer, length);
 
-    let mut decoding = bpe::decode(slice, &crate::bpe::vocabulary::CL100K_UNICODES);
-    for (idx, value) in decoding.drain(..).enumerate() {
-        callback(idx, value)
-    }
+        let mut decoding = bpe::decode(slice, &crate::bpe::vocabulary::CL100K_UNICODES);
+        for (idx, value) in decoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
 }
 
 /// Encodes a byte buffer using the o200k vocabulary.
@@ -250,6 +466,10 @@ pub extern "C" fn decode_cl100k(
 /// * `callback` - A C-compatible function that is called for each resulting token. It
 ///   receives the index and the token value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` bytes.
 #[no_mangle]
@@ -257,13 +477,16 @@ pub extern "C" fn encode_o200k(
     buffer: *const u8,
     length: usize,
     callback: extern "C" fn(usize, u32),
-) {
-    let slice = read::<u8>(buffer, length);
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u8>(buffer, length);
 
-    let mut encoding = bpe::encode(slice, &crate::bpe::vocabulary::O200K_TOKENS).concat();
-    for (idx, value) in encoding.drain(..).enumerate() {
-        callback(idx, value)
-    }
+        let mut encoding = bpe::vocabulary::Vocabularies::O200K.encode(slice).concat();
+        for (idx, value) in encoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
 }
 
 
@@ -276,6 +499,10 @@ pub extern "C" fn encode_o200k(
 /// * `callback` - A C-compatible function that is called for each resulting byte. It
 ///   receives the index and the byte value.
 ///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
 /// # Safety
 /// The `buffer` pointer must be valid for `length` `u32` tokens.
 #[no_mangle]
@@ -283,106 +510,1861 @@ pub extern "C" fn decode_o200k(
     buffer: *const u32,
     length: usize,
     callback: extern "C" fn(usize, u8),
-) {
-    let slice = read::<u32>(buffer, length);
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u32>(buffer, length);
 
-    let mut decoding = bpe::decode(slice, &crate::bpe::vocabulary::O200K_UNICODES);
-    for (idx, value) in decoding.drain(..).enumerate() {
-        callback(idx, value)
-    }
+        let mut decoding = bpe::decode(slice, &crate::bpe::vocabulary::O200K_UNICODES);
+        for (idx, value) in decoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(Status::Ok)
+    })
 }
 
-#[cfg(feature = "embeddings")]
-mod embeddings;
-
-/// Inserts a text and its corresponding embedding vector into the database.
+/// Decodes a buffer of r50k tokens into bytes, handling unknown token IDs with a policy.
 ///
-/// This function is only available when the `embeddings` feature is enabled.
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `policy` - How to handle token IDs missing from the vocabulary: `0` to fail,
+///   `1` to skip them and `2` to replace them with `U+FFFD`.
+/// * `callback` - A C-compatible function that is called for each resulting byte. It
+///   receives the index and the byte value.
+///
+/// # Returns
+///
+/// Returns `false` without calling `callback` if `policy` is not a valid code, or if it
+/// is `0` and a token ID is unknown, or if the call fails. Returns `true` otherwise.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` `u16` tokens.
+#[no_mangle]
+pub extern "C" fn decode_lenient_r50k(
+    buffer: *const u16,
+    length: usize,
+    policy: u8,
+    callback: extern "C" fn(usize, u8),
+) -> bool {
+    guard(false, || {
+        let slice = read::<u16>(buffer, length);
+        let policy = bpe::Policy::try_from(policy)?;
+
+        let mut decoding = bpe::decode_lenient(slice, &crate::bpe::vocabulary::R50K_UNICODES, policy)?;
+        for (idx, value) in decoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(true)
+    })
+}
+
+/// Decodes a buffer of p50k tokens into bytes, handling unknown token IDs with a policy.
 ///
 /// # Arguments
 ///
-/// * `buffer` - A raw pointer to the byte buffer representing the text.
-/// * `buffer_length` - The length of the text buffer.
-/// * `vector` - A raw pointer to the embedding vector.
-/// * `vector_length` - The length of the vector.
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `policy` - How to handle token IDs missing from the vocabulary: `0` to fail,
+///   `1` to skip them and `2` to replace them with `U+FFFD`.
+/// * `callback` - A C-compatible function that is called for each resulting byte. It
+///   receives the index and the byte value.
 ///
 /// # Returns
 ///
-/// Returns `true` if the insertion was successful or if the text already exists in the database, `false` otherwise.
-#[cfg(feature = "embeddings")]
+/// Returns `false` without calling `callback` if `policy` is not a valid code, or if it
+/// is `0` and a token ID is unknown, or if the call fails. Returns `true` otherwise.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` `u16` tokens.
 #[no_mangle]
-pub extern "C" fn insert(
-    buffer: *const u8,
-    buffer_length: usize,
-    vector: *const f32,
-    vector_length: usize,
+pub extern "C" fn decode_lenient_p50k(
+    buffer: *const u16,
+    length: usize,
+    policy: u8,
+    callback: extern "C" fn(usize, u8),
 ) -> bool {
-    let slice = read::<u8>(buffer, buffer_length);
-    let embeddings: &[f32; embeddings::DIMENSIONS] = read::<f32>(vector, vector_length).try_into().unwrap();
-    match embeddings::insert(&embeddings::connection(None), slice, embeddings) {
-        Ok(_) => true,
-        Err(e) => e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation)
-    }
+    guard(false, || {
+        let slice = read::<u16>(buffer, length);
+        let policy = bpe::Policy::try_from(policy)?;
+
+        let mut decoding = bpe::decode_lenient(slice, &crate::bpe::vocabulary::P50K_UNICODES, policy)?;
+        for (idx, value) in decoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(true)
+    })
 }
 
-/// Searches for the most similar embeddings to a given text.
+/// Decodes a buffer of cl100k tokens into bytes, handling unknown token IDs with a policy.
 ///
-/// This function is only available when the `embeddings` feature is enabled.
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `policy` - How to handle token IDs missing from the vocabulary: `0` to fail,
+///   `1` to skip them and `2` to replace them with `U+FFFD`.
+/// * `callback` - A C-compatible function that is called for each resulting byte. It
+///   receives the index and the byte value.
+///
+/// # Returns
+///
+/// Returns `false` without calling `callback` if `policy` is not a valid code, or if it
+/// is `0` and a token ID is unknown, or if the call fails. Returns `true` otherwise.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` `u32` tokens.
+#[no_mangle]
+pub extern "C" fn decode_lenient_cl100k(
+    buffer: *const u32,
+    length: usize,
+    policy: u8,
+    callback: extern "C" fn(usize, u8),
+) -> bool {
+    guard(false, || {
+        let slice = read::<u32>(buffer, length);
+        let policy = bpe::Policy::try_from(policy)?;
+
+        let mut decoding = bpe::decode_lenient(slice, &crate::bpe::vocabulary::CL100K_UNICODES, policy)?;
+        for (idx, value) in decoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(true)
+    })
+}
+
+/// Decodes a buffer of o200k tokens into bytes, handling unknown token IDs with a policy.
 ///
 /// # Arguments
 ///
-/// * `buffer` - A raw pointer to the byte buffer representing the text.
-/// * `buffer_length` - The length of the text buffer.
-/// * `k` - The number of nearest neighbors to retrieve.
-/// * `callback` - A C-compatible function that is called for each element of the resulting
-///   vectors. It receives the row ID (in context), distance, vector position, and vector value.
-#[cfg(feature = "embeddings")]
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `policy` - How to handle token IDs missing from the vocabulary: `0` to fail,
+///   `1` to skip them and `2` to replace them with `U+FFFD`.
+/// * `callback` - A C-compatible function that is called for each resulting byte. It
+///   receives the index and the byte value.
+///
+/// # Returns
+///
+/// Returns `false` without calling `callback` if `policy` is not a valid code, or if it
+/// is `0` and a token ID is unknown, or if the call fails. Returns `true` otherwise.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` `u32` tokens.
 #[no_mangle]
-pub extern "C" fn search(
-    buffer: *const u8,
-    buffer_length: usize,
-    k: u8,
-    callback: extern "C" fn(usize, f32, usize, f32),
-) {
-    let slice = read::<u8>(buffer, buffer_length);
-    let mut top =
-        embeddings::search::<{embeddings::DIMENSIONS}, {embeddings::TOKEN_LIMIT}>(&embeddings::connection(None), slice, k).unwrap();
-    for (rid, row) in top.drain(..).enumerate() {
-        for (position, value) in row.vector.iter().enumerate() {
-            callback(rid, row.distance, position, *value);
-        };
+pub extern "C" fn decode_lenient_o200k(
+    buffer: *const u32,
+    length: usize,
+    policy: u8,
+    callback: extern "C" fn(usize, u8),
+) -> bool {
+    guard(false, || {
+        let slice = read::<u32>(buffer, length);
+        let policy = bpe::Policy::try_from(policy)?;
+
+        let mut decoding = bpe::decode_lenient(slice, &crate::bpe::vocabulary::O200K_UNICODES, policy)?;
+        for (idx, value) in decoding.drain(..).enumerate() {
+            callback(idx, value)
+        }
+        Ok(true)
+    })
+}
+
+/// Decodes tokens into a caller-allocated buffer with `bpe::decode_into`, shared by the
+/// `decode_into_*` exports.
+///
+/// # Returns
+///
+/// The number of bytes written, or the number of bytes needed if `capacity` is too
+/// small, measured by decoding again into `std::io::sink`.
+fn decode_into<T: Copy + Ord + std::fmt::Debug + std::fmt::Display>(
+    tokens: &[T],
+    lookup: &std::sync::LazyLock<std::collections::BTreeMap<T, Vec<u16>>>,
+    output: *mut u8,
+    capacity: usize,
+) -> Result<usize, String> {
+    let mut slice = write(output, capacity);
+    let decoded = match bpe::decode_into(tokens, lookup, &mut slice) {
+        Err(error) if error.kind() == std::io::ErrorKind::WriteZero => {
+            bpe::decode_into(tokens, lookup, &mut std::io::sink())
+        }
+        decoded => decoded,
     };
+    decoded.map_err(|error| error.to_string())
 }
 
-/// Finds the nearest neighbors to a given embedding vector.
+/// Decodes a buffer of r50k tokens into a caller-allocated byte buffer.
 ///
-/// This function is only available when the `embeddings` feature is enabled.
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+///
+/// # Returns
+///
+/// The number of bytes of the decoded text. If it is larger than `capacity`, the
+/// output was truncated to its first `capacity` bytes; call again with a buffer of at
+/// least the returned length. Returns `usize::MAX` if a token ID is not in the
+/// vocabulary.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` `u16` tokens and `output` must be
+/// valid for writes of `capacity` bytes.
+#[no_mangle]
+pub extern "C" fn decode_into_r50k(
+    buffer: *const u16,
+    length: usize,
+    output: *mut u8,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        decode_into(read::<u16>(buffer, length), &crate::bpe::vocabulary::R50K_UNICODES, output, capacity)
+    })
+}
+
+/// Decodes a buffer of p50k tokens into a caller-allocated byte buffer.
 ///
 /// # Arguments
 ///
-/// * `vector` - A raw pointer to the embedding vector.
-/// * `vector_length` - The length of the vector.
-/// * `k` - The number of nearest neighbors to retrieve.
-/// * `callback` - A C-compatible function that is called for each byte of the resulting
-///   labels. It receives the row ID (in context), distance, label length, byte position, and byte value.
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+///
+/// # Returns
+///
+/// The number of bytes of the decoded text. If it is larger than `capacity`, the
+/// output was truncated to its first `capacity` bytes; call again with a buffer of at
+/// least the returned length. Returns `usize::MAX` if a token ID is not in the
+/// vocabulary.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` `u16` tokens and `output` must be
+/// valid for writes of `capacity` bytes.
 #[no_mangle]
-#[cfg(feature = "embeddings")]
-pub extern "C" fn nearest(
-    vector: *const f32,
-    vector_length: usize,
-    k: u8,
-    callback: extern "C" fn(usize, f32, usize, usize, u8),
-) {
-    let slice: &[f32; embeddings::DIMENSIONS] =
-        read::<f32>(vector, vector_length).try_into().unwrap();
-    let mut top = embeddings::nearest::<{ embeddings::DIMENSIONS }>(&embeddings::connection(None), slice, k).unwrap();
-    for (rid, row) in top.drain(..).enumerate() {
-        let bytes = row.label.as_bytes();
-        let len = bytes.len();
-        for (position, value) in bytes.iter().enumerate() {
-            callback( rid, row.distance, len, position, *value);
-        };
-    };
+pub extern "C" fn decode_into_p50k(
+    buffer: *const u16,
+    length: usize,
+    output: *mut u8,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        decode_into(read::<u16>(buffer, length), &crate::bpe::vocabulary::P50K_UNICODES, output, capacity)
+    })
+}
+
+/// Decodes a buffer of cl100k tokens into a caller-allocated byte buffer.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+///
+/// # Returns
+///
+/// The number of bytes of the decoded text. If it is larger than `capacity`, the
+/// output was truncated to its first `capacity` bytes; call again with a buffer of at
+/// least the returned length. Returns `usize::MAX` if a token ID is not in the
+/// vocabulary.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` `u32` tokens and `output` must be
+/// valid for writes of `capacity` bytes.
+#[no_mangle]
+pub extern "C" fn decode_into_cl100k(
+    buffer: *const u32,
+    length: usize,
+    output: *mut u8,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        decode_into(read::<u32>(buffer, length), &crate::bpe::vocabulary::CL100K_UNICODES, output, capacity)
+    })
+}
+
+/// Decodes a buffer of o200k tokens into a caller-allocated byte buffer.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+///
+/// # Returns
+///
+/// The number of bytes of the decoded text. If it is larger than `capacity`, the
+/// output was truncated to its first `capacity` bytes; call again with a buffer of at
+/// least the returned length. Returns `usize::MAX` if a token ID is not in the
+/// vocabulary.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` `u32` tokens and `output` must be
+/// valid for writes of `capacity` bytes.
+#[no_mangle]
+pub extern "C" fn decode_into_o200k(
+    buffer: *const u32,
+    length: usize,
+    output: *mut u8,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        decode_into(read::<u32>(buffer, length), &crate::bpe::vocabulary::O200K_UNICODES, output, capacity)
+    })
+}
+
+/// Copies values into a caller-allocated buffer, shared by the `*_into` exports.
+///
+/// # Returns
+///
+/// The number of values, so that a result larger than `capacity` reports that only the
+/// first `capacity` values were written.
+fn fill<T: Copy + 'static>(values: &[T], output: *mut T, capacity: usize) -> usize {
+    let slice = write(output, capacity);
+    let length = values.len().min(capacity);
+    slice[..length].copy_from_slice(&values[..length]);
+    values.len()
+}
+
+/// One input of a batch: a borrowed buffer of `length` elements.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Slice<T> {
+    /// A raw pointer to the buffer.
+    pub pointer: *const T,
+    /// The length of the buffer.
+    pub length: usize,
+}
+
+/// Runs `each` over a batch of inputs, writing the results one after another into a
+/// caller-allocated buffer, shared by the `*_batch` exports that return values.
+///
+/// The result of input `i` is `output[offsets[i]..offsets[i + 1]]`. The offsets are
+/// always written in full, so that they describe the whole result even if the output
+/// was truncated.
+///
+/// # Returns
+///
+/// The number of values of all results, like `fill`.
+///
+/// # Errors
+///
+/// Returns the error of the first input that fails, prefixed with its index.
+fn batch<T: 'static, U: Copy + 'static>(
+    inputs: *const Slice<T>,
+    count: usize,
+    output: *mut U,
+    capacity: usize,
+    offsets: *mut usize,
+    each: impl Fn(&[T]) -> Result<Vec<U>, String>,
+) -> Result<usize, String> {
+    let inputs = read(inputs, count);
+    let output = write(output, capacity);
+    let offsets = write(offsets, count + 1);
+    offsets[0] = 0;
+    for (index, input) in inputs.iter().enumerate() {
+        let values = each(read(input.pointer, input.length)).map_err(|e| format!("Input {index}: {e}"))?;
+        let start = offsets[index].min(capacity);
+        let end = (offsets[index] + values.len()).min(capacity);
+        output[start..end].copy_from_slice(&values[..end - start]);
+        offsets[index + 1] = offsets[index] + values.len();
+    }
+    Ok(offsets[count])
+}
+
+/// Counts the tokens of a batch of inputs into a caller-allocated buffer, shared by the
+/// `count_batch_*` exports.
+///
+/// # Returns
+///
+/// The number of tokens of all inputs.
+///
+/// # Errors
+///
+/// Returns the error of the first input that fails, prefixed with its index.
+fn tally(
+    inputs: *const Slice<u8>,
+    count: usize,
+    counts: *mut usize,
+    each: impl Fn(&[u8]) -> Result<usize, String>,
+) -> Result<usize, String> {
+    let inputs = read(inputs, count);
+    let counts = write(counts, count);
+    for (index, (input, counted)) in inputs.iter().zip(counts.iter_mut()).enumerate() {
+        *counted = each(read(input.pointer, input.length)).map_err(|e| format!("Input {index}: {e}"))?;
+    }
+    Ok(counts.iter().sum())
+}
+
+/// Splits a byte buffer into grapheme clusters, writing the bytes of the clusters into
+/// a caller-allocated buffer.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+///
+/// # Returns
+///
+/// The number of bytes of the grapheme clusters, the same bytes `grapheme` calls back
+/// with. If it is larger than `capacity`, the output was truncated to its first
+/// `capacity` bytes. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `output` must be valid
+/// for writes of `capacity` bytes.
+#[no_mangle]
+pub extern "C" fn grapheme_into(buffer: *const u8, length: usize, output: *mut u8, capacity: usize) -> usize {
+    guard(usize::MAX, || {
+        let slice = read(buffer, length);
+        Ok(fill(&bpe::grapheme(slice).concat(), output, capacity))
+    })
+}
+
+/// Encodes a byte buffer using the r50k vocabulary into a caller-allocated token buffer.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+///
+/// # Returns
+///
+/// The number of tokens of the encoding. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `output` must be valid
+/// for writes of `capacity` `u16` tokens.
+#[no_mangle]
+pub extern "C" fn encode_into_r50k(
+    buffer: *const u8,
+    length: usize,
+    output: *mut u16,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let slice = read::<u8>(buffer, length);
+        let encoding = narrow(bpe::encode(slice, &crate::bpe::vocabulary::R50K_TOKENS).concat())?;
+        Ok(fill(&encoding, output, capacity))
+    })
+}
+
+/// Encodes a byte buffer using the p50k vocabulary into a caller-allocated token buffer.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+///
+/// # Returns
+///
+/// The number of tokens of the encoding. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `output` must be valid
+/// for writes of `capacity` `u16` tokens.
+#[no_mangle]
+pub extern "C" fn encode_into_p50k(
+    buffer: *const u8,
+    length: usize,
+    output: *mut u16,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let slice = read::<u8>(buffer, length);
+        let encoding = narrow(bpe::encode(slice, &crate::bpe::vocabulary::P50K_TOKENS).concat())?;
+        Ok(fill(&encoding, output, capacity))
+    })
+}
+
+/// Encodes a byte buffer using the cl100k vocabulary into a caller-allocated token buffer.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+///
+/// # Returns
+///
+/// The number of tokens of the encoding. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `output` must be valid
+/// for writes of `capacity` `u32` tokens.
+#[no_mangle]
+pub extern "C" fn encode_into_cl100k(
+    buffer: *const u8,
+    length: usize,
+    output: *mut u32,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let slice = read::<u8>(buffer, length);
+        let encoding = bpe::vocabulary::Vocabularies::CL100K.encode(slice).concat();
+        Ok(fill(&encoding, output, capacity))
+    })
+}
+
+/// Encodes a byte buffer using the o200k vocabulary into a caller-allocated token buffer.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+///
+/// # Returns
+///
+/// The number of tokens of the encoding. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `output` must be valid
+/// for writes of `capacity` `u32` tokens.
+#[no_mangle]
+pub extern "C" fn encode_into_o200k(
+    buffer: *const u8,
+    length: usize,
+    output: *mut u32,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let slice = read::<u8>(buffer, length);
+        let encoding = bpe::vocabulary::Vocabularies::O200K.encode(slice).concat();
+        Ok(fill(&encoding, output, capacity))
+    })
+}
+
+/// Encodes a batch of byte buffers using the r50k vocabulary into one caller-allocated
+/// token buffer.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the buffers to encode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   encodings: the tokens of input `i` are `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of tokens of all encodings. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens, but the offsets are complete. Returns
+/// `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers, each valid for its length,
+/// `output` must be valid for writes of `capacity` `u16` tokens and `offsets` for writes
+/// of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn encode_batch_r50k(
+    inputs: *const Slice<u8>,
+    count: usize,
+    output: *mut u16,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        batch(inputs, count, output, capacity, offsets, |slice| {
+            narrow(bpe::encode(slice, &crate::bpe::vocabulary::R50K_TOKENS).concat())
+        })
+    })
+}
+
+/// Decodes a batch of r50k token buffers into one caller-allocated byte buffer.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the token buffers to decode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   texts: the text of input `i` is `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of bytes of all texts. If it is larger than `capacity`, the output was
+/// truncated to its first `capacity` bytes, but the offsets are complete. Returns
+/// `usize::MAX` if a token ID is not in the vocabulary.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers of `u16` tokens, each valid
+/// for its length, `output` must be valid for writes of `capacity` bytes and `offsets`
+/// for writes of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn decode_batch_r50k(
+    inputs: *const Slice<u16>,
+    count: usize,
+    output: *mut u8,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        batch(inputs, count, output, capacity, offsets, |tokens| {
+            let mut decoding = Vec::with_capacity(tokens.len());
+            bpe::decode_into(tokens, &crate::bpe::vocabulary::R50K_UNICODES, &mut decoding).map_err(|e| e.to_string())?;
+            Ok(decoding)
+        })
+    })
+}
+
+/// Counts the tokens of a batch of byte buffers using the r50k vocabulary.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the buffers to count.
+/// * `count` - The number of buffers.
+/// * `counts` - A raw pointer to `count` values that receive the number of tokens of
+///   each buffer.
+///
+/// # Returns
+///
+/// The number of tokens of all buffers, or `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers, each valid for its length,
+/// and `counts` must be valid for writes of `count` values.
+#[no_mangle]
+pub extern "C" fn count_batch_r50k(inputs: *const Slice<u8>, count: usize, counts: *mut usize) -> usize {
+    guard(usize::MAX, || {
+        tally(inputs, count, counts, |slice| {
+            Ok(bpe::encode(slice, &crate::bpe::vocabulary::R50K_TOKENS).iter().map(Vec::len).sum())
+        })
+    })
+}
+
+/// Encodes a batch of byte buffers using the p50k vocabulary into one caller-allocated
+/// token buffer.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the buffers to encode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   encodings: the tokens of input `i` are `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of tokens of all encodings. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens, but the offsets are complete. Returns
+/// `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers, each valid for its length,
+/// `output` must be valid for writes of `capacity` `u16` tokens and `offsets` for writes
+/// of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn encode_batch_p50k(
+    inputs: *const Slice<u8>,
+    count: usize,
+    output: *mut u16,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        batch(inputs, count, output, capacity, offsets, |slice| {
+            narrow(bpe::encode(slice, &crate::bpe::vocabulary::P50K_TOKENS).concat())
+        })
+    })
+}
+
+/// Decodes a batch of p50k token buffers into one caller-allocated byte buffer.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the token buffers to decode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   texts: the text of input `i` is `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of bytes of all texts. If it is larger than `capacity`, the output was
+/// truncated to its first `capacity` bytes, but the offsets are complete. Returns
+/// `usize::MAX` if a token ID is not in the vocabulary.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers of `u16` tokens, each valid
+/// for its length, `output` must be valid for writes of `capacity` bytes and `offsets`
+/// for writes of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn decode_batch_p50k(
+    inputs: *const Slice<u16>,
+    count: usize,
+    output: *mut u8,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        batch(inputs, count, output, capacity, offsets, |tokens| {
+            let mut decoding = Vec::with_capacity(tokens.len());
+            bpe::decode_into(tokens, &crate::bpe::vocabulary::P50K_UNICODES, &mut decoding).map_err(|e| e.to_string())?;
+            Ok(decoding)
+        })
+    })
+}
+
+/// Counts the tokens of a batch of byte buffers using the p50k vocabulary.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the buffers to count.
+/// * `count` - The number of buffers.
+/// * `counts` - A raw pointer to `count` values that receive the number of tokens of
+///   each buffer.
+///
+/// # Returns
+///
+/// The number of tokens of all buffers, or `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers, each valid for its length,
+/// and `counts` must be valid for writes of `count` values.
+#[no_mangle]
+pub extern "C" fn count_batch_p50k(inputs: *const Slice<u8>, count: usize, counts: *mut usize) -> usize {
+    guard(usize::MAX, || {
+        tally(inputs, count, counts, |slice| {
+            Ok(bpe::encode(slice, &crate::bpe::vocabulary::P50K_TOKENS).iter().map(Vec::len).sum())
+        })
+    })
+}
+
+/// Encodes a batch of byte buffers using the cl100k vocabulary into one caller-allocated
+/// token buffer.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the buffers to encode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   encodings: the tokens of input `i` are `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of tokens of all encodings. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens, but the offsets are complete. Returns
+/// `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers, each valid for its length,
+/// `output` must be valid for writes of `capacity` `u32` tokens and `offsets` for writes
+/// of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn encode_batch_cl100k(
+    inputs: *const Slice<u8>,
+    count: usize,
+    output: *mut u32,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        batch(inputs, count, output, capacity, offsets, |slice| {
+            Ok(bpe::vocabulary::Vocabularies::CL100K.encode(slice).concat())
+        })
+    })
+}
+
+/// Decodes a batch of cl100k token buffers into one caller-allocated byte buffer.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the token buffers to decode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   texts: the text of input `i` is `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of bytes of all texts. If it is larger than `capacity`, the output was
+/// truncated to its first `capacity` bytes, but the offsets are complete. Returns
+/// `usize::MAX` if a token ID is not in the vocabulary.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers of `u32` tokens, each valid
+/// for its length, `output` must be valid for writes of `capacity` bytes and `offsets`
+/// for writes of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn decode_batch_cl100k(
+    inputs: *const Slice<u32>,
+    count: usize,
+    output: *mut u8,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        batch(inputs, count, output, capacity, offsets, |tokens| {
+            let mut decoding = Vec::with_capacity(tokens.len());
+            bpe::decode_into(tokens, &crate::bpe::vocabulary::CL100K_UNICODES, &mut decoding).map_err(|e| e.to_string())?;
+            Ok(decoding)
+        })
+    })
+}
+
+/// Counts the tokens of a batch of byte buffers using the cl100k vocabulary.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the buffers to count.
+/// * `count` - The number of buffers.
+/// * `counts` - A raw pointer to `count` values that receive the number of tokens of
+///   each buffer.
+///
+/// # Returns
+///
+/// The number of tokens of all buffers, or `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers, each valid for its length,
+/// and `counts` must be valid for writes of `count` values.
+#[no_mangle]
+pub extern "C" fn count_batch_cl100k(inputs: *const Slice<u8>, count: usize, counts: *mut usize) -> usize {
+    guard(usize::MAX, || {
+        tally(inputs, count, counts, |slice| {
+            Ok(bpe::vocabulary::Vocabularies::CL100K.encode(slice).iter().map(Vec::len).sum())
+        })
+    })
+}
+
+/// Encodes a batch of byte buffers using the o200k vocabulary into one caller-allocated
+/// token buffer.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the buffers to encode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   encodings: the tokens of input `i` are `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of tokens of all encodings. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens, but the offsets are complete. Returns
+/// `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers, each valid for its length,
+/// `output` must be valid for writes of `capacity` `u32` tokens and `offsets` for writes
+/// of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn encode_batch_o200k(
+    inputs: *const Slice<u8>,
+    count: usize,
+    output: *mut u32,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        batch(inputs, count, output, capacity, offsets, |slice| {
+            Ok(bpe::vocabulary::Vocabularies::O200K.encode(slice).concat())
+        })
+    })
+}
+
+/// Decodes a batch of o200k token buffers into one caller-allocated byte buffer.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the token buffers to decode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   texts: the text of input `i` is `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of bytes of all texts. If it is larger than `capacity`, the output was
+/// truncated to its first `capacity` bytes, but the offsets are complete. Returns
+/// `usize::MAX` if a token ID is not in the vocabulary.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers of `u32` tokens, each valid
+/// for its length, `output` must be valid for writes of `capacity` bytes and `offsets`
+/// for writes of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn decode_batch_o200k(
+    inputs: *const Slice<u32>,
+    count: usize,
+    output: *mut u8,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        batch(inputs, count, output, capacity, offsets, |tokens| {
+            let mut decoding = Vec::with_capacity(tokens.len());
+            bpe::decode_into(tokens, &crate::bpe::vocabulary::O200K_UNICODES, &mut decoding).map_err(|e| e.to_string())?;
+            Ok(decoding)
+        })
+    })
+}
+
+/// Counts the tokens of a batch of byte buffers using the o200k vocabulary.
+///
+/// # Arguments
+///
+/// * `inputs` - A raw pointer to the buffers to count.
+/// * `count` - The number of buffers.
+/// * `counts` - A raw pointer to `count` values that receive the number of tokens of
+///   each buffer.
+///
+/// # Returns
+///
+/// The number of tokens of all buffers, or `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `inputs` pointer must be valid for `count` buffers, each valid for its length,
+/// and `counts` must be valid for writes of `count` values.
+#[no_mangle]
+pub extern "C" fn count_batch_o200k(inputs: *const Slice<u8>, count: usize, counts: *mut usize) -> usize {
+    guard(usize::MAX, || {
+        tally(inputs, count, counts, |slice| {
+            Ok(bpe::vocabulary::Vocabularies::O200K.encode(slice).iter().map(Vec::len).sum())
+        })
+    })
+}
+
+/// Truncates a UTF-8 buffer with `bpe::truncation::truncate`, shared by the
+/// `truncate_*` exports.
+fn truncate(
+    vocabulary: &bpe::vocabulary::Vocabularies,
+    buffer: *const u8,
+    length: usize,
+    max_tokens: usize,
+    side: u8,
+    removed: *mut usize,
+    callback: extern "C" fn(usize, u8),
+) -> Result<bool, String> {
+    let slice = read::<u8>(buffer, length);
+    assert!(!removed.is_null(), "[ERROR]: pointer is null.");
+    let side = bpe::truncation::Side::try_from(side)?;
+    let text = std::str::from_utf8(slice).map_err(|e| format!("Buffer is not valid UTF-8: {e}"))?;
+
+    let truncation = bpe::truncation::truncate(text, max_tokens, side, vocabulary);
+    let range = truncation.removed.unwrap_or(length..length);
+    unsafe {
+        removed.write(range.start);
+        removed.add(1).write(range.end);
+    };
+    for (idx, value) in truncation.text.bytes().enumerate() {
+        callback(idx, value)
+    }
+    Ok(true)
+}
+
+/// Truncates a UTF-8 buffer to at most `max_tokens` r50k tokens.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the UTF-8 byte buffer.
+/// * `length` - The length of the buffer.
+/// * `max_tokens` - The most tokens the result may encode to.
+/// * `side` - The side to remove text from: `0` for the head, `1` for the tail and `2`
+///   for the middle.
+/// * `removed` - A raw pointer to two `usize` values that receive the start and end of
+///   the removed byte range. Both are `length` if nothing was removed.
+/// * `callback` - A C-compatible function that is called for each byte of the truncated
+///   text. It receives the index and the byte value.
+///
+/// # Returns
+///
+/// Returns `false` without calling `callback` if `side` is not a valid code, the
+/// buffer is not valid UTF-8 or the call fails. Returns `true` otherwise.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `removed` must be valid
+/// for writes of two `usize` values.
+#[no_mangle]
+pub extern "C" fn truncate_r50k(
+    buffer: *const u8,
+    length: usize,
+    max_tokens: usize,
+    side: u8,
+    removed: *mut usize,
+    callback: extern "C" fn(usize, u8),
+) -> bool {
+    guard(false, || {
+        truncate(&bpe::vocabulary::Vocabularies::R50K, buffer, length, max_tokens, side, removed, callback)
+    })
+}
+
+/// Truncates a UTF-8 buffer to at most `max_tokens` p50k tokens.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the UTF-8 byte buffer.
+/// * `length` - The length of the buffer.
+/// * `max_tokens` - The most tokens the result may encode to.
+/// * `side` - The side to remove text from: `0` for the head, `1` for the tail and `2`
+///   for the middle.
+/// * `removed` - A raw pointer to two `usize` values that receive the start and end of
+///   the removed byte range. Both are `length` if nothing was removed.
+/// * `callback` - A C-compatible function that is called for each byte of the truncated
+///   text. It receives the index and the byte value.
+///
+/// # Returns
+///
+/// Returns `false` without calling `callback` if `side` is not a valid code, the
+/// buffer is not valid UTF-8 or the call fails. Returns `true` otherwise.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `removed` must be valid
+/// for writes of two `usize` values.
+#[no_mangle]
+pub extern "C" fn truncate_p50k(
+    buffer: *const u8,
+    length: usize,
+    max_tokens: usize,
+    side: u8,
+    removed: *mut usize,
+    callback: extern "C" fn(usize, u8),
+) -> bool {
+    guard(false, || {
+        truncate(&bpe::vocabulary::Vocabularies::P50K, buffer, length, max_tokens, side, removed, callback)
+    })
+}
+
+/// Truncates a UTF-8 buffer to at most `max_tokens` cl100k tokens.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the UTF-8 byte buffer.
+/// * `length` - The length of the buffer.
+/// * `max_tokens` - The most tokens the result may encode to.
+/// * `side` - The side to remove text from: `0` for the head, `1` for the tail and `2`
+///   for the middle.
+/// * `removed` - A raw pointer to two `usize` values that receive the start and end of
+///   the removed byte range. Both are `length` if nothing was removed.
+/// * `callback` - A C-compatible function that is called for each byte of the truncated
+///   text. It receives the index and the byte value.
+///
+/// # Returns
+///
+/// Returns `false` without calling `callback` if `side` is not a valid code, the
+/// buffer is not valid UTF-8 or the call fails. Returns `true` otherwise.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `removed` must be valid
+/// for writes of two `usize` values.
+#[no_mangle]
+pub extern "C" fn truncate_cl100k(
+    buffer: *const u8,
+    length: usize,
+    max_tokens: usize,
+    side: u8,
+    removed: *mut usize,
+    callback: extern "C" fn(usize, u8),
+) -> bool {
+    guard(false, || {
+        truncate(&bpe::vocabulary::Vocabularies::CL100K, buffer, length, max_tokens, side, removed, callback)
+    })
+}
+
+/// Truncates a UTF-8 buffer to at most `max_tokens` o200k tokens.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the UTF-8 byte buffer.
+/// * `length` - The length of the buffer.
+/// * `max_tokens` - The most tokens the result may encode to.
+/// * `side` - The side to remove text from: `0` for the head, `1` for the tail and `2`
+///   for the middle.
+/// * `removed` - A raw pointer to two `usize` values that receive the start and end of
+///   the removed byte range. Both are `length` if nothing was removed.
+/// * `callback` - A C-compatible function that is called for each byte of the truncated
+///   text. It receives the index and the byte value.
+///
+/// # Returns
+///
+/// Returns `false` without calling `callback` if `side` is not a valid code, the
+/// buffer is not valid UTF-8 or the call fails. Returns `true` otherwise.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `length` bytes and `removed` must be valid
+/// for writes of two `usize` values.
+#[no_mangle]
+pub extern "C" fn truncate_o200k(
+    buffer: *const u8,
+    length: usize,
+    max_tokens: usize,
+    side: u8,
+    removed: *mut usize,
+    callback: extern "C" fn(usize, u8),
+) -> bool {
+    guard(false, || {
+        truncate(&bpe::vocabulary::Vocabularies::O200K, buffer, length, max_tokens, side, removed, callback)
+    })
+}
+
+/// Creates a tokenizer handle from a JSON configuration.
+///
+/// The configuration is an object with the optional fields `vocabulary` (a built-in
+/// vocabulary name, `p50k` by default), `path` (a `.jsonl` vocabulary file to load
+/// instead), `special` (`text`, `allow` or `reject`), `special_tokens` (an object of
+/// names to IDs), `unknown` (`error`, `skip` or `replace`) and `cache` (the number of
+/// pieces to cache). An empty buffer is the default configuration.
+///
+/// # Arguments
+///
+/// * `config` - A raw pointer to the UTF-8 JSON configuration.
+/// * `length` - The length of the configuration.
+///
+/// # Returns
+///
+/// An opaque handle to pass to `gpt3bpe_encode` and `gpt3bpe_decode` and to release with
+/// `gpt3bpe_tokenizer_free`, or null if the configuration is invalid or the vocabulary
+/// file does not exist. The handle may be used from several threads at once.
+///
+/// # Safety
+/// The `config` pointer must be valid for `length` bytes.
+#[no_mangle]
+pub extern "C" fn gpt3bpe_tokenizer_new(config: *const u8, length: usize) -> *mut tokenizer::Tokenizer {
+    guard(std::ptr::null_mut(), || {
+        let config = match length {
+            0 => "",
+            _ => std::str::from_utf8(read::<u8>(config, length)).map_err(|e| format!("Config is not valid UTF-8: {e}"))?,
+        };
+        let tokenizer = tokenizer::Tokenizer::from_json(config)?;
+        Ok(Box::into_raw(Box::new(tokenizer)))
+    })
+}
+
+/// Borrows the tokenizer behind a handle.
+///
+/// # Panics
+///
+/// Panics if the handle is null.
+fn handle(tokenizer: *const tokenizer::Tokenizer) -> &'static tokenizer::Tokenizer {
+    assert!(!tokenizer.is_null(), "[ERROR]: tokenizer handle is null.");
+    unsafe { &*tokenizer }
+}
+
+/// Takes back ownership of the tokenizer behind a handle created by
+/// `gpt3bpe_tokenizer_new`.
+fn owned(tokenizer: *mut tokenizer::Tokenizer) -> Box<tokenizer::Tokenizer> {
+    unsafe { Box::from_raw(tokenizer) }
+}
+
+/// Encodes a byte buffer with a tokenizer handle into a caller-allocated token buffer.
+///
+/// # Arguments
+///
+/// * `tokenizer` - A handle returned by `gpt3bpe_tokenizer_new`.
+/// * `buffer` - A raw pointer to the byte buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+///
+/// # Returns
+///
+/// The number of tokens of the encoding. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens. Returns `usize::MAX` if the tokenizer
+/// rejects a special token in the input.
+///
+/// # Safety
+/// The handle must be live, the `buffer` pointer must be valid for `length` bytes and
+/// `output` must be valid for writes of `capacity` tokens.
+#[no_mangle]
+pub extern "C" fn gpt3bpe_encode(
+    tokenizer: *const tokenizer::Tokenizer,
+    buffer: *const u8,
+    length: usize,
+    output: *mut u32,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let encoding = handle(tokenizer).encode(read::<u8>(buffer, length))?;
+        Ok(fill(&encoding, output, capacity))
+    })
+}
+
+/// Decodes a token buffer with a tokenizer handle into a caller-allocated byte buffer.
+///
+/// # Arguments
+///
+/// * `tokenizer` - A handle returned by `gpt3bpe_tokenizer_new`.
+/// * `buffer` - A raw pointer to the token buffer.
+/// * `length` - The length of the buffer.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+///
+/// # Returns
+///
+/// The number of bytes of the decoded text. If it is larger than `capacity`, the
+/// output was truncated to its first `capacity` bytes. Returns `usize::MAX` if a token
+/// ID is unknown and the tokenizer treats unknown IDs as errors.
+///
+/// # Safety
+/// The handle must be live, the `buffer` pointer must be valid for `length` tokens and
+/// `output` must be valid for writes of `capacity` bytes.
+#[no_mangle]
+pub extern "C" fn gpt3bpe_decode(
+    tokenizer: *const tokenizer::Tokenizer,
+    buffer: *const u32,
+    length: usize,
+    output: *mut u8,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let decoding = handle(tokenizer).decode(read::<u32>(buffer, length))?;
+        Ok(fill(&decoding, output, capacity))
+    })
+}
+
+/// Encodes a batch of byte buffers with a tokenizer handle into one caller-allocated
+/// token buffer.
+///
+/// # Arguments
+///
+/// * `tokenizer` - A handle returned by `gpt3bpe_tokenizer_new`.
+/// * `inputs` - A raw pointer to the buffers to encode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The number of tokens the output buffer holds.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   encodings: the tokens of input `i` are `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of tokens of all encodings. If it is larger than `capacity`, the output
+/// was truncated to its first `capacity` tokens, but the offsets are complete. Returns
+/// `usize::MAX` if the tokenizer rejects a special token in an input.
+///
+/// # Safety
+/// The handle must be live, the `inputs` pointer must be valid for `count` buffers, each
+/// valid for its length, `output` must be valid for writes of `capacity` tokens and
+/// `offsets` for writes of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn gpt3bpe_encode_batch(
+    tokenizer: *const tokenizer::Tokenizer,
+    inputs: *const Slice<u8>,
+    count: usize,
+    output: *mut u32,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let tokenizer = handle(tokenizer);
+        batch(inputs, count, output, capacity, offsets, |slice| tokenizer.encode(slice))
+    })
+}
+
+/// Decodes a batch of token buffers with a tokenizer handle into one caller-allocated
+/// byte buffer.
+///
+/// # Arguments
+///
+/// * `tokenizer` - A handle returned by `gpt3bpe_tokenizer_new`.
+/// * `inputs` - A raw pointer to the token buffers to decode.
+/// * `count` - The number of buffers.
+/// * `output` - A raw pointer to the output buffer. May be null if `capacity` is `0`.
+/// * `capacity` - The length of the output buffer.
+/// * `offsets` - A raw pointer to `count + 1` values that receive the offsets of the
+///   texts: the text of input `i` is `output[offsets[i]..offsets[i + 1]]`.
+///
+/// # Returns
+///
+/// The number of bytes of all texts. If it is larger than `capacity`, the output was
+/// truncated to its first `capacity` bytes, but the offsets are complete. Returns
+/// `usize::MAX` if a token ID is unknown and the tokenizer treats unknown IDs as errors.
+///
+/// # Safety
+/// The handle must be live, the `inputs` pointer must be valid for `count` buffers, each
+/// valid for its length, `output` must be valid for writes of `capacity` bytes and
+/// `offsets` for writes of `count + 1` values.
+#[no_mangle]
+pub extern "C" fn gpt3bpe_decode_batch(
+    tokenizer: *const tokenizer::Tokenizer,
+    inputs: *const Slice<u32>,
+    count: usize,
+    output: *mut u8,
+    capacity: usize,
+    offsets: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let tokenizer = handle(tokenizer);
+        batch(inputs, count, output, capacity, offsets, |tokens| tokenizer.decode(tokens))
+    })
+}
+
+/// Counts the tokens of a batch of byte buffers with a tokenizer handle.
+///
+/// # Arguments
+///
+/// * `tokenizer` - A handle returned by `gpt3bpe_tokenizer_new`.
+/// * `inputs` - A raw pointer to the buffers to count.
+/// * `count` - The number of buffers.
+/// * `counts` - A raw pointer to `count` values that receive the number of tokens of
+///   each buffer.
+///
+/// # Returns
+///
+/// The number of tokens of all buffers, or `usize::MAX` if the tokenizer rejects a
+/// special token in an input.
+///
+/// # Safety
+/// The handle must be live, the `inputs` pointer must be valid for `count` buffers, each
+/// valid for its length, and `counts` must be valid for writes of `count` values.
+#[no_mangle]
+pub extern "C" fn gpt3bpe_count_batch(
+    tokenizer: *const tokenizer::Tokenizer,
+    inputs: *const Slice<u8>,
+    count: usize,
+    counts: *mut usize,
+) -> usize {
+    guard(usize::MAX, || {
+        let tokenizer = handle(tokenizer);
+        tally(inputs, count, counts, |slice| Ok(tokenizer.encode(slice)?.len()))
+    })
+}
+
+/// Releases a tokenizer handle.
+///
+/// # Arguments
+///
+/// * `tokenizer` - A handle returned by `gpt3bpe_tokenizer_new`. Null handles are ignored.
+///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
+/// # Safety
+/// The handle must not be in use by another thread, and must not be used or released
+/// again afterwards.
+#[no_mangle]
+pub extern "C" fn gpt3bpe_tokenizer_free(tokenizer: *mut tokenizer::Tokenizer) -> Status {
+    guard(Status::Error, || {
+        if !tokenizer.is_null() {
+            drop(owned(tokenizer));
+        };
+        Ok(Status::Ok)
+    })
+}
+
+#[cfg(feature = "embeddings")]
+pub mod embeddings;
+
+/// Reads an embedding vector of `embeddings::DIMENSIONS` floats.
+///
+/// # Errors
+///
+/// Returns an error if the vector has another length.
+#[cfg(feature = "embeddings")]
+fn embedding(vector: *const f32, length: usize) -> Result<&'static [f32; embeddings::DIMENSIONS], String> {
+    read::<f32>(vector, length)
+        .try_into()
+        .map_err(|_| format!("Expecting a vector of length {}, got {length}.", embeddings::DIMENSIONS))
+}
+
+/// Inserts a text and its embedding into a database, shared by `insert` and
+/// `gpt3bpe_store_insert`. A text that is already stored counts as inserted.
+#[cfg(feature = "embeddings")]
+fn insert_row(
+    connection: &rusqlite::Connection,
+    buffer: *const u8,
+    buffer_length: usize,
+    vector: *const f32,
+    vector_length: usize,
+) -> Result<bool, String> {
+    let slice = read::<u8>(buffer, buffer_length);
+    let embeddings = embedding(vector, vector_length)?;
+    match embeddings::insert(connection, slice, embeddings) {
+        Ok(_) => Ok(true),
+        Err(e) if e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation) => Ok(true),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Searches a database by text into caller-allocated buffers, shared by `search_into`
+/// and `gpt3bpe_store_search`.
+#[cfg(feature = "embeddings")]
+fn search_rows(
+    connection: &rusqlite::Connection,
+    buffer: *const u8,
+    buffer_length: usize,
+    k: u8,
+    distances: *mut f32,
+    vectors: *mut f32,
+    capacity: usize,
+) -> Result<usize, String> {
+    let slice = read::<u8>(buffer, buffer_length);
+    let top = embeddings::search::<{ embeddings::DIMENSIONS }, { embeddings::TOKEN_LIMIT }>(connection, slice, k)
+        .map_err(|e| e.to_string())?;
+    let distances = write(distances, capacity);
+    let vectors = write(vectors, capacity * embeddings::DIMENSIONS);
+    for ((row, distance), vector) in top.iter().zip(distances).zip(vectors.as_chunks_mut::<{ embeddings::DIMENSIONS }>().0) {
+        *distance = row.distance;
+        *vector = row.vector;
+    }
+    Ok(top.len())
+}
+
+/// Searches a database by vector into caller-allocated buffers, shared by
+/// `nearest_into` and `gpt3bpe_store_nearest`.
+#[cfg(feature = "embeddings")]
+#[allow(clippy::too_many_arguments)]
+fn nearest_rows(
+    connection: &rusqlite::Connection,
+    vector: *const f32,
+    vector_length: usize,
+    k: u8,
+    distances: *mut f32,
+    offsets: *mut usize,
+    capacity: usize,
+    labels: *mut *mut u8,
+) -> Result<usize, String> {
+    let slice = embedding(vector, vector_length)?;
+    let top = embeddings::nearest::<{ embeddings::DIMENSIONS }>(connection, slice, k).map_err(|e| e.to_string())?;
+    let rows = &top[..top.len().min(capacity)];
+    fill(&rows.iter().map(|row| row.distance).collect::<Vec<_>>(), distances, capacity);
+    let offsets = write(offsets, capacity + 1);
+    offsets[0] = 0;
+    for (i, row) in rows.iter().enumerate() {
+        offsets[i + 1] = offsets[i] + row.label.len();
+    }
+    if !labels.is_null() {
+        let bytes: Box<[u8]> = rows.iter().flat_map(|row| row.label.bytes()).collect();
+        unsafe { *labels = Box::into_raw(bytes).cast() };
+    };
+    Ok(top.len())
+}
+
+/// Inserts a text and its corresponding embedding vector into the database.
+///
+/// This function is only available when the `embeddings` feature is enabled.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer representing the text.
+/// * `buffer_length` - The length of the text buffer.
+/// * `vector` - A raw pointer to the embedding vector.
+/// * `vector_length` - The length of the vector.
+///
+/// # Returns
+///
+/// Returns `true` if the insertion was successful or if the text already exists in the database, `false` otherwise.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn insert(
+    buffer: *const u8,
+    buffer_length: usize,
+    vector: *const f32,
+    vector_length: usize,
+) -> bool {
+    guard(false, || insert_row(&embeddings::connection(None), buffer, buffer_length, vector, vector_length))
+}
+
+/// Searches for the most similar embeddings to a given text.
+///
+/// This function is only available when the `embeddings` feature is enabled.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer representing the text.
+/// * `buffer_length` - The length of the text buffer.
+/// * `k` - The number of nearest neighbors to retrieve.
+/// * `callback` - A C-compatible function that is called for each element of the resulting
+///   vectors. It receives the row ID (in context), distance, vector position, and vector value.
+///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn search(
+    buffer: *const u8,
+    buffer_length: usize,
+    k: u8,
+    callback: extern "C" fn(usize, f32, usize, f32),
+) -> Status {
+    guard(Status::Error, || {
+        let slice = read::<u8>(buffer, buffer_length);
+        let mut top =
+            embeddings::search::<{embeddings::DIMENSIONS}, {embeddings::TOKEN_LIMIT}>(&embeddings::connection(None), slice, k).map_err(|e| e.to_string())?;
+        for (rid, row) in top.drain(..).enumerate() {
+            for (position, value) in row.vector.iter().enumerate() {
+                callback(rid, row.distance, position, *value);
+            };
+        };
+        Ok(Status::Ok)
+    })
+}
+
+/// Finds the nearest neighbors to a given embedding vector.
+///
+/// This function is only available when the `embeddings` feature is enabled.
+///
+/// # Arguments
+///
+/// * `vector` - A raw pointer to the embedding vector.
+/// * `vector_length` - The length of the vector.
+/// * `k` - The number of nearest neighbors to retrieve.
+/// * `callback` - A C-compatible function that is called for each byte of the resulting
+///   labels. It receives the row ID (in context), distance, label length, byte position, and byte value.
+///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+#[no_mangle]
+#[cfg(feature = "embeddings")]
+pub extern "C" fn nearest(
+    vector: *const f32,
+    vector_length: usize,
+    k: u8,
+    callback: extern "C" fn(usize, f32, usize, usize, u8),
+) -> Status {
+    guard(Status::Error, || {
+        let slice = embedding(vector, vector_length)?;
+        let mut top = embeddings::nearest::<{ embeddings::DIMENSIONS }>(&embeddings::connection(None), slice, k).map_err(|e| e.to_string())?;
+        for (rid, row) in top.drain(..).enumerate() {
+            let bytes = row.label.as_bytes();
+            let len = bytes.len();
+            for (position, value) in bytes.iter().enumerate() {
+                callback( rid, row.distance, len, position, *value);
+            };
+        };
+        Ok(Status::Ok)
+    })
+}
+
+/// Searches for the most similar embeddings to a given text, writing the results into
+/// caller-allocated buffers.
+///
+/// This function is only available when the `embeddings` feature is enabled.
+///
+/// # Arguments
+///
+/// * `buffer` - A raw pointer to the byte buffer representing the text.
+/// * `buffer_length` - The length of the text buffer.
+/// * `k` - The number of nearest neighbors to retrieve.
+/// * `distances` - A raw pointer to `capacity` floats that receive the distance of each row.
+/// * `vectors` - A raw pointer to `capacity * 300` floats that receive the vector of each
+///   row, one after another.
+/// * `capacity` - The number of rows the buffers hold. Both pointers may be null if it is `0`.
+///
+/// # Returns
+///
+/// The number of rows found. If it is larger than `capacity`, only the first `capacity`
+/// rows were written. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `buffer` pointer must be valid for `buffer_length` bytes, and `distances` and
+/// `vectors` must be valid for writes of `capacity` and `capacity * 300` floats.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn search_into(
+    buffer: *const u8,
+    buffer_length: usize,
+    k: u8,
+    distances: *mut f32,
+    vectors: *mut f32,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        search_rows(&embeddings::connection(None), buffer, buffer_length, k, distances, vectors, capacity)
+    })
+}
+
+/// Finds the nearest neighbors to a given embedding vector, writing the results into
+/// caller-allocated buffers.
+///
+/// The labels have different lengths, so they are returned in a single buffer allocated
+/// by the library, which the caller must release with `free_buffer`.
+///
+/// This function is only available when the `embeddings` feature is enabled.
+///
+/// # Arguments
+///
+/// * `vector` - A raw pointer to the embedding vector.
+/// * `vector_length` - The length of the vector.
+/// * `k` - The number of nearest neighbors to retrieve.
+/// * `distances` - A raw pointer to `capacity` floats that receive the distance of each row.
+/// * `offsets` - A raw pointer to `capacity + 1` values that receive the offsets of the
+///   labels: the label of row `i` is bytes `offsets[i]..offsets[i + 1]` of `labels`.
+/// * `capacity` - The number of rows the buffers hold.
+/// * `labels` - A raw pointer that receives the buffer of the labels of the written rows,
+///   `offsets[n]` bytes long for `n` written rows. May be null to skip the labels.
+///
+/// # Returns
+///
+/// The number of rows found. If it is larger than `capacity`, only the first `capacity`
+/// rows were written. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The `vector` pointer must be valid for `vector_length` floats, `distances` and
+/// `offsets` for writes of `capacity` and `capacity + 1` values, and `labels` for a
+/// write of one pointer.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn nearest_into(
+    vector: *const f32,
+    vector_length: usize,
+    k: u8,
+    distances: *mut f32,
+    offsets: *mut usize,
+    capacity: usize,
+    labels: *mut *mut u8,
+) -> usize {
+    guard(usize::MAX, || {
+        nearest_rows(&embeddings::connection(None), vector, vector_length, k, distances, offsets, capacity, labels)
+    })
+}
+
+/// Opens an embeddings store at an explicit location and returns a handle to it.
+///
+/// Unlike `insert`, `search` and `nearest`, which open the database named by
+/// `GPT3BPE_WORDS` (or a fresh in-memory one) on every call, a store keeps one
+/// connection open until it is closed.
+///
+/// This function is only available when the `embeddings` feature is enabled.
+///
+/// # Arguments
+///
+/// * `path` - A raw pointer to the UTF-8 path of the database file, or `:memory:`.
+/// * `length` - The length of the path.
+///
+/// # Returns
+///
+/// An opaque handle to pass to the `gpt3bpe_store_*` functions and to release with
+/// `gpt3bpe_store_close`, or null if the database cannot be opened. The handle may be
+/// used from several threads at once.
+///
+/// # Safety
+/// The `path` pointer must be valid for `length` bytes.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn gpt3bpe_store_open(path: *const u8, length: usize) -> *mut embeddings::Store {
+    guard(std::ptr::null_mut(), || {
+        let path = std::str::from_utf8(read::<u8>(path, length)).map_err(|e| format!("Path is not valid UTF-8: {e}"))?;
+        if path.is_empty() {
+            return Err("Path is empty.".to_string());
+        };
+        Ok(Box::into_raw(Box::new(embeddings::Store::open(path))))
+    })
+}
+
+/// Borrows the store behind a handle.
+///
+/// # Panics
+///
+/// Panics if the handle is null.
+#[cfg(feature = "embeddings")]
+fn store(store: *const embeddings::Store) -> &'static embeddings::Store {
+    assert!(!store.is_null(), "[ERROR]: store handle is null.");
+    unsafe { &*store }
+}
+
+/// Inserts a text and its embedding vector into a store, like `insert`.
+///
+/// # Arguments
+///
+/// * `store` - A handle returned by `gpt3bpe_store_open`.
+/// * `buffer` - A raw pointer to the byte buffer representing the text.
+/// * `buffer_length` - The length of the text buffer.
+/// * `vector` - A raw pointer to the embedding vector.
+/// * `vector_length` - The length of the vector.
+///
+/// # Returns
+///
+/// Returns `true` if the insertion was successful or if the text already exists in the store, `false` otherwise.
+///
+/// # Safety
+/// The handle must be live, and `buffer` and `vector` must be valid for `buffer_length`
+/// bytes and `vector_length` floats.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn gpt3bpe_store_insert(
+    store: *const embeddings::Store,
+    buffer: *const u8,
+    buffer_length: usize,
+    vector: *const f32,
+    vector_length: usize,
+) -> bool {
+    guard(false, || insert_row(&self::store(store).lock(), buffer, buffer_length, vector, vector_length))
+}
+
+/// Searches a store for the most similar embeddings to a given text, like `search_into`.
+///
+/// # Arguments
+///
+/// * `store` - A handle returned by `gpt3bpe_store_open`.
+/// * `buffer` - A raw pointer to the byte buffer representing the text.
+/// * `buffer_length` - The length of the text buffer.
+/// * `k` - The number of nearest neighbors to retrieve.
+/// * `distances` - A raw pointer to `capacity` floats that receive the distance of each row.
+/// * `vectors` - A raw pointer to `capacity * 300` floats that receive the vector of each
+///   row, one after another.
+/// * `capacity` - The number of rows the buffers hold.
+///
+/// # Returns
+///
+/// The number of rows found. If it is larger than `capacity`, only the first `capacity`
+/// rows were written. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The handle must be live, the `buffer` pointer must be valid for `buffer_length` bytes,
+/// and `distances` and `vectors` must be valid for writes of `capacity` and
+/// `capacity * 300` floats.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn gpt3bpe_store_search(
+    store: *const embeddings::Store,
+    buffer: *const u8,
+    buffer_length: usize,
+    k: u8,
+    distances: *mut f32,
+    vectors: *mut f32,
+    capacity: usize,
+) -> usize {
+    guard(usize::MAX, || {
+        search_rows(&self::store(store).lock(), buffer, buffer_length, k, distances, vectors, capacity)
+    })
+}
+
+/// Finds the nearest neighbors in a store to a given embedding vector, like `nearest_into`.
+///
+/// # Arguments
+///
+/// * `store` - A handle returned by `gpt3bpe_store_open`.
+/// * `vector` - A raw pointer to the embedding vector.
+/// * `vector_length` - The length of the vector.
+/// * `k` - The number of nearest neighbors to retrieve.
+/// * `distances` - A raw pointer to `capacity` floats that receive the distance of each row.
+/// * `offsets` - A raw pointer to `capacity + 1` values that receive the offsets of the
+///   labels: the label of row `i` is bytes `offsets[i]..offsets[i + 1]` of `labels`.
+/// * `capacity` - The number of rows the buffers hold.
+/// * `labels` - A raw pointer that receives the buffer of the labels of the written rows,
+///   to release with `free_buffer`. May be null to skip the labels.
+///
+/// # Returns
+///
+/// The number of rows found. If it is larger than `capacity`, only the first `capacity`
+/// rows were written. Returns `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The handle must be live, the `vector` pointer must be valid for `vector_length`
+/// floats, `distances` and `offsets` for writes of `capacity` and `capacity + 1` values,
+/// and `labels` for a write of one pointer.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn gpt3bpe_store_nearest(
+    store: *const embeddings::Store,
+    vector: *const f32,
+    vector_length: usize,
+    k: u8,
+    distances: *mut f32,
+    offsets: *mut usize,
+    capacity: usize,
+    labels: *mut *mut u8,
+) -> usize {
+    guard(usize::MAX, || {
+        nearest_rows(&self::store(store).lock(), vector, vector_length, k, distances, offsets, capacity, labels)
+    })
+}
+
+/// Deletes a text and its embedding vector from a store.
+///
+/// # Arguments
+///
+/// * `store` - A handle returned by `gpt3bpe_store_open`.
+/// * `buffer` - A raw pointer to the byte buffer representing the text.
+/// * `buffer_length` - The length of the text buffer.
+///
+/// # Returns
+///
+/// The number of texts deleted, `0` or `1`, or `usize::MAX` if the call fails.
+///
+/// # Safety
+/// The handle must be live and the `buffer` pointer must be valid for `buffer_length` bytes.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn gpt3bpe_store_delete(store: *const embeddings::Store, buffer: *const u8, buffer_length: usize) -> usize {
+    guard(usize::MAX, || {
+        let slice = read::<u8>(buffer, buffer_length);
+        embeddings::delete(&self::store(store).lock(), slice).map_err(|e| e.to_string())
+    })
+}
+
+/// Takes back ownership of the store behind a handle created by `gpt3bpe_store_open`.
+#[cfg(feature = "embeddings")]
+fn closed(store: *mut embeddings::Store) -> Box<embeddings::Store> {
+    unsafe { Box::from_raw(store) }
+}
+
+/// Closes a store and releases its handle.
+///
+/// # Arguments
+///
+/// * `store` - A handle returned by `gpt3bpe_store_open`. Null handles are ignored.
+///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
+/// # Safety
+/// The handle must not be in use by another thread, and must not be used or closed
+/// again afterwards.
+#[cfg(feature = "embeddings")]
+#[no_mangle]
+pub extern "C" fn gpt3bpe_store_close(store: *mut embeddings::Store) -> Status {
+    guard(Status::Error, || {
+        if !store.is_null() {
+            drop(closed(store));
+        };
+        Ok(Status::Ok)
+    })
+}
+
+/// Releases a buffer allocated by the library, such as the labels of `nearest_into`.
+///
+/// # Arguments
+///
+/// * `pointer` - The pointer returned by the library. Null pointers are ignored.
+/// * `length` - The length of the buffer as reported by the library.
+///
+/// # Returns
+///
+/// `Status::Ok`, or `Status::Error` if the call fails; see `gpt3bpe_last_error`.
+///
+/// # Safety
+/// The pointer must have been returned by this library with the given length, and must
+/// not be used or released again afterwards.
+#[no_mangle]
+pub extern "C" fn free_buffer(pointer: *mut u8, length: usize) -> Status {
+    guard(Status::Error, || {
+        if !pointer.is_null() {
+            drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(pointer, length)) });
+        };
+        Ok(Status::Ok)
+    })
 }
 
 // #[cfg(feature = "neural")]
@@ -6,32 +6,36 @@
 //!
 //! The tool is designed to be used in a pipeline, for example:
 //! `echo "hello world" | gpt3bpe`
-mod bpe;
 mod cli;
-#[cfg(feature = "embeddings")]
-mod embeddings;
 mod instruments;
 #[cfg(feature = "neural")]
 mod neural;
-use std::io::{BufRead, Write, stdin, stdout};
+mod rpc;
+mod server;
+use gpt3bpe::bpe;
+#[cfg(feature = "embeddings")]
+use gpt3bpe::embeddings;
+use rand::SeedableRng;
+use std::io::Write;
 
 /// The main entry point of the command-line utility.
 ///
-/// This function parses command-line arguments, reads from stdin, and performs
-/// the requested operation (grapheme splitting, encoding, or decoding).
+/// This function parses command-line arguments, reads from stdin or the `--input`
+/// files, and performs the requested operation (grapheme splitting, encoding, or
+/// decoding), writing to stdout or the `--output` file.
 ///
-/// It processes input line by line, allowing it to be used with piped data streams.
+/// It processes input line by line, allowing it to be used with piped data streams,
+/// or as a single document with `--whole`.
 /// The default operation is encoding, but this can be changed with flags like `--decode`
 /// or by using a subcommand like `grapheme`.
 /// # Panics
 ///
 /// This function will panic if:
-/// * It fails to read a line from stdin.
+/// * It fails to read a line from the input.
 /// * It fails to parse a token from a line during decoding.
-/// * It fails to write the decoded bytes to stdout.
+/// * It fails to write to the output.
 fn main() {
     let args: cli::Arguments = argh::from_env();
-    let stdin = stdin();
 
     // Ensure that --encode and --decode flags are not used simultaneously.
     if args.encode && args.decode {
@@ -39,62 +43,275 @@ fn main() {
         std::process::exit(1);
     };
 
-    // Process each line from standard input.
-    for line in stdin.lock().lines() {
-        let line = line.expect("Could not read line from stdin");
+    // Open the --output file, or stdout, that every mode writes to.
+    let mut output = cli::output(&args).unwrap_or_else(|e| {
+        eprintln!("[ERROR]: {e}");
+        std::process::exit(1);
+    });
+
+    // Handle the 'verify' subcommand, which reads fixture files instead of the input.
+    if let Some(cli::Command::Verify(ref verify)) = args.command {
+        let mut failed = false;
+        let mut records = cli::Records::new(args.format);
+        for file in &verify.files {
+            let verification = cli::verify(file).unwrap_or_else(|e| {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            });
+            failed |= !verification.mismatches.is_empty();
+            if args.format != cli::Format::Text {
+                records.push(verification.record(file), &mut output).unwrap();
+                continue;
+            };
+            for (_, mismatch) in &verification.mismatches {
+                writeln!(output, "{mismatch}").unwrap();
+            }
+            writeln!(
+                output,
+                "{file}: {} passed, {} failed, {} known failures, {} skipped",
+                verification.passed,
+                verification.mismatches.len(),
+                verification.known.len(),
+                verification.skipped
+            )
+            .unwrap();
+        }
+        records.finish(&mut output).unwrap();
+        output.flush().unwrap();
+        std::process::exit(if failed { 1 } else { 0 });
+    };
+
+    // Handle the 'serve' subcommand, which answers HTTP requests until it is stopped.
+    if let Some(cli::Command::Serve(ref serve)) = args.command {
+        let address = format!("{}:{}", serve.host, serve.port);
+        let server = server::Server::bind(&address, serve.workers, args.vocabulary).unwrap_or_else(|e| {
+            eprintln!("[ERROR]: {e}");
+            std::process::exit(1);
+        });
+        eprintln!("Listening on http://{}", server.address());
+        if let Err(e) = server.run() {
+            eprintln!("[ERROR]: {e}");
+            std::process::exit(1);
+        };
+        return;
+    };
+
+    // Handle the 'sample' subcommand, which reads prepared shards instead of the input.
+    if let Some(cli::Command::Sample(ref sample)) = args.command {
+        match cli::sample(sample, &args) {
+            Ok(windows) => cli::single(&windows, args.format, &mut output).unwrap(),
+            Err(e) => {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            }
+        };
+        output.flush().unwrap();
+        return;
+    };
+
+    // Open the --input files one after another, or stdin.
+    let reader = cli::input(&args).unwrap_or_else(|e| {
+        eprintln!("[ERROR]: {e}");
+        std::process::exit(1);
+    });
+
+    // Answer JSON-RPC requests from the input until it ends.
+    if args.rpc {
+        if let Err(e) = rpc::serve(reader, &mut output, args.vocabulary) {
+            eprintln!("[ERROR]: {e}");
+            std::process::exit(1);
+        };
+        return;
+    };
+
+    // Handle the 'stats' subcommand, which summarises all of the input at once.
+    if let Some(cli::Command::Stats(ref stats)) = args.command {
+        match cli::stats(reader, stats, &args) {
+            Ok(summary) => cli::single(&summary, args.format, &mut output).unwrap(),
+            Err(e) => {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            }
+        };
+        output.flush().unwrap();
+        return;
+    };
+
+    // Handle the 'chat' subcommand, which reads all of the input as one JSON array.
+    if let Some(cli::Command::Chat(ref chat)) = args.command {
+        let input = std::io::read_to_string(reader).expect("Could not read input");
+        match cli::chat(&input, chat, &args) {
+            Ok(chat) => cli::single(&chat, args.format, &mut output).unwrap(),
+            Err(e) => {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            }
+        };
+        output.flush().unwrap();
+        return;
+    };
+
+    // Handle the 'pack' subcommand, which reads all of the input as one JSON array.
+    if let Some(cli::Command::Pack(ref pack)) = args.command {
+        let input = std::io::read_to_string(reader).expect("Could not read input");
+        match cli::pack(&input, pack, &args) {
+            Ok(pack) => cli::single(&pack, args.format, &mut output).unwrap(),
+            Err(e) => {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            }
+        };
+        output.flush().unwrap();
+        return;
+    };
+
+    // Handle the 'prepare' subcommand, which writes the input as binary token shards.
+    if let Some(cli::Command::Prepare(ref prepare)) = args.command {
+        match cli::prepare(reader, prepare, &args) {
+            Ok(metadata) => cli::single(&metadata, args.format, &mut output).unwrap(),
+            Err(e) => {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            }
+        };
+        output.flush().unwrap();
+        return;
+    };
+
+    // Validate the BPE-dropout probability before reading any input.
+    if let Some(p) = args.dropout {
+        if !(0.0..=1.0).contains(&p) {
+            eprintln!("[ERROR]: --dropout must be between 0 and 1.");
+            std::process::exit(1);
+        };
+    };
+    let mut rng = rand::rngs::StdRng::seed_from_u64(args.seed);
+
+    // HTML fragments collected by `show --html`, written out once all lines are read.
+    let mut report = vec![];
+
+    // Records of the `json` and `ndjson` formats.
+    let mut records = cli::Records::new(args.format);
+
+    // Process each line of the input, or the whole input with --whole.
+    for line in cli::documents(reader, &args) {
+        let line = line.unwrap_or_else(|e| {
+            eprintln!("[ERROR]: Could not read input: {e}.");
+            std::process::exit(1);
+        });
+
+        // In the `json` and `ndjson` formats every mode emits one record per line.
+        if args.format != cli::Format::Text {
+            if let Some(cli::Command::Show(cli::ShowCommand { html: Some(_) })) = args.command {
+                report.push(cli::html(line.clone(), &args).unwrap_or_else(|e| {
+                    eprintln!("[ERROR]: {e}");
+                    std::process::exit(1);
+                }));
+            };
+            let record = cli::record(line, &args, &mut rng).unwrap_or_else(|e| {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            });
+            records.push(record, &mut output).unwrap();
+            continue;
+        };
 
         // Handle the 'embed' subcommand if present.
         if let Some(cli::Command::Embed(_)) = args.command {
             if !cfg!(feature = "embeddings") | !cfg!(feature = "neural"){
-                println!("[WARNING]: `embed` command can only be used if the `embeddings` and `neural` feature is enabled.");
+                writeln!(output, "[WARNING]: `embed` command can only be used if the `embeddings` and `neural` feature is enabled.").unwrap();
                 break;
             }
             #[cfg(feature = "embeddings")]
             #[cfg(feature = "neural")]
             let embedding = cli::embed::<{embeddings::DIMENSIONS}, 75, {embeddings::PADDING}>(line, &args);
-            let mut writer = stdout().lock();
             #[cfg(feature = "embeddings")]
             #[cfg(feature = "neural")]
-            write!(stdout().lock(), "{:?}", embedding).unwrap();
-            writer.flush().unwrap();
+            write!(output, "{:?}", embedding).unwrap();
+            output.flush().unwrap();
             continue;
         };
         
+        // Handle the 'compare' subcommand if present.
+        if let Some(cli::Command::Compare(ref compare)) = args.command {
+            let table = cli::compare(line, compare).unwrap_or_else(|e| {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            });
+            writeln!(output, "{table}").unwrap();
+            continue;
+        };
+
+        // Handle the 'explain' subcommand if present.
+        if let Some(cli::Command::Explain(ref explain)) = args.command {
+            writeln!(output, "{}", cli::explain(line, explain, &args)).unwrap();
+            continue;
+        };
+
+        // Handle the 'truncate' subcommand if present.
+        if let Some(cli::Command::Truncate(ref truncate)) = args.command {
+            writeln!(output, "{}", cli::truncate(line, truncate, &args)).unwrap();
+            continue;
+        };
+
+        // Handle the 'prefix' subcommand if present.
+        if let Some(cli::Command::Prefix(ref prefix)) = args.command {
+            writeln!(output, "{}", cli::prefix(line, prefix, &args)).unwrap();
+            continue;
+        };
+
+        // Handle the 'show' subcommand if present.
+        if let Some(cli::Command::Show(ref show)) = args.command {
+            let shown = match show.html {
+                Some(_) => cli::html(line, &args),
+                None => cli::show(line, &args),
+            }
+            .unwrap_or_else(|e| {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            });
+            match show.html {
+                Some(_) => report.push(shown),
+                None => writeln!(output, "{shown}").unwrap(),
+            };
+            continue;
+        };
+
         // Handle the 'grapheme' subcommand if present.
         if let Some(cli::Command::Grapheme(_)) = args.command {
             let grapheme = cli::grapheme(line);
-            stdout().write_all(grapheme.as_bytes()).unwrap();
+            output.write_all(grapheme.as_bytes()).unwrap();
             continue;
         };
 
 
         if args.decode {
-            let bytes = cli::decode(line, &args);
-            stdout().write_all(&bytes).unwrap();
+            let bytes = cli::decode(line, &args).unwrap_or_else(|e| {
+                eprintln!("[ERROR]: {e}");
+                std::process::exit(1);
+            });
+            output.write_all(&bytes).unwrap();
             continue;
         };
 
         // The default operation is encoding.
-        let tokens = match args.vocabulary {
-            bpe::vocabulary::Vocabularies::R50K => {
-                bpe::encode(line.as_bytes(), &bpe::vocabulary::R50K_TOKENS)
-            },
-            bpe::vocabulary::Vocabularies::P50K => {
-                bpe::encode(line.as_bytes(), &bpe::vocabulary::P50K_TOKENS)
-            },
-            bpe::vocabulary::Vocabularies::CL100K => {
-                bpe::encode(line.as_bytes(), &bpe::vocabulary::CL100K_TOKENS)
-            },
-            bpe::vocabulary::Vocabularies::O200K => {
-                bpe::encode(line.as_bytes(), &bpe::vocabulary::O200K_TOKENS)
-            }
+        let tokens = match args.dropout {
+            Some(p) => cli::encode_dropout(line.as_bytes(), &args.vocabulary, p, &mut rng),
+            None => cli::encode(line.as_bytes(), &args.vocabulary),
         };
         // Format the resulting tokens into a space-separated string.
-        let output = tokens
+        let tokens = tokens
             .iter()
             .flat_map(|t| -> Vec<String> { t.iter().map(|u| u.to_string()).collect() })
             .collect::<Vec<_>>()
             .join(" ");
-        println!("{output}");
+        writeln!(output, "{tokens}").unwrap();
     }
+    records.finish(&mut output).unwrap();
+    output.flush().unwrap();
+
+    if let Some(cli::Command::Show(cli::ShowCommand { html: Some(ref path) })) = args.command {
+        std::fs::write(path, cli::report(&report, &args))
+            .unwrap_or_else(|_| panic!("[ERROR]: Could not write report to {path}"));
+    };
 }
@@ -344,9 +344,28 @@ pub(crate) mod tensor {
     }
 }
 
+#[cfg(test)]
 pub(crate) mod neural {
     use crate::neural::{Layer, Activation, tensor::Tensor, forward, train };
     use crate::neural::unit::tensor::assert_tensor_approx_eq;
+
+    /// Turns a batch of token windows into `(x, y)` pairs for `train`.
+    ///
+    /// Token IDs are scaled by `vocab_size` into `[0, 1)`, so that every window is fed to
+    /// the network on the same scale as its targets.
+    fn windows<const INPUT: usize, const INPUT_LANES: usize, const OUTPUT_LANES: usize, const OUTPUT: usize>(
+        batch: &crate::bpe::dataset::Batch,
+        vocab_size: u32,
+    ) -> Vec<(Tensor<f32, INPUT, INPUT_LANES>, Tensor<f32, OUTPUT, OUTPUT_LANES>)> {
+        let scale = |window: &Vec<u32>| window.iter().map(|&id| id as f32 / vocab_size as f32).collect::<Vec<f32>>();
+        batch
+            .contexts
+            .iter()
+            .zip(&batch.targets)
+            .map(|(context, target)| (Tensor::new(scale(context)), Tensor::new(scale(target))))
+            .collect()
+    }
+
     #[test]
     pub(crate) fn layer() {
         let weights: &[Tensor<f32, 4, 1>;4] = &[Tensor::new(vec![ 0.1, 0.2, -0.1, 0.0]), Tensor::new(vec![-0.2, 0.1, 0.3, 0.1]), Tensor::new(vec![ 0.0, 0.0, 0.2, -0.2]), Tensor::new(vec![ 0.1, -0.1, 0.1, 0.2])];
@@ -380,4 +399,16 @@ pub(crate) mod neural {
             assert_tensor_approx_eq( weight , &dweights[idx]);
         }
     }
+    #[test]
+    pub(crate) fn windows_of_batch() {
+        let batch = crate::bpe::dataset::Batch { contexts: vec![vec![0, 1, 2, 3]], targets: vec![vec![1, 2, 3, 4]] };
+        let pairs = windows::<4, 1, 1, 4>(&batch, 8);
+        assert_eq!(pairs.len(), 1);
+        assert_tensor_approx_eq::<4, 1>(&pairs[0].0, &Tensor::from(&[0.0, 0.125, 0.25, 0.375]));
+        assert_tensor_approx_eq::<4, 1>(&pairs[0].1, &Tensor::from(&[0.125, 0.25, 0.375, 0.5]));
+        let weights: [Tensor<f32, 4, 1>; 4] = std::array::from_fn(|_| Tensor::new(vec![0.1; 4]));
+        let mut net: Vec<Layer<f32, 4, 1, 1, 4>> = vec![Layer::new(Activation::Sigmoid, weights, [0.0; 4], None)];
+        let (x, y) = &pairs[0];
+        train(&mut net, x, y, &0.1);
+    }
 }
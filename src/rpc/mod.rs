@@ -0,0 +1,250 @@
+//! Speaks JSON-RPC 2.0 over stdin and stdout.
+//!
+//! Editors and agents that spawn the CLI as a child process can keep one process alive
+//! and send it requests, instead of paying for a new process, and the loading of its
+//! vocabularies, on every call. Each line of the input holds one request, or one batch
+//! of requests, as JSON; each response is written on one line and flushed at once.
+//! JSON escapes line breaks inside strings, so a line always holds exactly one message.
+//!
+//! Parameters are passed by name. Every method but `vocabulary` takes the text or IDs
+//! to work on, and all of them take an optional `vocabulary` that defaults to the one
+//! the process was started with. Vocabularies are loaded on first use and stay loaded
+//! until the process exits.
+pub(crate) mod unit;
+use crate::bpe;
+use crate::bpe::vocabulary::Vocabularies;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+
+/// Invalid JSON was received.
+pub(crate) const PARSE_ERROR: i64 = -32700;
+/// The JSON sent is not a valid request object.
+pub(crate) const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist.
+pub(crate) const METHOD_NOT_FOUND: i64 = -32601;
+/// The method parameters are invalid.
+pub(crate) const INVALID_PARAMS: i64 = -32602;
+/// The method failed unexpectedly.
+pub(crate) const INTERNAL_ERROR: i64 = -32603;
+
+/// The methods served.
+pub(crate) const METHODS: &[&str] = &["encode", "decode", "count", "truncate", "vocabulary"];
+
+/// A JSON-RPC error object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Fault {
+    /// The error code.
+    pub code: i64,
+    /// A short description of the error.
+    pub message: String,
+}
+
+impl Fault {
+    /// Creates an error with a code and a message.
+    fn new(code: i64, message: impl std::fmt::Display) -> Self {
+        Fault { code, message: message.to_string() }
+    }
+
+    /// Returns the error as a JSON object.
+    fn value(&self) -> Value {
+        json!({ "code": self.code, "message": self.message })
+    }
+}
+
+/// The parameters of `encode` and `count`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Text {
+    /// The text to encode.
+    text: String,
+    /// The vocabulary to encode with.
+    vocabulary: Option<String>,
+}
+
+/// The parameters of `decode`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Ids {
+    /// The token IDs to decode.
+    ids: Vec<u32>,
+    /// The vocabulary to decode with.
+    vocabulary: Option<String>,
+    /// How to decode unknown token IDs (`error`, `skip`, `replace`).
+    unknown: Option<String>,
+}
+
+/// The parameters of `truncate`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Truncate {
+    /// The text to truncate.
+    text: String,
+    /// The most tokens the text may encode to.
+    max_tokens: usize,
+    /// The side to remove text from (`head`, `tail`, `middle`).
+    side: Option<String>,
+    /// The vocabulary to encode with.
+    vocabulary: Option<String>,
+}
+
+/// The parameters of `vocabulary`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Info {
+    /// The vocabulary to describe.
+    vocabulary: Option<String>,
+}
+
+/// Reads the parameters of a method. Missing parameters read as an empty object.
+fn params<T: serde::de::DeserializeOwned>(params: Option<&Value>) -> Result<T, Fault> {
+    let params = match params {
+        None => json!({}),
+        Some(params @ Value::Object(_)) => params.clone(),
+        Some(_) => return Err(Fault::new(INVALID_PARAMS, "Parameters must be passed by name.")),
+    };
+    serde_json::from_value(params).map_err(|e| Fault::new(INVALID_PARAMS, e))
+}
+
+/// Resolves an optional vocabulary name, falling back to the default of the process.
+///
+/// # Errors
+///
+/// Returns an error if the name is unknown or the vocabulary file is missing.
+fn vocabulary(name: Option<&str>, default: Vocabularies) -> Result<Vocabularies, Fault> {
+    Vocabularies::resolve(name, default).map_err(|e| Fault::new(INVALID_PARAMS, e))
+}
+
+/// Calls a method.
+///
+/// # Arguments
+///
+/// * `method` - The name of the method.
+/// * `parameters` - The `params` member of the request, if any.
+/// * `default` - The vocabulary of calls that do not name one.
+///
+/// # Returns
+///
+/// The result of the method, or the error to answer with.
+pub(crate) fn call(method: &str, parameters: Option<&Value>, default: Vocabularies) -> Result<Value, Fault> {
+    match method {
+        "encode" => {
+            let Text { text, vocabulary: name } = params(parameters)?;
+            let ids = vocabulary(name.as_deref(), default)?.encode(text.as_bytes()).concat();
+            Ok(json!({ "count": ids.len(), "ids": ids }))
+        }
+        "count" => {
+            let Text { text, vocabulary: name } = params(parameters)?;
+            let count = vocabulary(name.as_deref(), default)?.encode(text.as_bytes()).concat().len();
+            Ok(json!({ "count": count }))
+        }
+        "decode" => {
+            let Ids { ids, vocabulary: name, unknown } = params(parameters)?;
+            let policy = bpe::Policy::resolve(unknown.as_deref()).map_err(|e| Fault::new(INVALID_PARAMS, e))?;
+            let bytes = vocabulary(name.as_deref(), default)?
+                .decode(&ids, policy)
+                .map_err(|e| Fault::new(INVALID_PARAMS, e))?;
+            Ok(json!({ "text": String::from_utf8_lossy(&bytes) }))
+        }
+        "truncate" => {
+            let Truncate { text, max_tokens, side, vocabulary: name } = params(parameters)?;
+            let side = match side {
+                Some(side) => side.parse().map_err(|e| Fault::new(INVALID_PARAMS, e))?,
+                None => bpe::truncation::Side::default(),
+            };
+            let vocabulary = vocabulary(name.as_deref(), default)?;
+            let truncation = bpe::truncation::truncate(&text, max_tokens, side, &vocabulary);
+            Ok(serde_json::to_value(truncation).unwrap())
+        }
+        "vocabulary" => {
+            let Info { vocabulary: name } = params(parameters)?;
+            let vocabulary = vocabulary(name.as_deref(), default)?;
+            let special: serde_json::Map<String, Value> =
+                vocabulary.special().iter().map(|&(name, id)| (name.to_string(), json!(id))).collect();
+            let available: Vec<String> =
+                Vocabularies::iter().filter(|v| v.available()).map(|v| v.to_string()).collect();
+            Ok(json!({
+                "name": vocabulary.to_string(),
+                "size": vocabulary.size(),
+                "special": special,
+                "available": available,
+            }))
+        }
+        _ => Err(Fault::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {method}. Please use one of: {}", METHODS.join(", ")),
+        )),
+    }
+}
+
+/// Answers one request object.
+///
+/// # Returns
+///
+/// The response, or `None` for a notification, a request without an `id`, which is
+/// never answered.
+pub(crate) fn respond(request: &Value, default: Vocabularies) -> Option<Value> {
+    let id = match request.get("id") {
+        None => None,
+        Some(id @ (Value::Null | Value::String(_) | Value::Number(_))) => Some(id.clone()),
+        Some(_) => return Some(failure(Value::Null, Fault::new(INVALID_REQUEST, "Member id must be a string, number or null."))),
+    };
+    let method = match (request.get("jsonrpc"), request.get("method")) {
+        (Some(Value::String(version)), Some(Value::String(method))) if version == "2.0" => method,
+        _ => {
+            let fault = Fault::new(INVALID_REQUEST, "Requests must have jsonrpc \"2.0\" and a string method.");
+            return Some(failure(id.unwrap_or(Value::Null), fault));
+        }
+    };
+    // A panic in a method must not end the process its client relies on.
+    let result = std::panic::catch_unwind(|| call(method, request.get("params"), default))
+        .unwrap_or_else(|_| Err(Fault::new(INTERNAL_ERROR, format!("Method {method} failed."))));
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(fault) => failure(id, fault),
+    })
+}
+
+/// Builds an error response.
+fn failure(id: Value, fault: Fault) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": fault.value() })
+}
+
+/// Answers one line of the input, holding a request or a batch of requests.
+///
+/// # Returns
+///
+/// The response, the array of responses of a batch, or `None` if there is nothing to
+/// answer because the line is blank or holds only notifications.
+pub(crate) fn line(line: &str, default: Vocabularies) -> Option<Value> {
+    if line.trim().is_empty() {
+        return None;
+    };
+    match serde_json::from_str::<Value>(line) {
+        Err(e) => Some(failure(Value::Null, Fault::new(PARSE_ERROR, e))),
+        Ok(Value::Array(batch)) if batch.is_empty() => {
+            Some(failure(Value::Null, Fault::new(INVALID_REQUEST, "Batches must not be empty.")))
+        }
+        Ok(Value::Array(batch)) => {
+            let responses: Vec<Value> = batch.iter().filter_map(|request| respond(request, default)).collect();
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        Ok(request) => respond(&request, default),
+    }
+}
+
+/// Answers every line of `reader` until it ends.
+///
+/// # Errors
+///
+/// Returns an error if the input cannot be read or the output cannot be written.
+pub(crate) fn serve(reader: impl BufRead, writer: &mut impl Write, default: Vocabularies) -> std::io::Result<()> {
+    for input in reader.lines() {
+        if let Some(response) = line(&input?, default) {
+            writeln!(writer, "{response}")?;
+            writer.flush()?;
+        };
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+#[cfg(test)]
+pub(crate) mod rpc {
+    use crate::bpe::vocabulary::Vocabularies;
+    use crate::rpc::{line, serve, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR};
+    use serde_json::json;
+
+    fn request(text: &str) -> serde_json::Value {
+        line(text, Vocabularies::P50K).unwrap()
+    }
+
+    #[test]
+    pub (crate) fn test_rpc_methods() {
+        let response = request(r#"{"jsonrpc": "2.0", "id": 1, "method": "encode", "params": {"text": "hello world"}}"#);
+        assert_eq!(response, json!({ "jsonrpc": "2.0", "id": 1, "result": { "count": 2, "ids": [31373, 995] } }));
+
+        let response = request(r#"{"jsonrpc": "2.0", "id": "a", "method": "encode", "params": {"text": "hello world", "vocabulary": "cl100k"}}"#);
+        assert_eq!(response["result"]["ids"], json!([15339, 1917]));
+
+        let response = request(r#"{"jsonrpc": "2.0", "id": 2, "method": "decode", "params": {"ids": [31373, 995]}}"#);
+        assert_eq!(response["result"]["text"], "hello world");
+
+        let response = request(r#"{"jsonrpc": "2.0", "id": 3, "method": "count", "params": {"text": "let there be light."}}"#);
+        assert_eq!(response["result"]["count"], 5);
+
+        let response = request(
+            r#"{"jsonrpc": "2.0", "id": 4, "method": "truncate", "params": {"text": "let there be light.", "max_tokens": 3, "side": "head"}}"#,
+        );
+        assert!(response["result"]["ids"].as_array().unwrap().len() <= 3);
+        assert!(response["result"]["text"].as_str().unwrap().starts_with("..."));
+
+        let response = request(r#"{"jsonrpc": "2.0", "id": 5, "method": "vocabulary"}"#);
+        assert_eq!(response["result"]["name"], "P50K");
+        assert_eq!(response["result"]["special"]["<|endoftext|>"], 50256);
+        assert!(response["result"]["available"].as_array().unwrap().contains(&json!("R50K")));
+    }
+
+    #[test]
+    pub (crate) fn test_rpc_errors() {
+        let code = |text: &str| request(text)["error"]["code"].as_i64().unwrap();
+        assert_eq!(code("{not json"), PARSE_ERROR);
+        assert_eq!(code("[]"), INVALID_REQUEST);
+        assert_eq!(code(r#"{"id": 1, "method": "encode"}"#), INVALID_REQUEST);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "id": 1, "method": "embed"}"#), METHOD_NOT_FOUND);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "id": 1, "method": "encode", "params": ["hello"]}"#), INVALID_PARAMS);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "id": 1, "method": "encode", "params": {}}"#), INVALID_PARAMS);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "id": 1, "method": "encode", "params": {"text": "a", "vocabulary": "x"}}"#), INVALID_PARAMS);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "id": 1, "method": "decode", "params": {"ids": [4000000000]}}"#), INVALID_PARAMS);
+        assert_eq!(request("{not json")["id"], serde_json::Value::Null);
+    }
+
+    #[test]
+    pub (crate) fn test_rpc_batches_and_notifications() {
+        assert_eq!(line(r#"{"jsonrpc": "2.0", "method": "count", "params": {"text": "a"}}"#, Vocabularies::P50K), None);
+        assert_eq!(line("   ", Vocabularies::P50K), None);
+        let response = request(
+            r#"[{"jsonrpc": "2.0", "id": 1, "method": "count", "params": {"text": "hello"}}, {"jsonrpc": "2.0", "method": "count", "params": {"text": "a"}}, {"jsonrpc": "2.0", "id": 2, "method": "nope"}]"#,
+        );
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["result"]["count"], 1);
+        assert_eq!(responses[1]["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    pub (crate) fn test_rpc_serve() {
+        let input = concat!(
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "encode", "params": {"text": "hello\nworld"}}"#,
+            "\n\n",
+            r#"{"jsonrpc": "2.0", "method": "count", "params": {"text": "a"}}"#,
+            "\n",
+            r#"{"jsonrpc": "2.0", "id": 2, "method": "count", "params": {"text": "hello world"}}"#,
+            "\n",
+        );
+        let mut output = vec![];
+        serve(std::io::Cursor::new(input), &mut output, Vocabularies::P50K).unwrap();
+        let responses: Vec<serde_json::Value> =
+            String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"]["ids"], json!(Vocabularies::P50K.encode(b"hello\nworld").concat()));
+        assert_eq!(responses[1]["result"]["count"], 2);
+    }
+}
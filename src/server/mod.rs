@@ -0,0 +1,468 @@
+//! Serves the tokenizer over HTTP/1.1.
+//!
+//! This module implements a small HTTP/1.1 server on top of `std::net`, so that other
+//! services can tokenize text without linking the library. Connections are accepted on
+//! the main thread and handed to a fixed pool of worker threads, each of which serves
+//! the requests of one connection at a time and keeps the connection alive until the
+//! client closes it, asks to with `Connection: close`, or stays idle for longer than
+//! the read timeout of the server. A request must also arrive in full within a deadline,
+//! so that a client sending a byte at a time cannot hold a worker either.
+//!
+//! Every endpoint takes a JSON object in the body of a `POST` request and answers with
+//! a JSON object; errors are answered with `{"error": ...}` and a `4xx` or `5xx` status.
+//! Requests may name a `vocabulary`, and use the one the server was started with
+//! otherwise.
+pub(crate) mod unit;
+use crate::bpe;
+use crate::bpe::vocabulary::Vocabularies;
+#[cfg(feature = "embeddings")]
+use crate::embeddings;
+use serde_json::{json, Value};
+use std::io::{BufRead, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The largest request body the server reads, in bytes.
+pub(crate) const MAX_BODY: usize = 16 * 1024 * 1024;
+
+/// The longest request line or header line the server reads, in bytes.
+pub(crate) const MAX_LINE: usize = 8 * 1024;
+
+/// The most header lines a request may have.
+pub(crate) const MAX_HEADERS: usize = 100;
+
+/// How long a connection may stay silent before the server closes it.
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client may take to send one request, head and body, however steadily it sends.
+pub(crate) const DEADLINE: Duration = Duration::from_secs(30);
+
+/// The endpoints served, in the order they are documented.
+pub(crate) const ENDPOINTS: &[&str] = &[
+    "/encode",
+    "/decode",
+    "/count",
+    "/chunk",
+    #[cfg(feature = "embeddings")]
+    "/search",
+    #[cfg(feature = "embeddings")]
+    "/nearest",
+];
+
+/// An HTTP request, reduced to what the endpoints use.
+#[derive(Debug)]
+pub(crate) struct Request {
+    /// The request method, e.g. `POST`.
+    pub method: String,
+    /// The request target, without its query string.
+    pub path: String,
+    /// Whether the client asked to close the connection after this request.
+    pub close: bool,
+    /// The request body.
+    pub body: Vec<u8>,
+}
+
+/// An HTTP response with a JSON body.
+#[derive(Debug)]
+pub(crate) struct Response {
+    /// The status code.
+    pub status: u16,
+    /// The body.
+    pub body: Value,
+}
+
+impl Response {
+    /// Creates a `200 OK` response.
+    fn ok(body: Value) -> Self {
+        Response { status: 200, body }
+    }
+
+    /// Creates an error response with the message in an `error` field.
+    fn error(status: u16, message: impl std::fmt::Display) -> Self {
+        Response { status, body: json!({ "error": message.to_string() }) }
+    }
+
+    /// Returns the reason phrase of the status code.
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            414 => "URI Too Long",
+            431 => "Request Header Fields Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+
+    /// Writes the response to a stream.
+    fn write(&self, stream: &mut impl Write, close: bool) -> std::io::Result<()> {
+        let body = self.body.to_string();
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n{body}",
+            self.status,
+            self.reason(),
+            body.len(),
+            if close { "Connection: close\r\n" } else { "" }
+        )?;
+        stream.flush()
+    }
+}
+
+/// Reads one line of the request head, of at most `MAX_LINE` bytes.
+///
+/// # Returns
+///
+/// The number of bytes read, or the error response to answer with, `status` if the line
+/// is too long and `408 Request Timeout` if the client stopped sending.
+fn head(reader: &mut impl BufRead, line: &mut String, status: u16) -> Result<usize, Response> {
+    match reader.by_ref().take(MAX_LINE as u64 + 1).read_line(line) {
+        Ok(_) if line.len() > MAX_LINE => Err(Response::error(status, format!("Line exceeds {MAX_LINE} bytes."))),
+        Ok(read) => Ok(read),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+            Err(Response::error(408, "Timed out reading the request."))
+        }
+        Err(e) => Err(Response::error(400, format!("Could not read request: {e}"))),
+    }
+}
+
+/// Reads one request from a connection.
+///
+/// # Returns
+///
+/// The request, `Ok(None)` if the client closed the connection, or let it time out,
+/// before sending one, or an error response if the request is malformed.
+pub(crate) fn read(reader: &mut impl BufRead) -> Result<Option<Request>, Response> {
+    let mut line = String::new();
+    match head(reader, &mut line, 414) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        // An idle keep-alive connection is closed without an answer.
+        Err(response) if response.status == 408 && line.is_empty() => return Ok(None),
+        Err(response) => return Err(response),
+    };
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(Response::error(400, format!("Malformed request line: {}", line.trim_end())));
+    };
+    let mut close = version == "HTTP/1.0";
+    let mut length = 0;
+    for count in 0.. {
+        if count == MAX_HEADERS {
+            return Err(Response::error(431, format!("Request has more than {MAX_HEADERS} headers.")));
+        };
+        let mut header = String::new();
+        if head(reader, &mut header, 431)? == 0 {
+            return Err(Response::error(400, "Connection closed inside the headers."));
+        };
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        };
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(Response::error(400, format!("Malformed header: {header}")));
+        };
+        match name.trim().to_lowercase().as_str() {
+            "content-length" => {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| Response::error(400, format!("Invalid Content-Length: {}", value.trim())))?;
+            }
+            "connection" => close = value.trim().eq_ignore_ascii_case("close"),
+            "transfer-encoding" => return Err(Response::error(400, "Chunked requests are not supported.")),
+            _ => {}
+        };
+    }
+    if length > MAX_BODY {
+        return Err(Response::error(413, format!("Request body exceeds {MAX_BODY} bytes.")));
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+            Response::error(408, "Timed out reading the request body.")
+        }
+        _ => Response::error(400, format!("Could not read request body: {e}")),
+    })?;
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    Ok(Some(Request { method: method.to_string(), path, close, body }))
+}
+
+/// What the workers of a server share.
+#[derive(Debug)]
+pub(crate) struct State {
+    /// The vocabulary of requests that do not name one.
+    pub vocabulary: Vocabularies,
+    /// The word embeddings queried by `/search` and `/nearest`, opened once for all workers.
+    #[cfg(feature = "embeddings")]
+    pub store: embeddings::Store,
+}
+
+/// Reads an optional `vocabulary` field, falling back to the default of the server.
+fn vocabulary(body: &Value, default: Vocabularies) -> Result<Vocabularies, Response> {
+    let name = match body.get("vocabulary") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(name)) => Some(name.as_str()),
+        Some(_) => return Err(Response::error(400, "Field vocabulary must be a string.")),
+    };
+    Vocabularies::resolve(name, default).map_err(|e| Response::error(400, e))
+}
+
+/// Reads a required string field.
+fn text<'a>(body: &'a Value, field: &str) -> Result<&'a str, Response> {
+    body.get(field)
+        .and_then(Value::as_str)
+        .ok_or_else(|| Response::error(400, format!("Missing string field {field}.")))
+}
+
+/// Reads a required non-negative integer field.
+fn number(body: &Value, field: &str) -> Result<u64, Response> {
+    body.get(field)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| Response::error(400, format!("Missing non-negative integer field {field}.")))
+}
+
+/// Reads the `k` field of the embeddings endpoints, a number of rows between 1 and 255.
+#[cfg(feature = "embeddings")]
+fn rows(body: &Value) -> Result<u8, Response> {
+    u8::try_from(number(body, "k")?)
+        .ok()
+        .filter(|&k| k > 0)
+        .ok_or_else(|| Response::error(400, "Field k must be between 1 and 255."))
+}
+
+/// Writes the rows of a similarity search as JSON.
+#[cfg(feature = "embeddings")]
+fn table<const D: usize>(rows: Vec<embeddings::Row<D>>) -> Value {
+    let rows: Vec<Value> = rows
+        .into_iter()
+        .map(|row| json!({ "rid": row.rid, "label": row.label, "distance": row.distance }))
+        .collect();
+    json!({ "rows": rows })
+}
+
+/// Answers a request.
+///
+/// # Arguments
+///
+/// * `request` - The request to answer.
+/// * `state` - The default vocabulary and the word embeddings of the server.
+///
+/// # Returns
+///
+/// The response, `404 Not Found` for unknown paths, `405 Method Not Allowed` for
+/// methods other than `POST`, and `400 Bad Request` for invalid bodies.
+pub(crate) fn handle(request: &Request, state: &State) -> Response {
+    let default = state.vocabulary;
+    if !ENDPOINTS.contains(&request.path.as_str()) {
+        return Response::error(404, format!("Unknown endpoint {}. Please use one of: {}", request.path, ENDPOINTS.join(", ")));
+    };
+    if request.method != "POST" {
+        return Response::error(405, format!("Method {} is not allowed. Please use POST.", request.method));
+    };
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body @ Value::Object(_)) => body,
+        Ok(_) => return Response::error(400, "Request body must be a JSON object."),
+        Err(e) => return Response::error(400, format!("Could not parse request body: {e}")),
+    };
+    let answer = || -> Result<Value, Response> {
+        match request.path.as_str() {
+            "/encode" => {
+                let vocabulary = vocabulary(&body, default)?;
+                let ids = vocabulary.encode(text(&body, "text")?.as_bytes()).concat();
+                Ok(json!({ "vocabulary": vocabulary.to_string(), "count": ids.len(), "ids": ids }))
+            }
+            "/decode" => {
+                let vocabulary = vocabulary(&body, default)?;
+                let ids: Vec<u32> = body
+                    .get("ids")
+                    .cloned()
+                    .and_then(|ids| serde_json::from_value(ids).ok())
+                    .ok_or_else(|| Response::error(400, "Missing field ids of token IDs."))?;
+                let policy = bpe::Policy::resolve(body.get("unknown").and_then(Value::as_str))
+                    .map_err(|e| Response::error(400, e))?;
+                let bytes = vocabulary.decode(&ids, policy).map_err(|e| Response::error(400, e))?;
+                Ok(json!({ "vocabulary": vocabulary.to_string(), "text": String::from_utf8_lossy(&bytes) }))
+            }
+            "/count" => {
+                let vocabulary = vocabulary(&body, default)?;
+                let count = vocabulary.encode(text(&body, "text")?.as_bytes()).concat().len();
+                Ok(json!({ "vocabulary": vocabulary.to_string(), "count": count }))
+            }
+            "/chunk" => {
+                let vocabulary = vocabulary(&body, default)?;
+                let max_tokens = usize::try_from(number(&body, "max_tokens")?).unwrap_or(usize::MAX);
+                let chunks = bpe::chunk::chunk(text(&body, "text")?, max_tokens, &vocabulary)
+                    .map_err(|e| Response::error(400, e))?;
+                Ok(json!({ "vocabulary": vocabulary.to_string(), "chunks": chunks }))
+            }
+            #[cfg(feature = "embeddings")]
+            "/search" => {
+                let query = text(&body, "text")?;
+                if query.is_empty() {
+                    return Err(Response::error(400, "Field text must not be empty."));
+                };
+                let rows = embeddings::search::<{ embeddings::DIMENSIONS }, { embeddings::TOKEN_LIMIT }>(
+                    &state.store.lock(),
+                    query.as_bytes(),
+                    rows(&body)?,
+                )
+                .map_err(|e| Response::error(500, e))?;
+                Ok(table(rows))
+            }
+            #[cfg(feature = "embeddings")]
+            "/nearest" => {
+                let vector: [f32; embeddings::DIMENSIONS] = body
+                    .get("vector")
+                    .cloned()
+                    .and_then(|vector| serde_json::from_value::<Vec<f32>>(vector).ok())
+                    .and_then(|vector| vector.try_into().ok())
+                    .ok_or_else(|| {
+                        Response::error(400, format!("Field vector must hold {} numbers.", embeddings::DIMENSIONS))
+                    })?;
+                let rows = embeddings::nearest(&state.store.lock(), &vector, rows(&body)?)
+                    .map_err(|e| Response::error(500, e))?;
+                Ok(table(rows))
+            }
+            _ => unreachable!("[ERROR]: Endpoint {} has no handler.", request.path),
+        }
+    };
+    // A panic in a handler must not take the worker thread down with it.
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(answer)) {
+        Ok(Ok(body)) => Response::ok(body),
+        Ok(Err(response)) => response,
+        Err(_) => Response::error(500, "The request could not be handled."),
+    }
+}
+
+/// A connection whose reads time out after `timeout` of silence or once `until` has passed.
+#[derive(Debug)]
+struct Deadline {
+    /// The connection read from.
+    stream: TcpStream,
+    /// How long a single read may wait.
+    timeout: Duration,
+    /// When reading the current request times out.
+    until: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Request deadline passed."));
+        };
+        self.stream.set_read_timeout(Some(remaining.min(self.timeout)))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Serves the requests of one connection until it is closed or stays idle for `timeout`.
+///
+/// Every request, counted from the end of the previous one, must be read within `deadline`.
+fn connection(stream: TcpStream, state: &State, timeout: Duration, deadline: Duration) -> std::io::Result<()> {
+    let source = Deadline { stream: stream.try_clone()?, timeout, until: Instant::now() + deadline };
+    let mut reader = std::io::BufReader::new(source);
+    let mut writer = stream;
+    loop {
+        reader.get_mut().until = Instant::now() + deadline;
+        match read(&mut reader) {
+            Ok(None) => return Ok(()),
+            Ok(Some(request)) => {
+                handle(&request, state).write(&mut writer, request.close)?;
+                if request.close {
+                    return Ok(());
+                };
+            }
+            Err(response) => return response.write(&mut writer, true),
+        };
+    }
+}
+
+/// An HTTP server bound to a local address.
+#[derive(Debug)]
+pub(crate) struct Server {
+    /// The listening socket.
+    listener: TcpListener,
+    /// The number of worker threads.
+    workers: usize,
+    /// The state shared by the workers.
+    state: Arc<State>,
+    /// How long a connection may stay silent before it is closed.
+    timeout: Duration,
+    /// How long a client may take to send one request.
+    deadline: Duration,
+}
+
+impl Server {
+    /// Binds a server to an address.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address to listen on, e.g. `127.0.0.1:8080`. Port `0` picks a free port.
+    /// * `workers` - The number of worker threads, or `None` for one per available CPU.
+    /// * `vocabulary` - The vocabulary of requests that do not name one.
+    ///
+    /// With the `embeddings` feature, the database named by `GPT3BPE_WORDS` is opened
+    /// here, or an in-memory one if it is unset, and shared by every worker.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `workers` is `0` or the address cannot be bound.
+    pub(crate) fn bind(address: &str, workers: Option<usize>, vocabulary: Vocabularies) -> Result<Self, String> {
+        let workers = workers
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+        if workers == 0 {
+            return Err("The server needs at least one worker.".to_string());
+        };
+        let listener = TcpListener::bind(address).map_err(|e| format!("Could not bind {address}: {e}"))?;
+        let state = State {
+            vocabulary,
+            #[cfg(feature = "embeddings")]
+            store: embeddings::Store::global(),
+        };
+        Ok(Server { listener, workers, state: Arc::new(state), timeout: TIMEOUT, deadline: DEADLINE })
+    }
+
+    /// Returns the address the server listens on.
+    pub(crate) fn address(&self) -> SocketAddr {
+        self.listener.local_addr().expect("[ERROR]: Listener has no local address.")
+    }
+
+    /// Accepts connections and hands them to the workers, forever.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the workers cannot be started.
+    pub(crate) fn run(self) -> Result<(), String> {
+        let (sender, receiver) = mpsc::channel::<TcpStream>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..self.workers {
+            let receiver = Arc::clone(&receiver);
+            let (state, timeout, deadline) = (Arc::clone(&self.state), self.timeout, self.deadline);
+            std::thread::Builder::new()
+                .name(format!("gpt3bpe-worker-{index}"))
+                .spawn(move || loop {
+                    let stream = match receiver.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    if let Err(e) = connection(stream, &state, timeout, deadline) {
+                        eprintln!("[WARNING]: Connection failed: {e}");
+                    };
+                })
+                .map_err(|e| format!("Could not start worker {index}: {e}"))?;
+        }
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => sender.send(stream).expect("[ERROR]: Every worker has stopped."),
+                Err(e) => eprintln!("[WARNING]: Could not accept connection: {e}"),
+            };
+        }
+        Ok(())
+    }
+}
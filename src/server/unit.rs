@@ -0,0 +1,231 @@
+#[cfg(test)]
+pub(crate) mod server {
+    use crate::bpe::vocabulary::Vocabularies;
+    use crate::server::Server;
+    use std::io::{BufRead, Write};
+
+    /// Starts a server on a free loopback port in the background.
+    fn start(workers: usize) -> std::net::SocketAddr {
+        let server = Server::bind("127.0.0.1:0", Some(workers), Vocabularies::P50K).unwrap();
+        let address = server.address();
+        std::thread::spawn(move || server.run());
+        address
+    }
+
+    /// Reads one response from a connection.
+    ///
+    /// # Returns
+    ///
+    /// The status code, whether the server will close the connection, and the body.
+    fn response(reader: &mut impl BufRead) -> (u16, bool, serde_json::Value) {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let status = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let (mut length, mut close) = (0, false);
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            match header.trim_end().split_once(": ") {
+                Some(("Content-Length", value)) => length = value.parse().unwrap(),
+                Some(("Connection", "close")) => close = true,
+                Some(_) => {}
+                None => break,
+            };
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (status, close, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// Sends one request on a new connection.
+    fn post(address: std::net::SocketAddr, path: &str, body: &str) -> (u16, serde_json::Value) {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "POST {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let (status, close, body) = response(&mut std::io::BufReader::new(stream));
+        assert!(close);
+        (status, body)
+    }
+
+    #[test]
+    pub (crate) fn test_server_endpoints() {
+        let address = start(2);
+        let (status, body) = post(address, "/encode", r#"{"text": "hello world"}"#);
+        assert_eq!(status, 200);
+        assert_eq!(body, serde_json::json!({ "vocabulary": "P50K", "count": 2, "ids": [31373, 995] }));
+
+        let (_, body) = post(address, "/encode", r#"{"text": "hello world", "vocabulary": "cl100k"}"#);
+        assert_eq!(body["ids"], serde_json::json!([15339, 1917]));
+
+        let (status, body) = post(address, "/decode", r#"{"ids": [31373, 995]}"#);
+        assert_eq!(status, 200);
+        assert_eq!(body["text"], "hello world");
+
+        let (status, body) = post(address, "/count", r#"{"text": "let there be light."}"#);
+        assert_eq!(status, 200);
+        assert_eq!(body["count"], 5);
+
+        let (status, body) = post(address, "/chunk", r#"{"text": "let there be light.", "max_tokens": 2}"#);
+        assert_eq!(status, 200);
+        let texts: Vec<&str> = body["chunks"].as_array().unwrap().iter().map(|c| c["text"].as_str().unwrap()).collect();
+        assert_eq!(texts, ["let there", " be light", "."]);
+    }
+
+    #[test]
+    pub (crate) fn test_server_errors() {
+        let address = start(1);
+        let (status, body) = post(address, "/missing", "{}");
+        assert_eq!(status, 404);
+        assert!(body["error"].as_str().unwrap().starts_with("Unknown endpoint /missing"));
+
+        let (status, _) = post(address, "/encode", "not json");
+        assert_eq!(status, 400);
+        let (status, body) = post(address, "/encode", r#"{"text": "a", "vocabulary": "x"}"#);
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().starts_with("unknown vocabulary"));
+        if !Vocabularies::O200K.available() {
+            let (status, body) = post(address, "/count", r#"{"text": "a", "vocabulary": "o200k"}"#);
+            assert_eq!((status, body["error"].as_str()), (400, Some("Vocabulary O200K is not available.")));
+        };
+        let (status, body) = post(address, "/decode", r#"{"ids": [4000000000]}"#);
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().contains("4000000000"));
+        let (status, _) = post(address, "/decode", r#"{"ids": [4000000000], "unknown": "skip"}"#);
+        assert_eq!(status, 200);
+        let (status, _) = post(address, "/chunk", r#"{"text": "a", "max_tokens": 0}"#);
+        assert_eq!(status, 400);
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(stream, "GET /encode HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert_eq!(response(&mut std::io::BufReader::new(stream)).0, 405);
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(stream, "garbage\r\n\r\n").unwrap();
+        let (status, close, _) = response(&mut std::io::BufReader::new(stream));
+        assert_eq!((status, close), (400, true));
+    }
+
+    #[test]
+    pub (crate) fn test_server_keep_alive() {
+        let address = start(1);
+        let stream = std::net::TcpStream::connect(address).unwrap();
+        let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        for text in ["hello", "hello world", "let there be light."] {
+            let body = serde_json::json!({ "text": text }).to_string();
+            write!(writer, "POST /count HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+            let (status, close, body) = response(&mut reader);
+            assert_eq!((status, close), (200, false));
+            assert_eq!(body["count"], Vocabularies::P50K.encode(text.as_bytes()).concat().len());
+        }
+    }
+
+    #[test]
+    pub (crate) fn test_server_timeout() {
+        let mut server = Server::bind("127.0.0.1:0", Some(1), Vocabularies::P50K).unwrap();
+        server.timeout = std::time::Duration::from_millis(200);
+        let address = server.address();
+        std::thread::spawn(move || server.run());
+
+        // The only worker drops the idle connection and serves the next one.
+        let idle = std::net::TcpStream::connect(address).unwrap();
+        let (status, body) = post(address, "/count", r#"{"text": "hello"}"#);
+        assert_eq!((status, body["count"].as_u64()), (200, Some(1)));
+        assert_eq!(std::io::Read::read(&mut &idle, &mut [0; 1]).unwrap(), 0);
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(stream, "POST /count HTTP/1.1\r\nContent-Length: 10\r\n\r\n{{").unwrap();
+        let (status, close, _) = response(&mut std::io::BufReader::new(stream));
+        assert_eq!((status, close), (408, true));
+    }
+
+    #[test]
+    pub (crate) fn test_server_long_lines() {
+        let address = start(1);
+        let long = "a".repeat(crate::server::MAX_LINE);
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(stream, "POST /{long} HTTP/1.1\r\n\r\n").unwrap();
+        let (status, close, _) = response(&mut std::io::BufReader::new(stream));
+        assert_eq!((status, close), (414, true));
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(stream, "POST /count HTTP/1.1\r\nX-Long: {long}\r\n\r\n").unwrap();
+        let (status, close, _) = response(&mut std::io::BufReader::new(stream));
+        assert_eq!((status, close), (431, true));
+
+        let headers = "X-Header: a\r\n".repeat(crate::server::MAX_HEADERS + 1);
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(stream, "POST /count HTTP/1.1\r\n{headers}\r\n").unwrap();
+        let (status, close, body) = response(&mut std::io::BufReader::new(stream));
+        assert_eq!((status, close), (431, true));
+        assert_eq!(body["error"], format!("Request has more than {} headers.", crate::server::MAX_HEADERS));
+    }
+
+    #[test]
+    pub (crate) fn test_server_deadline() {
+        let mut server = Server::bind("127.0.0.1:0", Some(1), Vocabularies::P50K).unwrap();
+        server.timeout = std::time::Duration::from_millis(200);
+        server.deadline = std::time::Duration::from_millis(500);
+        let address = server.address();
+        std::thread::spawn(move || server.run());
+
+        // A client that sends a byte well within every read timeout still runs out of time.
+        let stream = std::net::TcpStream::connect(address).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let start = std::time::Instant::now();
+        std::thread::spawn(move || {
+            for byte in b"POST /count HTTP/1.1\r\n".iter().chain(b"X-Slow: a".repeat(100).iter()) {
+                if writer.write_all(&[*byte]).is_err() {
+                    return;
+                };
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+        });
+        let (status, close, _) = response(&mut std::io::BufReader::new(stream));
+        assert_eq!((status, close), (408, true));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    pub (crate) fn test_server_workers() {
+        assert!(Server::bind("127.0.0.1:0", Some(0), Vocabularies::P50K).is_err());
+        let address = start(4);
+        let clients: Vec<_> = (0..16)
+            .map(|i| std::thread::spawn(move || post(address, "/count", &format!(r#"{{"text": "{}"}}"#, "a ".repeat(i)))))
+            .collect();
+        for (i, client) in clients.into_iter().enumerate() {
+            let (status, body) = client.join().unwrap();
+            assert_eq!(status, 200);
+            assert_eq!(body["count"], Vocabularies::P50K.encode("a ".repeat(i).as_bytes()).concat().len());
+        }
+    }
+
+    #[cfg(feature = "embeddings")]
+    #[test]
+    pub (crate) fn test_server_embeddings() {
+        use crate::embeddings;
+        // Words inserted into the store of the server are found by every later request.
+        let server = Server::bind("127.0.0.1:0", Some(2), Vocabularies::P50K).unwrap();
+        embeddings::insert(&server.state.store.lock(), b"hello", &[0.5; embeddings::DIMENSIONS]).unwrap();
+        let address = server.address();
+        std::thread::spawn(move || server.run());
+
+        let vector = serde_json::json!({ "vector": vec![0.5; embeddings::DIMENSIONS], "k": 3 });
+        for _ in 0..2 {
+            let (status, body) = post(address, "/nearest", &vector.to_string());
+            assert_eq!(status, 200);
+            assert_eq!(body["rows"][0]["label"], "hello");
+        }
+        let (status, _) = post(address, "/nearest", r#"{"vector": [0.5], "k": 3}"#);
+        assert_eq!(status, 400);
+        let (status, _) = post(address, "/search", r#"{"text": "hello", "k": 0}"#);
+        assert_eq!(status, 400);
+        let (status, body) = post(address, "/search", r#"{"text": "hello", "k": 3}"#);
+        assert_eq!(status, 200);
+        assert!(body["rows"].is_array());
+    }
+}
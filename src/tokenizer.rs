@@ -0,0 +1,207 @@
+//! # Tokenizer Handles
+//!
+//! The per-vocabulary exports always use the vocabularies the library was built with,
+//! encode special tokens as plain text and keep no state between calls. A `Tokenizer`
+//! is configured once instead, from a JSON `Config`, and handed to FFI callers as an
+//! opaque handle:
+//!
+//! - **Vocabulary**: one of the built-in vocabularies, or any `.jsonl` file in the same
+//!   format, with its own special tokens.
+//! - **Special tokens**: whether special tokens in the input are encoded as text,
+//!   encoded as their IDs, or rejected.
+//! - **Cache**: an optional cache of the encoding of recent pieces, so that repeated
+//!   words skip the merge loop.
+//!
+//! A `Tokenizer` is `Send` and `Sync`: the vocabulary is read-only after loading and the
+//! cache sits behind a `Mutex`, so one handle can be shared by many threads.
+use crate::bpe;
+use crate::bpe::vocabulary::Vocabularies;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// How encoding handles special tokens, like `<|endoftext|>`, in the input.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Special {
+    /// Encode special tokens as ordinary text, like `bpe::encode`.
+    #[default]
+    Text,
+    /// Encode special tokens as their IDs.
+    Allow,
+    /// Fail if the input contains a special token.
+    Reject,
+}
+
+/// The configuration of a `Tokenizer`, as passed to `gpt3bpe_tokenizer_new` in JSON.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The built-in vocabulary to use, e.g. `cl100k`. Defaults to `p50k`. With `path`,
+    /// it still chooses the pattern that splits text into pieces.
+    pub vocabulary: Option<String>,
+    /// The path of a `.jsonl` vocabulary file to use instead of a built-in one.
+    pub path: Option<String>,
+    /// How special tokens in the input are encoded.
+    pub special: Special,
+    /// The special tokens and their IDs. Defaults to those of the built-in vocabulary,
+    /// or to none for a vocabulary loaded from `path`.
+    pub special_tokens: Option<BTreeMap<String, u32>>,
+    /// How decoding handles unknown token IDs.
+    pub unknown: bpe::Policy,
+    /// The number of pieces whose encoding is cached, or `0` for no cache.
+    pub cache: usize,
+}
+
+/// A configured tokenizer.
+#[derive(Debug)]
+pub struct Tokenizer {
+    /// The built-in vocabulary, whose pattern splits text into pieces even when the
+    /// tokens are loaded from `path`.
+    vocabulary: Vocabularies,
+    /// The vocabulary mapping raw token bytes to token IDs.
+    tokens: BTreeMap<Vec<u8>, u32>,
+    /// The reverse mapping from token IDs to Unicode code points for decoding.
+    unicodes: BTreeMap<u32, Vec<u16>>,
+    /// The special tokens and their IDs, longest first so that the longest match wins.
+    special_tokens: Vec<(String, u32)>,
+    /// How special tokens in the input are encoded.
+    special: Special,
+    /// How decoding handles unknown token IDs.
+    unknown: bpe::Policy,
+    /// The number of pieces whose encoding is cached.
+    capacity: usize,
+    /// The encoding of recently seen pieces.
+    cache: Mutex<HashMap<Vec<u8>, Vec<u32>>>,
+}
+
+impl Tokenizer {
+    /// Creates a `Tokenizer` from its configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vocabulary is unknown or its file does not exist.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the vocabulary file cannot be parsed.
+    pub fn new(config: Config) -> Result<Self, String> {
+        let vocabulary: Vocabularies = config.vocabulary.as_deref().unwrap_or("p50k").parse()?;
+        let file = match &config.path {
+            Some(path) => path.clone(),
+            None => bpe::vocabulary::location(vocabulary.file()),
+        };
+        if !std::path::Path::new(&file).exists() {
+            return Err(format!("Vocabulary file {file} not found."));
+        };
+
+        let mut special_tokens: Vec<(String, u32)> = match config.special_tokens {
+            Some(special_tokens) => special_tokens.into_iter().collect(),
+            None if config.path.is_some() => vec![],
+            None => vocabulary.special().iter().map(|&(name, id)| (name.to_string(), id)).collect(),
+        };
+        special_tokens.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+        Ok(Tokenizer {
+            vocabulary,
+            tokens: bpe::vocabulary::load_vocabulary(&file),
+            unicodes: bpe::vocabulary::generate_unicodes(&file),
+            special_tokens,
+            special: config.special,
+            unknown: config.unknown,
+            capacity: config.cache,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Creates a `Tokenizer` from a JSON configuration. An empty string is the default
+    /// configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the JSON is not a valid `Config`, or as `Tokenizer::new`.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let config = match json.trim() {
+            "" => Config::default(),
+            json => serde_json::from_str(json).map_err(|e| format!("Could not parse config: {e}"))?,
+        };
+        Tokenizer::new(config)
+    }
+
+    /// Finds the first special token in `slice`.
+    ///
+    /// # Returns
+    ///
+    /// The byte offset, length and ID of the token.
+    fn find_special(&self, slice: &[u8]) -> Option<(usize, usize, u32)> {
+        (0..slice.len()).find_map(|start| {
+            self.special_tokens
+                .iter()
+                .find(|(name, _)| slice[start..].starts_with(name.as_bytes()))
+                .map(|(name, id)| (start, name.len(), *id))
+        })
+    }
+
+    /// Encodes the pieces of text without special tokens, through the cache.
+    fn encode_ordinary(&self, slice: &[u8], result: &mut Vec<u32>) {
+        for piece in self.vocabulary.tokens(slice) {
+            if self.capacity == 0 {
+                result.extend(bpe::encode_piece(piece, &self.tokens));
+                continue;
+            };
+            if let Some(ids) = self.cache.lock().unwrap().get(piece) {
+                result.extend(ids);
+                continue;
+            };
+            let ids = bpe::encode_piece(piece, &self.tokens);
+            result.extend(&ids);
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= self.capacity {
+                cache.clear();
+            };
+            cache.insert(piece.to_vec(), ids);
+        }
+    }
+
+    /// Encodes a byte slice, handling special tokens as configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if special tokens are rejected and the input contains one.
+    pub fn encode(&self, slice: &[u8]) -> Result<Vec<u32>, String> {
+        let mut result = vec![];
+        if self.special == Special::Text {
+            self.encode_ordinary(slice, &mut result);
+            return Ok(result);
+        };
+
+        let mut rest = slice;
+        while let Some((start, length, id)) = self.find_special(rest) {
+            if self.special == Special::Reject {
+                let name = String::from_utf8_lossy(&rest[start..start + length]);
+                return Err(format!("Special token {name} is not allowed."));
+            };
+            self.encode_ordinary(&rest[..start], &mut result);
+            result.push(id);
+            rest = &rest[start + length..];
+        }
+        self.encode_ordinary(rest, &mut result);
+        Ok(result)
+    }
+
+    /// Decodes token IDs, writing special tokens as their names and handling unknown
+    /// IDs as configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the first unknown token ID if unknown IDs are errors.
+    pub fn decode(&self, tokens: &[u32]) -> Result<Vec<u8>, String> {
+        let mut result = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            match self.special_tokens.iter().find(|(_, id)| id == token) {
+                Some((name, _)) if !self.unicodes.contains_key(token) => result.extend(name.as_bytes()),
+                _ => result.extend(bpe::decode_lenient(&[*token], &self.unicodes, self.unknown)?),
+            };
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,364 @@
+#[cfg(test)]
+pub(crate) mod into {
+    #[test]
+    pub (crate) fn test_encode_into() {
+        let text = b"let there be light.";
+        let length = crate::encode_into_p50k(text.as_ptr(), text.len(), std::ptr::null_mut(), 0);
+        assert_eq!(length, 5);
+
+        let mut output = vec![0u16; length];
+        assert_eq!(crate::encode_into_p50k(text.as_ptr(), text.len(), output.as_mut_ptr(), length), 5);
+        assert_eq!(output, [1616, 612, 307, 1657, 13]);
+    }
+
+    #[test]
+    pub (crate) fn test_encode_into_truncated() {
+        let text = b"let there be light.";
+        let mut output = [0u32; 3];
+        let length = crate::encode_into_cl100k(text.as_ptr(), text.len(), output.as_mut_ptr(), output.len());
+        assert_eq!(length, 5);
+        assert_eq!(output, [1169, 1070, 387]);
+    }
+
+    #[test]
+    pub (crate) fn test_decode_into_truncated() {
+        let tokens = [1616u16, 612, 307, 1657, 13];
+        let mut output = [0u8; 8];
+        let length = crate::decode_into_p50k(tokens.as_ptr(), tokens.len(), output.as_mut_ptr(), output.len());
+        assert_eq!(length, 19);
+        assert_eq!(&output, b"let ther");
+    }
+
+    #[test]
+    pub (crate) fn test_grapheme_into() {
+        let text = "a é".as_bytes();
+        let length = crate::grapheme_into(text.as_ptr(), text.len(), std::ptr::null_mut(), 0);
+        let mut output = vec![0u8; length];
+        crate::grapheme_into(text.as_ptr(), text.len(), output.as_mut_ptr(), length);
+        assert_eq!(output, crate::bpe::grapheme(text).concat());
+    }
+
+    #[test]
+    pub (crate) fn test_free_buffer() {
+        let bytes: Box<[u8]> = b"label".to_vec().into_boxed_slice();
+        let length = bytes.len();
+        crate::free_buffer(Box::into_raw(bytes).cast(), length);
+        crate::free_buffer(std::ptr::null_mut(), 0);
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "embeddings")]
+pub(crate) mod embeddings {
+    #[test]
+    pub (crate) fn test_nearest_into_empty_store() {
+        let vector = [0.5f32; crate::embeddings::DIMENSIONS];
+        let mut distances = [0f32; 2];
+        let mut offsets = [usize::MAX; 3];
+        let mut labels = std::ptr::null_mut();
+        let rows = crate::nearest_into(vector.as_ptr(), vector.len(), 2, distances.as_mut_ptr(), offsets.as_mut_ptr(), 2, &mut labels);
+        assert_eq!(rows, 0);
+        assert_eq!(offsets[0], 0);
+        assert!(!labels.is_null());
+        crate::free_buffer(labels, offsets[rows]);
+    }
+
+    #[test]
+    pub (crate) fn test_store_handle() {
+        let path = std::env::temp_dir().join(format!("gpt3bpe-store-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let location = path.to_str().unwrap();
+        let (label, vector) = crate::embeddings::unit::VECTORS[0];
+
+        let store = crate::gpt3bpe_store_open(location.as_ptr(), location.len());
+        assert!(!store.is_null());
+        assert!(crate::gpt3bpe_store_insert(store, label.as_ptr(), label.len(), vector.as_ptr(), vector.len()));
+        assert!(matches!(crate::gpt3bpe_store_close(store), crate::Status::Ok));
+
+        let store = crate::gpt3bpe_store_open(location.as_ptr(), location.len());
+        let mut distances = [0f32; 1];
+        let mut offsets = [0usize; 2];
+        let mut labels = std::ptr::null_mut();
+        let rows = crate::gpt3bpe_store_nearest(store, vector.as_ptr(), vector.len(), 1, distances.as_mut_ptr(), offsets.as_mut_ptr(), 1, &mut labels);
+        assert_eq!(rows, 1);
+        assert_eq!(unsafe { std::slice::from_raw_parts(labels, offsets[1]) }, label.as_bytes());
+        crate::free_buffer(labels, offsets[1]);
+
+        let mut vectors = vec![0f32; crate::embeddings::DIMENSIONS];
+        let rows = crate::gpt3bpe_store_search(store, label.as_ptr(), label.len(), 1, distances.as_mut_ptr(), vectors.as_mut_ptr(), 1);
+        assert_eq!(rows, 1);
+        assert_eq!(vectors, vector);
+
+        assert_eq!(crate::gpt3bpe_store_delete(store, label.as_ptr(), label.len()), 1);
+        assert_eq!(crate::gpt3bpe_store_delete(store, label.as_ptr(), label.len()), 0);
+        let rows = crate::gpt3bpe_store_nearest(store, vector.as_ptr(), vector.len(), 1, distances.as_mut_ptr(), offsets.as_mut_ptr(), 1, std::ptr::null_mut());
+        assert_eq!(rows, 0);
+        crate::gpt3bpe_store_close(store);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    pub (crate) fn test_store_null_handle() {
+        let label = "let";
+        assert_eq!(crate::gpt3bpe_store_delete(std::ptr::null(), label.as_ptr(), label.len()), usize::MAX);
+        let error = unsafe { std::ffi::CStr::from_ptr(crate::gpt3bpe_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "store handle is null.");
+        assert!(matches!(crate::gpt3bpe_store_close(std::ptr::null_mut()), crate::Status::Ok));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tokenizer {
+    use crate::tokenizer::Tokenizer;
+
+    #[test]
+    pub (crate) fn test_tokenizer_default() {
+        let tokenizer = Tokenizer::from_json("").unwrap();
+        let text = b"let there be light. <|endoftext|>";
+        assert_eq!(
+            tokenizer.encode(text).unwrap(),
+            crate::bpe::encode(text, &crate::bpe::vocabulary::P50K_TOKENS).concat()
+        );
+    }
+
+    #[test]
+    pub (crate) fn test_tokenizer_special() {
+        let allow = Tokenizer::from_json(r#"{"vocabulary": "cl100k", "special": "allow"}"#).unwrap();
+        let ids = allow.encode(b"hello<|endoftext|>world").unwrap();
+        assert_eq!(ids, [15339, 100257, 14957]);
+        assert_eq!(allow.decode(&ids).unwrap(), b"hello<|endoftext|>world");
+
+        let reject = Tokenizer::from_json(r#"{"vocabulary": "cl100k", "special": "reject"}"#).unwrap();
+        assert_eq!(
+            reject.encode(b"hello<|endoftext|>"),
+            Err("Special token <|endoftext|> is not allowed.".to_string())
+        );
+        assert!(reject.encode(b"hello").is_ok());
+    }
+
+    #[test]
+    pub (crate) fn test_tokenizer_path_and_unknown() {
+        let tokenizer =
+            Tokenizer::from_json(r#"{"path": "src/bpe/vocabulary/r50k.jsonl", "unknown": "replace", "cache": 2}"#)
+                .unwrap();
+        let text = b"let there be light, let there be light.";
+        let ids = tokenizer.encode(text).unwrap();
+        assert_eq!(ids, crate::bpe::encode(text, &crate::bpe::vocabulary::R50K_TOKENS).concat());
+        assert_eq!(tokenizer.decode(&[1616, 60000]).unwrap(), "let\u{FFFD}".as_bytes());
+    }
+
+    #[test]
+    pub (crate) fn test_tokenizer_invalid_config() {
+        assert!(Tokenizer::from_json(r#"{"vocabulary": "gpt5"}"#).is_err());
+        assert!(Tokenizer::from_json(r#"{"path": "missing.jsonl"}"#).is_err());
+        assert!(Tokenizer::from_json(r#"{"special": "maybe"}"#).is_err());
+        assert!(Tokenizer::from_json(r#"{"unknown_field": 1}"#).is_err());
+    }
+
+    #[test]
+    pub (crate) fn test_tokenizer_handle_threads() {
+        let config = br#"{"vocabulary": "r50k", "cache": 64}"#;
+        let handle = crate::gpt3bpe_tokenizer_new(config.as_ptr(), config.len()) as usize;
+        assert_ne!(handle, 0);
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                std::thread::spawn(move || {
+                    let handle = handle as *const Tokenizer;
+                    let text = format!("thread {i} says let there be light.");
+                    let length = crate::gpt3bpe_encode(handle, text.as_ptr(), text.len(), std::ptr::null_mut(), 0);
+                    let mut ids = vec![0u32; length];
+                    crate::gpt3bpe_encode(handle, text.as_ptr(), text.len(), ids.as_mut_ptr(), length);
+
+                    let mut bytes = vec![0u8; text.len()];
+                    let decoded = crate::gpt3bpe_decode(handle, ids.as_ptr(), ids.len(), bytes.as_mut_ptr(), bytes.len());
+                    assert_eq!(decoded, text.len());
+                    assert_eq!(bytes, text.as_bytes());
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        crate::gpt3bpe_tokenizer_free(handle as *mut Tokenizer);
+    }
+
+    #[test]
+    pub (crate) fn test_tokenizer_handle_invalid() {
+        let config = b"{not json";
+        assert!(crate::gpt3bpe_tokenizer_new(config.as_ptr(), config.len()).is_null());
+        crate::gpt3bpe_tokenizer_free(std::ptr::null_mut());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod errors {
+    fn last_error() -> String {
+        unsafe { std::ffi::CStr::from_ptr(crate::gpt3bpe_last_error()) }.to_string_lossy().into_owned()
+    }
+
+    extern "C" fn ignore(_: usize, _: u8) {}
+
+    #[test]
+    pub (crate) fn test_null_pointer_is_reported() {
+        assert_eq!(crate::decode_p50k(std::ptr::null(), 3, ignore), crate::Status::Error);
+        assert_eq!(last_error(), "pointer is null.");
+    }
+
+    #[test]
+    pub (crate) fn test_unknown_token_is_reported() {
+        let tokens = [1616u16, 60000];
+        assert_eq!(crate::decode_p50k(tokens.as_ptr(), tokens.len(), ignore), crate::Status::Error);
+        assert_eq!(last_error(), "Token ID 60000 not found.");
+
+        let length = crate::decode_into_p50k(tokens.as_ptr(), tokens.len(), std::ptr::null_mut(), 0);
+        assert_eq!(length, usize::MAX);
+        assert_eq!(last_error(), "Token ID 60000 not found.");
+
+        assert!(!crate::decode_lenient_p50k(tokens.as_ptr(), tokens.len(), 7, ignore));
+        assert_eq!(last_error(), "unknown policy code: 7");
+    }
+
+    #[test]
+    pub (crate) fn test_success_returns_ok() {
+        let tokens = [1616u16, 13];
+        assert_eq!(crate::decode_p50k(tokens.as_ptr(), tokens.len(), ignore), crate::Status::Ok);
+        let text = "héllo";
+        assert!(crate::truncate_p50k(text.as_ptr(), text.len(), 10, 1, [0usize; 2].as_mut_ptr(), ignore));
+    }
+
+    #[test]
+    pub (crate) fn test_invalid_config_is_reported() {
+        let config = br#"{"vocabulary": "gpt5"}"#;
+        assert!(crate::gpt3bpe_tokenizer_new(config.as_ptr(), config.len()).is_null());
+        assert!(last_error().starts_with("unknown vocabulary: gpt5."));
+    }
+
+    #[test]
+    pub (crate) fn test_last_error_is_thread_local() {
+        assert_eq!(crate::decode_p50k(std::ptr::null(), 3, ignore), crate::Status::Error);
+        let other = std::thread::spawn(last_error).join().unwrap();
+        assert_eq!(other, "");
+        assert_eq!(last_error(), "pointer is null.");
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod clusters {
+    /// A cluster reported by `grapheme_clusters`: index, offset, original and GPT-unicode bytes.
+    type Cluster = (usize, usize, Vec<u8>, Vec<u8>);
+
+    static CLUSTERS: std::sync::Mutex<Vec<Cluster>> = std::sync::Mutex::new(vec![]);
+
+    extern "C" fn collect(index: usize, offset: usize, bytes: *const u8, length: usize, unicode: *const u8, unicode_length: usize) {
+        let bytes = unsafe { std::slice::from_raw_parts(bytes, length) }.to_vec();
+        let unicode = unsafe { std::slice::from_raw_parts(unicode, unicode_length) }.to_vec();
+        CLUSTERS.lock().unwrap().push((index, offset, bytes, unicode));
+    }
+
+    #[test]
+    pub (crate) fn test_grapheme_clusters() {
+        let text = b"a e\xCC\x81\xFF\xF0\x9F\x91\x8B";
+        CLUSTERS.lock().unwrap().clear();
+        assert_eq!(crate::grapheme_clusters(text.as_ptr(), text.len(), collect), crate::Status::Ok);
+        assert_eq!(
+            *CLUSTERS.lock().unwrap(),
+            [
+                (0, 0, b"a".to_vec(), b"a".to_vec()),
+                (1, 1, b" ".to_vec(), "\u{120}".as_bytes().to_vec()),
+                (2, 2, "e\u{301}".as_bytes().to_vec(), "e\u{CC}\u{123}".as_bytes().to_vec()),
+                (3, 5, b"\xFF".to_vec(), "\u{FF}".as_bytes().to_vec()),
+                (4, 6, "\u{1F44B}".as_bytes().to_vec(), "\u{F0}\u{141}\u{133}\u{12D}".as_bytes().to_vec()),
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod batch {
+    use crate::Slice;
+
+    fn slices<T>(items: &[&[T]]) -> Vec<Slice<T>> {
+        items.iter().map(|item| Slice { pointer: item.as_ptr(), length: item.len() }).collect()
+    }
+
+    #[test]
+    pub (crate) fn test_encode_batch() {
+        let texts: [&[u8]; 3] = [b"let there be light.", b"", b"hello world"];
+        let inputs = slices(&texts);
+        let mut output = [0u32; 16];
+        let mut offsets = [usize::MAX; 4];
+        let total = crate::encode_batch_cl100k(inputs.as_ptr(), inputs.len(), output.as_mut_ptr(), output.len(), offsets.as_mut_ptr());
+
+        let expected: Vec<Vec<u32>> = texts
+            .iter()
+            .map(|text| crate::bpe::vocabulary::Vocabularies::CL100K.encode(text).concat())
+            .collect();
+        assert_eq!(total, expected.concat().len());
+        assert_eq!(offsets[0], 0);
+        for (i, tokens) in expected.iter().enumerate() {
+            assert_eq!(&output[offsets[i]..offsets[i + 1]], tokens.as_slice());
+        }
+
+        let mut counts = [0usize; 3];
+        assert_eq!(crate::count_batch_cl100k(inputs.as_ptr(), inputs.len(), counts.as_mut_ptr()), total);
+        assert_eq!(counts.to_vec(), expected.iter().map(Vec::len).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub (crate) fn test_encode_batch_truncated() {
+        let texts: [&[u8]; 2] = [b"let there be light.", b"hello world"];
+        let inputs = slices(&texts);
+        let mut offsets = [0usize; 3];
+        let total = crate::encode_batch_p50k(inputs.as_ptr(), inputs.len(), std::ptr::null_mut(), 0, offsets.as_mut_ptr());
+        assert_eq!(offsets[2], total);
+
+        let mut output = vec![0u16; total - 1];
+        assert_eq!(crate::encode_batch_p50k(inputs.as_ptr(), inputs.len(), output.as_mut_ptr(), output.len(), offsets.as_mut_ptr()), total);
+        let expected = crate::bpe::encode(texts[0], &crate::bpe::vocabulary::P50K_TOKENS).concat();
+        assert_eq!(&output[..offsets[1]], expected.iter().map(|&t| t as u16).collect::<Vec<_>>().as_slice());
+    }
+
+    #[test]
+    pub (crate) fn test_decode_batch() {
+        let first = [1616u16, 612, 307, 1657, 13];
+        let second = [31373u16, 995];
+        let inputs = slices(&[&first[..], &second[..]]);
+        let mut output = [0u8; 64];
+        let mut offsets = [0usize; 3];
+        let total = crate::decode_batch_r50k(inputs.as_ptr(), inputs.len(), output.as_mut_ptr(), output.len(), offsets.as_mut_ptr());
+        assert_eq!(&output[..total], b"let there be light.hello world");
+        assert_eq!(offsets, [0, 19, 30]);
+
+        let unknown = [60000u16];
+        let inputs = slices(&[&first[..], &unknown[..]]);
+        let total = crate::decode_batch_r50k(inputs.as_ptr(), inputs.len(), output.as_mut_ptr(), output.len(), offsets.as_mut_ptr());
+        assert_eq!(total, usize::MAX);
+        let error = unsafe { std::ffi::CStr::from_ptr(crate::gpt3bpe_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "Input 1: Token ID 60000 not found.");
+    }
+
+    #[test]
+    pub (crate) fn test_tokenizer_batch() {
+        let config = r#"{"vocabulary": "cl100k", "special": "allow"}"#;
+        let tokenizer = crate::gpt3bpe_tokenizer_new(config.as_ptr(), config.len());
+        let texts: [&[u8]; 2] = [b"hello<|endoftext|>", b"world"];
+        let inputs = slices(&texts);
+        let mut output = [0u32; 8];
+        let mut offsets = [0usize; 3];
+        let total = crate::gpt3bpe_encode_batch(tokenizer, inputs.as_ptr(), inputs.len(), output.as_mut_ptr(), output.len(), offsets.as_mut_ptr());
+        assert_eq!(&output[..total], [15339, 100257, 14957]);
+        assert_eq!(offsets, [0, 2, 3]);
+
+        let mut counts = [0usize; 2];
+        assert_eq!(crate::gpt3bpe_count_batch(tokenizer, inputs.as_ptr(), inputs.len(), counts.as_mut_ptr()), 3);
+        assert_eq!(counts, [2, 1]);
+
+        let encodings = slices(&[&output[0..2], &output[2..3]]);
+        let mut text = [0u8; 32];
+        let total = crate::gpt3bpe_decode_batch(tokenizer, encodings.as_ptr(), encodings.len(), text.as_mut_ptr(), text.len(), offsets.as_mut_ptr());
+        assert_eq!(&text[..total], b"hello<|endoftext|>world");
+        assert_eq!(offsets, [0, 18, 23]);
+        crate::gpt3bpe_tokenizer_free(tokenizer);
+    }
+}